@@ -0,0 +1,276 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+#![deny(warnings)]
+// Enable all clippy lints except for many of the pedantic ones. It's a shame this needs to be copied and pasted across crates, but there doesn't appear to be a way to include inner attributes from a common source.
+#![deny(
+  clippy::all,
+  clippy::default_trait_access,
+  clippy::expl_impl_clone_on_copy,
+  clippy::if_not_else,
+  clippy::needless_continue,
+  clippy::unseparated_literal_suffix,
+  // TODO: Falsely triggers for async/await:
+  //   see https://github.com/rust-lang/rust-clippy/issues/5360
+  // clippy::used_underscore_binding
+)]
+// It is often more clear to show that nothing is being moved.
+#![allow(clippy::match_ref_pats)]
+// Subjective style.
+#![allow(
+  clippy::len_without_is_empty,
+  clippy::redundant_field_names,
+  clippy::too_many_arguments
+)]
+// Default isn't as big a deal as people seem to think it is.
+#![allow(clippy::new_without_default, clippy::new_ret_no_self)]
+// Arc<Mutex> can be more clear than needing to grok Orderings:
+#![allow(clippy::mutex_atomic)]
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use std::fmt;
+use std::future::Future;
+use std::ops::DerefMut;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future;
+use parking_lot::Mutex;
+
+use graph::{Direction, Entry, EntryId, Graph, Node, NodeContext, NodeError, Stats};
+
+///
+/// Benchmarks many concurrent requests for Nodes which have already completed, which (without
+/// any Nodes needing to run) measures contention on the Graph's locks.
+///
+pub fn criterion_benchmark_concurrent_requests(c: &mut Criterion) {
+  let runtime = tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap();
+
+  let mut cgroup = c.benchmark_group("concurrent_requests");
+
+  for (nodes, tasks) in vec![(1000, 1), (1000, 16), (1000, 64)] {
+    let graph = Arc::new(Graph::new());
+    let context = BContext::new(graph.clone());
+    // Complete every Node up front.
+    runtime
+      .block_on(future::try_join_all(
+        (0..nodes).map(|id| graph.create(BNode(id), &context)),
+      ))
+      .unwrap();
+
+    cgroup
+      .sample_size(10)
+      .measurement_time(Duration::from_secs(30))
+      .bench_function(format!("concurrent_requests({}, {})", nodes, tasks), |b| {
+        b.iter(|| {
+          runtime
+            .block_on(future::try_join_all((0..tasks).map(|task| {
+              let graph = graph.clone();
+              let context = context.clone();
+              runtime.spawn(async move {
+                for id in 0..nodes {
+                  // Each task requests the Nodes in a different order.
+                  graph
+                    .create(BNode((id + task * 7) % nodes), &context)
+                    .await
+                    .unwrap();
+                }
+              })
+            })))
+            .unwrap();
+        })
+      });
+  }
+}
+
+///
+/// Benchmarks checking for a cycle when adding a dependency to a Node with very high fan-in, for
+/// which choosing the direction to search in should not require visiting all of its dependents.
+///
+pub fn criterion_benchmark_high_fan_in_cycle_detection(c: &mut Criterion) {
+  let runtime = tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap();
+
+  let mut cgroup = c.benchmark_group("high_fan_in_cycle_detection");
+
+  for fan_in in vec![1000, 100000] {
+    let graph = Arc::new(Graph::new());
+    let context = BContext::new(graph.clone());
+    // Create `fan_in` dependents of BNode(1).
+    runtime
+      .block_on(future::try_join_all(
+        (FAN_IN_BASE..FAN_IN_BASE + fan_in).map(|id| graph.create(BNode(id), &context)),
+      ))
+      .unwrap();
+
+    cgroup.bench_function(format!("would_create_cycle({})", fan_in), |b| {
+      b.iter(|| assert!(!graph.would_create_cycle(&BNode(1), &BNode(0))))
+    });
+    for direction in vec![Direction::Outgoing, Direction::Incoming] {
+      cgroup.bench_function(
+        format!("detect_cycle_with_direction({}, {:?})", fan_in, direction),
+        |b| b.iter(|| assert!(!graph.detect_cycle_with_direction(&BNode(1), &BNode(0), direction))),
+      );
+    }
+  }
+}
+
+///
+/// Benchmarks recomputing the critical path of a long chain of Nodes after the duration of a Node
+/// near the bottom of the chain has changed, either from scratch or incrementally.
+///
+pub fn criterion_benchmark_critical_path(c: &mut Criterion) {
+  let runtime = tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap();
+
+  let mut cgroup = c.benchmark_group("critical_path");
+
+  let nodes = 1000;
+  let graph = Arc::new(Graph::new());
+  let context = BContext::new(graph.clone());
+  runtime
+    .block_on(graph.create(BNode(nodes - 1), &context))
+    .unwrap();
+  let roots = [BNode(nodes - 1)];
+  let duration = |entry: &Entry<BNode>| Duration::from_millis(entry.node().0 as u64);
+
+  cgroup.bench_function(format!("critical_path({})", nodes), |b| {
+    b.iter(|| graph.critical_path(&roots, &duration))
+  });
+  let mut cache = graph.critical_path_cache(&roots, &duration);
+  cgroup.bench_function(format!("critical_path_incremental({})", nodes), |b| {
+    b.iter(|| graph.critical_path_incremental(&mut cache, &[BNode(10)], &duration))
+  });
+}
+
+criterion_group!(
+  benches,
+  criterion_benchmark_concurrent_requests,
+  criterion_benchmark_high_fan_in_cycle_detection,
+  criterion_benchmark_critical_path
+);
+criterion_main!(benches);
+
+///
+/// A Node which (when requested) depends on the Node with the next lowest id, or (if its id is
+/// FAN_IN_BASE or above) on BNode(1).
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct BNode(usize);
+
+const FAN_IN_BASE: usize = 1_000_000;
+
+#[async_trait]
+impl Node for BNode {
+  type Context = BContext;
+  type Item = usize;
+  type Error = BError;
+
+  async fn run(self, context: BContext) -> Result<usize, BError> {
+    if self.0 == 0 {
+      Ok(0)
+    } else if self.0 >= FAN_IN_BASE {
+      Ok(context.get(BNode(1)).await? + 1)
+    } else {
+      Ok(context.get(BNode(self.0 - 1)).await? + 1)
+    }
+  }
+
+  fn cacheable(&self) -> bool {
+    true
+  }
+}
+
+impl fmt::Display for BNode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:?}", self)
+  }
+}
+
+#[derive(Clone)]
+struct BContext {
+  entry_id: Option<EntryId>,
+  graph: Arc<Graph<BNode>>,
+  stats: Arc<Mutex<Stats>>,
+}
+
+impl BContext {
+  fn new(graph: Arc<Graph<BNode>>) -> BContext {
+    BContext {
+      entry_id: None,
+      graph,
+      stats: Arc::default(),
+    }
+  }
+
+  async fn get(&self, node: BNode) -> Result<usize, BError> {
+    self.graph.get(self.entry_id, self, node).await
+  }
+}
+
+impl NodeContext for BContext {
+  type Node = BNode;
+  type RunId = ();
+
+  fn stats<'a>(&'a self) -> Box<dyn DerefMut<Target = Stats> + 'a> {
+    Box::new(self.stats.lock())
+  }
+
+  fn clone_for(&self, entry_id: EntryId) -> BContext {
+    BContext {
+      entry_id: Some(entry_id),
+      graph: self.graph.clone(),
+      stats: self.stats.clone(),
+    }
+  }
+
+  fn run_id(&self) -> &() {
+    &()
+  }
+
+  fn graph(&self) -> &Graph<BNode> {
+    &self.graph
+  }
+
+  fn spawn<F>(&self, future: F)
+  where
+    F: Future<Output = ()> + Send + 'static,
+  {
+    tokio::spawn(future);
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum BError {
+  Cyclic,
+  ForbiddenEdge,
+  GraphFull,
+  Invalidated,
+}
+
+impl NodeError for BError {
+  fn invalidated() -> Self {
+    BError::Invalidated
+  }
+
+  fn cyclic(_path: Vec<String>) -> Self {
+    BError::Cyclic
+  }
+
+  fn forbidden_edge(_src: String, _dst: String) -> Self {
+    BError::ForbiddenEdge
+  }
+
+  fn graph_full() -> Self {
+    BError::GraphFull
+  }
+}
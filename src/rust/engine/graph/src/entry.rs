@@ -1,8 +1,9 @@
 use std::mem;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::node::{EntryId, Node, NodeContext, NodeError};
-use crate::test_trace_log;
+use crate::{test_trace_log, GraphEvent};
 
 use async_value::{AsyncValue, AsyncValueReceiver, AsyncValueSender};
 use futures::channel::oneshot;
@@ -37,7 +38,7 @@ impl RunToken {
 /// Unlike the RunToken (which is incremented whenever a node re-runs), the Generation is only
 /// incremented when the output of a node has changed.
 ///
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Generation(u32);
 
 impl Generation {
@@ -154,6 +155,25 @@ impl<N: Node> AsRef<N::Item> for EntryResult<N> {
 
 pub type NodeResult<N> = Result<(<N as Node>::Item, Generation), <N as Node>::Error>;
 
+///
+/// A coarse classification of the state of an Entry, relative to some NodeContext.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NodeStatus {
+  /// The Node is not running, and has no value. If its most recent run failed, it is instead
+  /// classified as `Completed { ok: false }`.
+  NotStarted,
+  Running,
+  /// The Node's most recent run completed, and if it succeeded, the Node has a value which is valid
+  /// for the context.
+  Completed {
+    ok: bool,
+  },
+  /// The Node has a value, but it is not valid for the context (and so the Node will be cleaned or
+  /// re-run when it is next requested).
+  Dirty,
+}
+
 #[derive(Debug)]
 pub enum EntryState<N: Node> {
   // A node that has either been explicitly cleared, or has not yet started Running. In this state
@@ -180,6 +200,7 @@ pub enum EntryState<N: Node> {
     pending_value: AsyncValue<NodeResult<N>>,
     generation: Generation,
     previous_result: Option<EntryResult<N>>,
+    start_time: Instant,
   },
   // A node that has completed, and then possibly been marked dirty. Because marking a node
   // dirty does not eagerly re-execute any logic, it will stay this way until a caller moves it
@@ -206,6 +227,26 @@ impl<N: Node> EntryState<N> {
   }
 }
 
+///
+/// Information about the runs of an Entry which, unlike its EntryState, is preserved when the
+/// Entry is cleared.
+///
+#[derive(Debug, Default)]
+struct RunInfo {
+  // The wall-clock time taken by the most recently completed run (including cleaning).
+  last_run_duration: Option<Duration>,
+  // The time at which the most recently completed run (including cleaning) completed.
+  last_run_completed: Option<Instant>,
+  // The number of times that the Entry has been started (either to run or to be cleaned): this
+  // corresponds to the number of times that its RunToken has been incremented to start work.
+  run_count: u64,
+  // True if the most recently completed run failed. Because failures are not memoized, a failed
+  // Entry will re-run the next time it is requested.
+  last_run_failed: bool,
+  // Waiters to be notified the next time that the Entry is started. See `completion_or_start`.
+  start_waiters: Vec<oneshot::Sender<()>>,
+}
+
 ///
 /// An Entry and its adjacencies.
 ///
@@ -217,6 +258,8 @@ pub struct Entry<N: Node> {
   node: N,
 
   pub state: Arc<Mutex<EntryState<N>>>,
+
+  run_info: Arc<Mutex<RunInfo>>,
 }
 
 impl<N: Node> Entry<N> {
@@ -229,6 +272,7 @@ impl<N: Node> Entry<N> {
     Entry {
       node,
       state: Arc::new(Mutex::new(EntryState::initial())),
+      run_info: Arc::default(),
     }
   }
 
@@ -291,6 +335,99 @@ impl<N: Node> Entry<N> {
     }
   }
 
+  ///
+  /// If the Future for this Node has already completed, calls the given function with a reference
+  /// to its result (while holding the lock for this Entry) rather than cloning it.
+  ///
+  pub(crate) fn peek_with<T>(
+    &self,
+    context: &N::Context,
+    f: impl FnOnce(&N::Item) -> T,
+  ) -> Option<T> {
+    let state = self.state.lock();
+    match *state {
+      EntryState::Completed { ref result, .. } if result.is_clean(context) => {
+        Some(f(result.as_ref()))
+      }
+      _ => None,
+    }
+  }
+
+  ///
+  /// If this Node is Completed and clean, returns a Future for its value. If it is Running,
+  /// returns a Future for the result of the run, which will be None if the run is canceled.
+  /// Otherwise, returns None without starting the Node.
+  ///
+  /// NB: Awaiting the result of a run expresses interest in it, and so will prevent it from being
+  /// canceled due to the loss of interest of its other requestors.
+  ///
+  pub(crate) fn completion(
+    &self,
+    context: &N::Context,
+  ) -> Option<BoxFuture<'static, Option<NodeResult<N>>>> {
+    Self::completion_of(&self.state.lock(), context)
+  }
+
+  ///
+  /// Like `completion`, but rather than returning None, returns a receiver which will be notified
+  /// the next time that the Node is started (by some other caller).
+  ///
+  pub(crate) fn completion_or_start(
+    &self,
+    context: &N::Context,
+  ) -> Result<BoxFuture<'static, Option<NodeResult<N>>>, oneshot::Receiver<()>> {
+    // NB: The waiter is registered while holding the lock on the state, which must also be held
+    // to start the Node, so that a start cannot be missed.
+    let state = self.state.lock();
+    Self::completion_of(&state, context).ok_or_else(|| {
+      let (sender, receiver) = oneshot::channel();
+      let mut run_info = self.run_info.lock();
+      run_info.start_waiters.retain(|waiter| !waiter.is_closed());
+      run_info.start_waiters.push(sender);
+      receiver
+    })
+  }
+
+  fn completion_of(
+    state: &EntryState<N>,
+    context: &N::Context,
+  ) -> Option<BoxFuture<'static, Option<NodeResult<N>>>> {
+    match *state {
+      EntryState::Completed {
+        ref result,
+        generation,
+        ..
+      } if result.is_clean(context) => {
+        Some(future::ready(Some(Ok((result.as_ref().clone(), generation)))).boxed())
+      }
+      EntryState::Running {
+        ref pending_value, ..
+      } => pending_value
+        .receiver()
+        .map(|receiver| async move { receiver.recv().await }.boxed()),
+      _ => None,
+    }
+  }
+
+  ///
+  /// Calls the given function with a reference to any value held by this entry, whether or not it
+  /// is clean: for an entry which is not Completed, this is its previous result (if any).
+  ///
+  pub(crate) fn with_held_value<T>(&self, f: impl FnOnce(&N::Item) -> T) -> Option<T> {
+    let state = self.state.lock();
+    match *state {
+      EntryState::Completed { ref result, .. } => Some(f(result.as_ref())),
+      EntryState::NotStarted {
+        ref previous_result,
+        ..
+      }
+      | EntryState::Running {
+        ref previous_result,
+        ..
+      } => previous_result.as_ref().map(|result| f(result.as_ref())),
+    }
+  }
+
   ///
   /// Spawn the execution of the node on an Executor, which will cause it to execute outside of
   /// the Graph lock and call back into the graph lock to set the final value.
@@ -310,6 +447,19 @@ impl<N: Node> Entry<N> {
     let context2 = context.clone();
     let node = node.clone();
     let (value, mut sender, receiver) = AsyncValue::new();
+    let not_started = previous_dep_generations.is_none();
+    context_factory
+      .graph()
+      .publish_event(|| GraphEvent::NodeStarted(node.clone()));
+    // With the `tracing` feature enabled, this run of the Node is instrumented with a span for the
+    // Node and its (new) RunToken.
+    #[cfg(feature = "tracing")]
+    let span = tracing::span!(
+      tracing::Level::DEBUG,
+      "node",
+      node = %node,
+      run_token = ?run_token
+    );
 
     let run_or_clean = async move {
       // If we have previous result generations, compare them to all current dependency
@@ -341,12 +491,27 @@ impl<N: Node> Entry<N> {
         // previous_result or generation.
         None
       } else {
-        // The Node needs to (re-)run!
-        let res = node.run(context.clone()).await;
-        context.stats().ran += 1;
-        Some(res)
+        // A Node which was not started may be able to load its value rather than running.
+        let cached = if not_started {
+          node.try_load_cached(&context)
+        } else {
+          None
+        };
+        if let Some(item) = cached {
+          Some(Ok(item))
+        } else {
+          // The Node needs to (re-)run!
+          let res = context
+            .graph()
+            .run_with_permit(entry_id, node.run(context.clone()))
+            .await;
+          context.stats().ran += 1;
+          Some(res)
+        }
       }
     };
+    #[cfg(feature = "tracing")]
+    let run_or_clean = tracing::Instrument::instrument(run_or_clean, span);
 
     context_factory.spawn(async move {
       tokio::select! {
@@ -371,6 +536,7 @@ impl<N: Node> Entry<N> {
         pending_value: value,
         generation,
         previous_result,
+        start_time: Instant::now(),
       },
       receiver,
     )
@@ -476,10 +642,52 @@ impl<N: Node> Entry<N> {
 
     // Swap in the new state, and return the receiver.
     *state = next_state;
+    {
+      let mut run_info = self.run_info.lock();
+      run_info.run_count += 1;
+      for waiter in run_info.start_waiters.drain(..) {
+        let _ = waiter.send(());
+      }
+    }
 
     async move { receiver.recv().await.ok_or_else(N::Error::invalidated)? }.boxed()
   }
 
+  ///
+  /// If this Node is Running, cancels the run and returns true.
+  ///
+  /// Dropping the pending value of the run signals the spawned work to exit (dropping the
+  /// requests for its own dependencies, which will be canceled in turn if nothing else is waiting
+  /// for them), and causes any waiters to fail with `NodeError::invalidated`.
+  ///
+  pub(crate) fn cancel_running(&mut self) -> bool {
+    let mut state = self.state.lock();
+    if !matches!(*state, EntryState::Running { .. }) {
+      return false;
+    }
+
+    *state = match mem::replace(&mut *state, EntryState::initial()) {
+      EntryState::Running {
+        run_token,
+        pending_value,
+        generation,
+        previous_result,
+        ..
+      } => {
+        test_trace_log!("Explicitly canceling {:?} of {}.", run_token, self.node);
+        std::mem::drop(pending_value);
+        // Increment the RunToken so that the outcome of the canceled work is ignored.
+        EntryState::NotStarted {
+          run_token: run_token.next(),
+          generation,
+          previous_result,
+        }
+      }
+      s => s,
+    };
+    true
+  }
+
   ///
   /// Called from the Executor when a Node is cancelled.
   ///
@@ -528,6 +736,9 @@ impl<N: Node> Entry<N> {
   /// complete _while_ a batch of nodes are being marked as dirty, and this exclusive access ensures
   /// that can't happen.
   ///
+  /// Returns whether the run succeeded if the Node was completed, or None if the run had been
+  /// superseded (and so was discarded).
+  ///
   /// See also: `Self::cancel`.
   ///
   pub(crate) fn complete(
@@ -539,7 +750,7 @@ impl<N: Node> Entry<N> {
     result: Option<Result<N::Item, N::Error>>,
     has_uncacheable_deps: bool,
     _graph: &mut super::InnerGraph<N>,
-  ) {
+  ) -> Option<bool> {
     let mut state = self.state.lock();
 
     // We care about exactly one case: a Running state with the same run_token. All other states
@@ -553,17 +764,30 @@ impl<N: Node> Entry<N> {
           "Not completing node {:?} because it was invalidated.",
           self.node
         );
-        return;
+        return None;
       }
     }
 
+    let succeeded = !matches!(result, Some(Err(_)));
+    let should_cache = result
+      .as_ref()
+      .map(|result| self.node.should_cache_result(result))
+      .unwrap_or(true);
+
     *state = match mem::replace(&mut *state, EntryState::initial()) {
       EntryState::Running {
         run_token,
         mut generation,
         mut previous_result,
+        start_time,
         ..
       } => {
+        {
+          let mut run_info = self.run_info.lock();
+          run_info.last_run_duration = Some(start_time.elapsed());
+          run_info.last_run_completed = Some(Instant::now());
+          run_info.last_run_failed = !succeeded;
+        }
         match result {
           Some(Err(e)) => {
             if let Some(previous_result) = previous_result.as_mut() {
@@ -578,19 +802,30 @@ impl<N: Node> Entry<N> {
           }
           Some(Ok(result)) => {
             let cacheable = self.cacheable_with_output(Some(&result));
-            let next_result: EntryResult<N> =
+            let mut next_result: EntryResult<N> =
               EntryResult::new(result, context, cacheable, has_uncacheable_deps);
             if Some(next_result.as_ref()) != previous_result.as_ref().map(EntryResult::as_ref) {
               // Node was re-executed (ie not cleaned) and had a different result value.
               generation = generation.next()
             };
             sender.send(Ok((next_result.as_ref().clone(), generation)));
-            EntryState::Completed {
-              result: next_result,
-              pollers: Vec::new(),
-              dep_generations,
-              run_token,
-              generation,
+            if should_cache {
+              EntryState::Completed {
+                result: next_result,
+                pollers: Vec::new(),
+                dep_generations,
+                run_token,
+                generation,
+              }
+            } else {
+              // The result was returned to its waiters, but it is only kept as a previous result
+              // (in order to compare its value to the next one) so that the Node will re-run.
+              next_result.dirty();
+              EntryState::NotStarted {
+                run_token: run_token.next(),
+                generation,
+                previous_result: Some(next_result),
+              }
             }
           }
           None => {
@@ -616,6 +851,7 @@ impl<N: Node> Entry<N> {
       }
       s => s,
     };
+    Some(succeeded)
   }
 
   ///
@@ -646,6 +882,57 @@ impl<N: Node> Entry<N> {
     }
   }
 
+  ///
+  /// Get the wall-clock time taken by the most recently completed run of this entry, if any.
+  ///
+  pub fn last_run_duration(&self) -> Option<Duration> {
+    self.run_info.lock().last_run_duration
+  }
+
+  ///
+  /// Get the time at which the most recently completed run of this entry completed, if any.
+  ///
+  pub fn last_run_completed(&self) -> Option<Instant> {
+    self.run_info.lock().last_run_completed
+  }
+
+  ///
+  /// Returns true if the most recently completed run of this entry failed.
+  ///
+  pub fn last_run_failed(&self) -> bool {
+    self.run_info.lock().last_run_failed
+  }
+
+  ///
+  /// Get the number of times that this entry has been started (either to run or to be cleaned).
+  ///
+  pub fn run_count(&self) -> u64 {
+    self.run_info.lock().run_count
+  }
+
+  ///
+  /// Get the current RunToken and Generation of this entry, under a single acquisition of its lock.
+  ///
+  pub(crate) fn versions(&self) -> (RunToken, Generation) {
+    match *self.state.lock() {
+      EntryState::NotStarted {
+        run_token,
+        generation,
+        ..
+      }
+      | EntryState::Running {
+        run_token,
+        generation,
+        ..
+      }
+      | EntryState::Completed {
+        run_token,
+        generation,
+        ..
+      } => (run_token, generation),
+    }
+  }
+
   ///
   /// Clears the state of this Node, forcing it to be recomputed.
   ///
@@ -701,6 +988,24 @@ impl<N: Node> Entry<N> {
     };
   }
 
+  ///
+  /// Dirties this Node, and forgets the Generations of the dependencies which were consumed by its
+  /// most recent completion, which will cause it to re-run (rather than be cleaned) the next time
+  /// it is requested. Used when its dependencies have been changed from outside of the Node.
+  ///
+  pub(crate) fn dirty_and_forget_dependencies(&mut self, graph: &mut super::InnerGraph<N>) {
+    self.dirty(graph);
+    if let EntryState::Completed {
+      ref mut dep_generations,
+      ..
+    } = *self.state.lock()
+    {
+      // NB: A Node which has no dependencies would be considered clean regardless, but a Node
+      // whose dependencies have been replaced has at least one.
+      dep_generations.clear();
+    }
+  }
+
   ///
   /// Dirties this Node, which will cause it to examine its dependencies the next time it is
   /// requested, and re-run if any of them have changed generations.
@@ -768,6 +1073,63 @@ impl<N: Node> Entry<N> {
     }
   }
 
+  ///
+  /// Classifies the current state of this entry relative to the given context.
+  ///
+  pub fn status(&self, context: &N::Context) -> NodeStatus {
+    match *self.state.lock() {
+      EntryState::NotStarted { .. } if self.last_run_failed() => {
+        NodeStatus::Completed { ok: false }
+      }
+      EntryState::NotStarted { .. } => NodeStatus::NotStarted,
+      EntryState::Running { .. } => NodeStatus::Running,
+      EntryState::Completed { ref result, .. } if result.is_clean(context) => {
+        NodeStatus::Completed { ok: true }
+      }
+      EntryState::Completed { .. } => NodeStatus::Dirty,
+    }
+  }
+
+  ///
+  /// Returns true if this entry has completed, and has since been marked dirty.
+  ///
+  pub(crate) fn is_dirty(&self) -> bool {
+    match *self.state.lock() {
+      EntryState::Completed { ref result, .. } => matches!(result, EntryResult::Dirty(..)),
+      EntryState::NotStarted { .. } | EntryState::Running { .. } => false,
+    }
+  }
+
+  ///
+  /// If this entry has completed, returns the Generations of its dependencies which were consumed
+  /// by that completion (whether or not it has since been dirtied).
+  ///
+  pub(crate) fn recorded_dep_generations(&self) -> Option<Vec<Generation>> {
+    match *self.state.lock() {
+      EntryState::Completed {
+        ref dep_generations,
+        ..
+      } => Some(dep_generations.clone()),
+      _ => None,
+    }
+  }
+
+  ///
+  /// If this entry has completed, but its result is not valid for the given context (and so will
+  /// be cleaned or re-run when next requested), returns the Generations of its dependencies which
+  /// were consumed by that completion.
+  ///
+  pub(crate) fn stale_dep_generations(&self, context: &N::Context) -> Option<Vec<Generation>> {
+    match *self.state.lock() {
+      EntryState::Completed {
+        ref result,
+        ref dep_generations,
+        ..
+      } if !result.is_clean(context) => Some(dep_generations.clone()),
+      _ => None,
+    }
+  }
+
   pub fn is_clean(&self, context: &N::Context) -> bool {
     match *self.state.lock() {
       EntryState::NotStarted {
@@ -32,44 +32,520 @@
 pub mod entry;
 mod node;
 
-pub use crate::entry::{Entry, EntryState};
-use crate::entry::{Generation, NodeResult, RunToken};
+use crate::entry::NodeResult;
+pub use crate::entry::{Entry, EntryState, Generation, NodeStatus, RunToken};
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::cmp::{self, Reverse};
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
 use std::fs::File;
-use std::hash::BuildHasherDefault;
-use std::io::{self, BufWriter, Write};
+use std::future::Future;
+use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
+use std::io::{self, BufWriter, Read, Write};
 use std::path::Path;
-use std::time::Duration;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 
 use async_value::AsyncValueSender;
 use fixedbitset::FixedBitSet;
 use fnv::FnvHasher;
-use futures::future;
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::stream::{self, Stream};
+use hashing::{Digest, WriterHasher};
 use log::{debug, info, warn};
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use petgraph::graph::DiGraph;
 use petgraph::visit::{EdgeRef, VisitMap, Visitable};
-use petgraph::Direction;
+pub use petgraph::Direction;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::{broadcast, watch, OwnedSemaphorePermit, Semaphore};
 use tokio::time::sleep;
 
-pub use crate::node::{EntryId, Node, NodeContext, NodeError, NodeVisualizer, Stats};
+pub use crate::node::{
+  EntryId, Node, NodeContext, NodeError, NodeVisualizer, StateAgeVisualizer, Stats,
+};
 
 type Fnv = BuildHasherDefault<FnvHasher>;
 
-type PGraph<N> = DiGraph<Entry<N>, f32, u32>;
+///
+/// The BuildHasher used for the Node-keyed collections of a Graph.
+///
+/// By default this is the (randomly keyed) `RandomState` of the standard library, but an
+/// alternative may be injected with `GraphOptions::hasher`.
+///
+/// NB: The built-in hashers are stored inline, but a custom BuildHasher (see `NodeHasher::new`)
+/// must be type-erased, and so allocates for each hash. Collections keyed by EntryId continue to
+/// use `Fnv`, since their keys are allocated by the Graph itself.
+///
+#[derive(Clone)]
+pub struct NodeHasher(NodeHasherKind);
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone)]
+enum NodeHasherKind {
+  Default(RandomState),
+  Fnv,
+  Custom(Arc<dyn Fn() -> Box<dyn Hasher> + Send + Sync>),
+}
+
+impl NodeHasher {
+  ///
+  /// A NodeHasher which uses the given BuildHasher: for example, a keyed hasher which is resistant
+  /// to adversarial Nodes. Because the BuildHasher's Hasher is boxed, each hash allocates.
+  ///
+  pub fn new<S>(build_hasher: S) -> NodeHasher
+  where
+    S: BuildHasher + Send + Sync + 'static,
+    S::Hasher: 'static,
+  {
+    NodeHasher(NodeHasherKind::Custom(Arc::new(move || {
+      Box::new(build_hasher.build_hasher())
+    })))
+  }
+
+  ///
+  /// A NodeHasher which uses the (unkeyed) FNV hash: faster than the default for small Nodes, but
+  /// vulnerable to collisions, and so only appropriate for Nodes from trusted sources.
+  ///
+  pub fn fnv() -> NodeHasher {
+    NodeHasher(NodeHasherKind::Fnv)
+  }
+}
+
+impl Default for NodeHasher {
+  fn default() -> NodeHasher {
+    NodeHasher(NodeHasherKind::Default(RandomState::new()))
+  }
+}
+
+impl BuildHasher for NodeHasher {
+  type Hasher = NodeHash;
+
+  fn build_hasher(&self) -> NodeHash {
+    match &self.0 {
+      NodeHasherKind::Default(random_state) => {
+        NodeHash(NodeHashKind::Default(random_state.build_hasher()))
+      }
+      NodeHasherKind::Fnv => NodeHash(NodeHashKind::Fnv(FnvHasher::default())),
+      NodeHasherKind::Custom(build_hasher) => NodeHash(NodeHashKind::Custom(build_hasher())),
+    }
+  }
+}
+
+///
+/// The Hasher created by a NodeHasher.
+///
+pub struct NodeHash(NodeHashKind);
+
+enum NodeHashKind {
+  Default(DefaultHasher),
+  Fnv(FnvHasher),
+  Custom(Box<dyn Hasher>),
+}
+
+impl Hasher for NodeHash {
+  fn finish(&self) -> u64 {
+    match &self.0 {
+      NodeHashKind::Default(hasher) => hasher.finish(),
+      NodeHashKind::Fnv(hasher) => hasher.finish(),
+      NodeHashKind::Custom(hasher) => hasher.finish(),
+    }
+  }
+
+  fn write(&mut self, bytes: &[u8]) {
+    match &mut self.0 {
+      NodeHashKind::Default(hasher) => hasher.write(bytes),
+      NodeHashKind::Fnv(hasher) => hasher.write(bytes),
+      NodeHashKind::Custom(hasher) => hasher.write(bytes),
+    }
+  }
+}
+
+///
+/// The underlying petgraph representation of a Graph, indexed by EntryId. See
+/// `Graph::with_raw_graph`.
+///
+pub type PGraph<N> = DiGraph<Entry<N>, DependencyEdge, u32>;
+
+// A copy of a PGraph weighted by (negated) durations for the computation of a critical path, with
+// an additional synthetic source node.
+type CriticalPathGraph<N> = DiGraph<Option<Entry<N>>, f64, u32>;
+
+///
+/// A dependency edge from a dependent to its dependency.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DependencyEdge {
+  // The weight of the edge, as computed by `Node::edge_weight`.
+  weight: f32,
+  // The Generation of the dependency that was consumed by the dependent: when the edge is added,
+  // this is the current Generation of the dependency, and it is updated when the dependent
+  // completes. See `Graph::prune_stale_edges`.
+  generation: Generation,
+}
+
+impl DependencyEdge {
+  ///
+  /// The weight of the edge, as computed by `Node::edge_weight`.
+  ///
+  pub fn weight(&self) -> f32 {
+    self.weight
+  }
+
+  ///
+  /// The Generation of the dependency that was last consumed by the dependent.
+  ///
+  pub fn generation(&self) -> Generation {
+    self.generation
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct InvalidationResult {
   pub cleared: usize,
   pub dirtied: usize,
+  // The number of transitive dependents which were skipped because they were already dirty.
+  pub skipped: usize,
+}
+
+///
+/// Totals of the invalidations of a Graph over its lifetime. See `Graph::invalidation_metrics`.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct InvalidationMetrics {
+  /// The number of invalidations.
+  pub invalidations: usize,
+  /// The total number of Nodes which were cleared as the roots of invalidations.
+  pub cleared: usize,
+  /// The total number of transitive dependents which were dirtied by invalidations.
+  pub dirtied: usize,
+  /// The total number of transitive dependents which were skipped because they were already dirty.
+  pub skipped: usize,
+}
+
+///
+/// The way in which a Node was affected by an invalidation.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InvalidationKind {
+  /// The Node was an invalidation root, and its value was cleared.
+  Cleared,
+  /// The Node transitively depended on an invalidation root, and was dirtied.
+  Dirtied,
+}
+
+///
+/// A callback which is invoked for each Node affected by an invalidation.
+///
+pub type InvalidationObserver<N> = Arc<dyn Fn(&N, InvalidationKind) + Send + Sync>;
+
+///
+/// A callback which is invoked each time a Node completes, with true if it succeeded, or false if
+/// it failed.
+///
+pub type CompletionObserver<N> = Arc<dyn Fn(&N, bool) + Send + Sync>;
+
+///
+/// How a Graph responds to a request for a dependency which would create a cycle (which does not
+/// contain any dirty Nodes). See `Graph::set_cycle_strategy`.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CycleStrategy {
+  /// The request fails with `NodeError::cyclic`.
+  Error,
+  /// The existing dependency edge with the lowest weight (see `Node::edge_weight`) on the cycle is
+  /// removed, and the request proceeds.
+  BreakLowestWeightEdge,
+}
+
+///
+/// A change to the state of a Node, published to subscribers of `Graph::subscribe`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum GraphEvent<N> {
+  /// The Node began running (or cleaning, if it was dirty).
+  NodeStarted(N),
+  /// The Node completed, successfully if `ok` is true.
+  NodeCompleted { node: N, ok: bool },
+  /// The Node transitively depended on an invalidation root, and was dirtied.
+  NodeDirtied(N),
+  /// The Node was an invalidation root, and its value was cleared.
+  NodeCleared(N),
+  /// The Node was running, and was canceled by `Graph::cancel_node`.
+  NodeCanceled(N),
+}
+
+// The number of GraphEvents which are retained for a subscriber which has fallen behind.
+const GRAPH_EVENT_CAPACITY: usize = 1024;
+
+///
+/// A predicate which is consulted before adding a dependency from a src Node to a dst Node, and
+/// which returns false if the dependency is forbidden.
+///
+pub type EdgePolicy<N> = Arc<dyn Fn(&N, &N) -> bool + Send + Sync>;
+
+///
+/// A batch of invalidations which arrived within a coalescing window, and which will be applied
+/// as a single invalidation once the window has elapsed. See
+/// `Graph::invalidate_from_roots_coalesced`.
+///
+struct CoalescedInvalidation<N> {
+  deadline: Instant,
+  predicates: Vec<Box<dyn Fn(&N) -> bool + Send>>,
+  // The number of callers which have joined the batch and not been canceled.
+  callers: usize,
+  // Sent the result of the batch once it has been applied. The Sender also identifies the batch.
+  sender: Arc<watch::Sender<Option<InvalidationResult>>>,
+  receiver: watch::Receiver<Option<InvalidationResult>>,
+}
+
+///
+/// A caller of `Graph::invalidate_from_roots_coalesced` which has joined a batch. If the last
+/// caller of a batch is canceled before the batch is applied, the batch is applied when it drops,
+/// so that an expired batch is never left pending.
+///
+struct CoalescedCaller<'a, N: Node> {
+  graph: &'a Graph<N>,
+  sender: Arc<watch::Sender<Option<InvalidationResult>>>,
+}
+
+impl<'a, N: Node> CoalescedCaller<'a, N> {
+  ///
+  /// Removes this caller's batch from the Graph if it is still pending, and if `leaving` is false
+  /// or this is the last caller of the batch.
+  ///
+  fn take_batch(&self, leaving: bool) -> Option<CoalescedInvalidation<N>> {
+    let mut pending = self.graph.coalesced_invalidation.lock();
+    let batch = pending
+      .as_mut()
+      .filter(|batch| Arc::ptr_eq(&batch.sender, &self.sender))?;
+    if leaving {
+      batch.callers -= 1;
+      if batch.callers > 0 {
+        return None;
+      }
+    }
+    pending.take()
+  }
+}
+
+impl<'a, N: Node> Drop for CoalescedCaller<'a, N> {
+  fn drop(&mut self) {
+    if let Some(batch) = self.take_batch(true) {
+      self.graph.apply_coalesced_invalidation(batch);
+    }
+  }
+}
+
+///
+/// A limit on the number of Nodes which may run concurrently. See `GraphOptions::max_concurrency`.
+///
+struct ConcurrencyLimit {
+  // The number of permits that the semaphore was created with.
+  max_concurrency: usize,
+  semaphore: Arc<Semaphore>,
+  // The permit slots of running Nodes. Each slot is owned by the future running its Node, so that
+  // its permit is released if the future is canceled.
+  running: Mutex<HashMap<EntryId, Weak<Mutex<PermitSlot>>>>,
+}
+
+impl ConcurrencyLimit {
+  async fn acquire(&self) -> OwnedSemaphorePermit {
+    self
+      .semaphore
+      .clone()
+      .acquire_owned()
+      .await
+      .expect("The concurrency semaphore is never closed.")
+  }
+}
+
+///
+/// The permit held by a running Node, which is released while the Node is waiting for any of its
+/// dependencies: otherwise, chains of dependencies longer than the limit would deadlock.
+///
+struct PermitSlot {
+  permit: Option<OwnedSemaphorePermit>,
+  // The number of requests for dependencies that the Node is currently waiting for.
+  waiting: usize,
+}
+
+///
+/// Marks a running Node as waiting for a dependency until dropped.
+///
+struct WaitingGuard(Arc<Mutex<PermitSlot>>);
+
+impl Drop for WaitingGuard {
+  fn drop(&mut self) {
+    self.0.lock().waiting -= 1;
+  }
+}
+
+///
+/// Marks a request as outstanding until dropped. See `Graph::outstanding_request`.
+///
+struct OutstandingRequest<'a>(&'a Mutex<usize>);
+
+impl<'a> Drop for OutstandingRequest<'a> {
+  fn drop(&mut self) {
+    *self.0.lock() -= 1;
+  }
+}
+
+///
+/// Owns the permit slot of a running Node, and unregisters it from the ConcurrencyLimit when
+/// dropped (unless it has already been replaced by the slot of a later run of the Node).
+///
+struct RunningGuard<'a> {
+  limit: &'a ConcurrencyLimit,
+  entry_id: EntryId,
+  slot: Arc<Mutex<PermitSlot>>,
+}
+
+impl<'a> Drop for RunningGuard<'a> {
+  fn drop(&mut self) {
+    let mut running = self.limit.running.lock();
+    if running
+      .get(&self.entry_id)
+      .map_or(false, |s| s.as_ptr() == Arc::as_ptr(&self.slot))
+    {
+      running.remove(&self.entry_id);
+    }
+  }
+}
+
+type InvalidationEvents<'a, N> = Option<&'a mut Vec<(N, InvalidationKind)>>;
+
+///
+/// Statistics about the size and state of a Graph, gathered at a single point in time.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GraphStats {
+  pub nodes: usize,
+  pub edges: usize,
+  pub not_started: usize,
+  pub running: usize,
+  pub completed: usize,
+  /// The number of Nodes which are not `Node::cacheable`.
+  pub uncacheable: usize,
+}
+
+///
+/// An error returned when a Graph does not finish draining within a timeout.
+///
+#[derive(Debug, Eq, PartialEq)]
+pub struct DrainTimeout {
+  /// The number of entries that were still running when the timeout elapsed.
+  pub running: usize,
+}
+
+///
+/// A cycle which would be created by adding a dependency from a src Node to a dst Node.
+///
+#[derive(Clone, Debug)]
+pub struct Cycle<N: Node> {
+  /// The entries in the cycle in dependency order (ie, each entry depends on the next): beginning
+  /// with the src, followed by the dst and the existing path from the dst back to the src, and
+  /// ending with the src again.
+  pub path: Vec<Entry<N>>,
+  /// The direction in which the existing path was discovered: Outgoing if by searching forward
+  /// from the dst, or Incoming if by searching backward from the src. The orientation of the
+  /// `path` is the same in either case.
+  pub searched: Direction,
+}
+
+///
+/// The critical (ie, longest) path through some subset of a Graph.
+///
+#[derive(Clone, Debug)]
+pub struct CriticalPath<N: Node> {
+  /// The total duration of the path.
+  pub total: Duration,
+  /// The entries on the path in dependency order, each paired with the duration that it
+  /// contributed to the total.
+  pub entries: Vec<(Entry<N>, Duration)>,
+}
+
+///
+/// The durations of the longest paths to each entry in the subgraph reachable from some roots,
+/// which allow `Graph::critical_path_incremental` to recompute the critical path of the subgraph
+/// after the durations of some of its Nodes have changed, without recomputing it from scratch.
+///
+pub struct CriticalPathCache<N: Node> {
+  roots: Vec<N>,
+  root_ids: HashSet<EntryId, Fnv>,
+  // For each entry reachable from the roots: its duration, and the duration of the longest path
+  // from a root which ends with it.
+  durations: HashMap<EntryId, Duration, Fnv>,
+  distances: HashMap<EntryId, Duration, Fnv>,
+  // The topology epoch of the graph when the cache was created, used to detect changes to its
+  // topology.
+  topology_epoch: usize,
+}
+
+///
+/// An owned rendering of the subgraph reachable from some roots, which can be written by
+/// `Graph::visualize` without holding the Graph's lock.
+///
+struct VisualizationSnapshot {
+  // For each rendered Node: its rendering, its color, and the renderings of its dependencies.
+  nodes: Vec<(String, String, Vec<String>)>,
+  // True if the walk was truncated by `max_nodes`.
+  truncated: bool,
+}
+
+impl VisualizationSnapshot {
+  fn write_to<W: Write>(&self, color_scheme: &str, f: &mut W) -> io::Result<()> {
+    f.write_all(b"digraph plans {\n")?;
+    f.write_fmt(format_args!("  node[colorscheme={}];\n", color_scheme))?;
+    f.write_all(b"  concentrate=true;\n")?;
+    f.write_all(b"  rankdir=TB;\n")?;
+
+    for (node_str, color, dep_strs) in &self.nodes {
+      // Write the node header.
+      f.write_fmt(format_args!(
+        "  \"{}\" [style=filled, fillcolor={}];\n",
+        node_str, color
+      ))?;
+
+      // Write an entry per edge.
+      for dep_str in dep_strs {
+        f.write_fmt(format_args!("    \"{}\" -> \"{}\"\n", node_str, dep_str))?;
+      }
+    }
+    if self.truncated {
+      // Render a placeholder to make it clear that the output is partial.
+      f.write_all(b"  \"... (truncated)\" [style=dashed];\n")?;
+    }
+
+    f.write_all(b"}\n")?;
+    Ok(())
+  }
 }
 
-type Nodes<N> = HashMap<N, EntryId>;
+type Nodes<N> = HashMap<N, EntryId, NodeHasher>;
 
 struct InnerGraph<N: Node> {
   nodes: Nodes<N>,
   pg: PGraph<N>,
+  // True while the Graph is draining: no new Nodes may be requested.
+  draining: bool,
+  // The roots of subtrees which are draining: no new dependencies may be requested from within them.
+  draining_roots: HashSet<N, NodeHasher>,
+  // The entries within the subtrees of the draining roots, recomputed when the roots change or
+  // entries or edges are removed. See `InnerGraph::topology_reduced`.
+  draining_ids: HashSet<EntryId, Fnv>,
+  // Nodes which are exempt from clearing and invalidation. See `Graph::pin`.
+  pinned: HashSet<N, NodeHasher>,
+  // Opaque key-value annotations on entries, which survive invalidation and clearing, but not the
+  // removal of the entry. See `Graph::set_metadata`.
+  metadata: HashMap<EntryId, HashMap<String, String>, Fnv>,
+  // Incremented by every addition or removal of an entry or edge (including the remapping of
+  // EntryIds by `gc_unreachable`), so that derived data can detect changes to the topology.
+  topology_epoch: usize,
 }
 
 impl<N: Node> InnerGraph<N> {
@@ -77,12 +553,10 @@ impl<N: Node> InnerGraph<N> {
     self.nodes.get(node)
   }
 
-  // TODO: Now that we never delete Entries, we should consider making this infallible.
   fn entry_for_id(&self, id: EntryId) -> Option<&Entry<N>> {
     self.pg.node_weight(id)
   }
 
-  // TODO: Now that we never delete Entries, we should consider making this infallible.
   fn entry_for_id_mut(&mut self, id: EntryId) -> Option<&mut Entry<N>> {
     self.pg.node_weight_mut(id)
   }
@@ -94,805 +568,4492 @@ impl<N: Node> InnerGraph<N> {
       .expect("The unsafe_entry_for_id method should only be used in read-only methods!")
   }
 
-  fn ensure_entry(&mut self, node: N) -> EntryId {
-    InnerGraph::ensure_entry_internal(&mut self.pg, &mut self.nodes, node)
-  }
-
-  fn ensure_entry_internal(pg: &mut PGraph<N>, nodes: &mut Nodes<N>, node: N) -> EntryId {
-    if let Some(&id) = nodes.get(&node) {
-      return id;
-    }
-
-    // New entry.
-    let id = pg.add_node(Entry::new(node.clone()));
-    nodes.insert(node, id);
-    id
+  ///
+  /// Records a change to the topology of the graph: must be called after every addition of an
+  /// entry or edge. See also `topology_reduced`.
+  ///
+  fn topology_changed(&mut self) {
+    self.topology_epoch += 1;
   }
 
   ///
-  /// Detect whether adding an edge from src to dst would create a cycle.
-  ///
-  /// Returns a path which would cause the cycle if an edge were added from src to dst, or None if
-  /// no cycle would be created.
+  /// Records a change to the topology of the graph which may have removed entries or edges (or
+  /// renumbered entries): must be called after every such change.
   ///
-  /// This strongly optimizes for the case of no cycles. If cycles are detected, this is very
-  /// expensive to call.
+  /// NB: Unlike additions (which cannot extend a draining subtree, since requests from within it
+  /// fail), removals may shrink a draining subtree, and so it is recomputed.
   ///
-  fn report_cycle(&self, src_id: EntryId, dst_id: EntryId) -> Option<Vec<Entry<N>>> {
-    if src_id == dst_id {
-      let entry = self.entry_for_id(src_id).unwrap();
-      return Some(vec![entry.clone(), entry.clone()]);
-    }
-    if !self.detect_cycle(src_id, dst_id) {
-      return None;
+  fn topology_reduced(&mut self) {
+    self.topology_changed();
+    if !self.draining_roots.is_empty() {
+      self.update_draining_ids();
     }
-    Self::shortest_path(&self.pg, dst_id, src_id).map(|mut path| {
-      path.reverse();
-      path.push(dst_id);
-      path
-        .into_iter()
-        .map(|index| self.entry_for_id(index).unwrap().clone())
-        .collect()
-    })
   }
 
   ///
-  /// Detect whether adding an edge from src to dst would create a cycle.
-  ///
-  /// Uses Dijkstra's algorithm, which is significantly cheaper than the Bellman-Ford, but keeps
-  /// less context around paths on the way.
+  /// Recomputes the entries within the subtrees of the draining roots.
   ///
-  fn detect_cycle(&self, src_id: EntryId, dst_id: EntryId) -> bool {
-    // Search either forward from the dst, or backward from the src.
-    let (root, needle, direction) = {
-      let out_from_dst = self.pg.neighbors(dst_id).count();
-      let in_to_src = self
-        .pg
-        .neighbors_directed(src_id, Direction::Incoming)
-        .count();
-      if out_from_dst < in_to_src {
-        (dst_id, src_id, Direction::Outgoing)
-      } else {
-        (src_id, dst_id, Direction::Incoming)
+  fn update_draining_ids(&mut self) {
+    let roots = self
+      .draining_roots
+      .iter()
+      .filter_map(|root| self.entry_id(root))
+      .cloned()
+      .collect();
+    let draining_ids = self
+      .walk(roots, Direction::Outgoing, |_| false)
+      .collect::<HashSet<_, Fnv>>();
+    self.draining_ids = draining_ids;
+  }
+
+  fn ensure_entry(&mut self, node: N) -> EntryId {
+    let node_count = self.pg.node_count();
+    let id = InnerGraph::ensure_entry_internal(&mut self.pg, &mut self.nodes, node);
+    if self.pg.node_count() != node_count {
+      self.topology_changed();
+      // A draining root may be marked before it is added.
+      if self
+        .draining_roots
+        .contains(self.unsafe_entry_for_id(id).node())
+      {
+        self.draining_ids.insert(id);
       }
-    };
+    }
+    id
+  }
 
-    // Search for an existing path from dst to src.
-    let mut roots = VecDeque::new();
-    roots.push_back(root);
+  ///
+  /// Adds a dependency edge from src to dst, which must not introduce a cycle.
+  ///
+  fn add_dependency_edge(&mut self, src_id: EntryId, dst_id: EntryId) {
+    // Edges are weighted by the src Node (by default, with a uniform weight of 1.0) so that we
+    // can Bellman-Ford over the graph. Cycle detection does not consider the weights.
+    let dst_entry = self.unsafe_entry_for_id(dst_id);
+    let weight = self
+      .unsafe_entry_for_id(src_id)
+      .node()
+      .edge_weight(dst_entry.node());
+    debug_assert!(weight >= 0.0, "Edge weights must be non-negative.");
+    let generation = dst_entry.generation();
     self
-      .walk(roots, direction, |_| false)
-      .any(|eid| eid == needle)
+      .pg
+      .add_edge(src_id, dst_id, DependencyEdge { weight, generation });
+    self.topology_changed();
+    // Requests cannot add edges from within a draining subtree, but other mutations (such as
+    // `preload`) can.
+    if self.draining_ids.contains(&src_id) && !self.draining_ids.contains(&dst_id) {
+      self.update_draining_ids();
+    }
   }
 
   ///
-  /// Compute and return one shortest path from `src` to `dst`.
-  ///
-  /// Uses Bellman-Ford, which is pretty expensive O(VE) as it has to traverse the whole graph and
-  /// keeping a lot of state on the way.
+  /// Removes the dependency edges (if any) from src to each of the given dsts.
   ///
-  fn shortest_path(graph: &PGraph<N>, src: EntryId, dst: EntryId) -> Option<Vec<EntryId>> {
-    let (_path_weights, paths) = petgraph::algo::bellman_ford(graph, src)
-      .expect("There should not be any negative edge weights");
-
-    let mut next = dst;
-    let mut path = vec![next];
-    while let Some(current) = paths[next.index()] {
-      path.push(current);
-      if current == src {
-        return Some(path);
+  fn remove_dependency_edges(&mut self, src_id: EntryId, dst_ids: &[EntryId]) {
+    let mut removed = false;
+    for &dst_id in dst_ids {
+      if let Some(edge) = self.pg.find_edge(src_id, dst_id) {
+        self.pg.remove_edge(edge);
+        removed = true;
       }
-      next = current;
     }
-    None
+    if removed {
+      self.topology_reduced();
+    }
   }
 
   ///
-  /// Compute the critical path for this graph.
+  /// Ensures that the given Nodes and the dependency edges between them are present in the graph.
   ///
-  /// The critical path is the longest path. For a directed acyclic graph, it is equivalent to a
-  /// shortest path algorithm.
-  ///
-  /// Modify the graph we have to fit into the expectations of the Bellman-Ford shortest graph
-  /// algorithm and use that to calculate the critical path.
+  /// If any edge would introduce a cycle, the graph is restored to its previous state, and the
+  /// Nodes in the cycle are returned.
   ///
-  fn critical_path<F>(&self, roots: &[N], duration: &F) -> (Duration, Vec<Entry<N>>)
-  where
-    F: Fn(&Entry<N>) -> Duration,
-  {
-    fn duration_into_weight(d: Duration) -> f64 {
-      -(d.as_nanos() as f64)
+  fn preload(&mut self, nodes: Vec<N>, edges: Vec<(N, N)>) -> Result<(), Vec<N>> {
+    let (node_count, edge_count) = (self.pg.node_count(), self.pg.edge_count());
+    for node in nodes {
+      self.ensure_entry(node);
     }
 
-    // First, let's map nodes to edges
-    let mut graph = self.pg.filter_map(
-      |_node_idx, node_weight| Some(Some(node_weight)),
-      |edge_idx, _edge_weight| {
-        let target_node = self.pg.raw_edges()[edge_idx.index()].target();
-        self
-          .pg
-          .node_weight(target_node)
-          .map(duration)
-          .map(duration_into_weight)
-      },
-    );
+    for (src, dst) in edges {
+      let src_id = self.ensure_entry(src);
+      let dst_id = self.ensure_entry(dst);
+      if self.pg.find_edge(src_id, dst_id).is_some() {
+        continue;
+      }
+      if let Some(cycle) = self.report_cycle(src_id, dst_id) {
+        // Remove the added edges and then Nodes, in descending index order so that the indices of
+        // the remaining items do not change.
+        while self.pg.edge_count() > edge_count {
+          let edge_id = petgraph::graph::EdgeIndex::new(self.pg.edge_count() - 1);
+          self.pg.remove_edge(edge_id);
+        }
+        while self.pg.node_count() > node_count {
+          let entry_id = EntryId::new(self.pg.node_count() - 1);
+          if let Some(entry) = self.pg.remove_node(entry_id) {
+            self.nodes.remove(entry.node());
+            self.metadata.remove(&entry_id);
+          }
+        }
+        self.topology_reduced();
+        return Err(
+          cycle
+            .path
+            .iter()
+            .map(|entry| entry.node().clone())
+            .collect(),
+        );
+      }
+      self.add_dependency_edge(src_id, dst_id);
+    }
+    Ok(())
+  }
 
-    // Add a single source that's a parent to all roots
-    let srcs = roots
+  fn ensure_entry_internal(pg: &mut PGraph<N>, nodes: &mut Nodes<N>, node: N) -> EntryId {
+    if let Some(&id) = nodes.get(&node) {
+      return id;
+    }
+
+    // New entry.
+    let id = pg.add_node(Entry::new(node.clone()));
+    nodes.insert(node, id);
+    id
+  }
+
+  ///
+  /// Returns the distinct neighbors of the given Node in the given direction (in the order in which
+  /// the edges to them were added), or None if the Node is not present in the graph.
+  ///
+  fn neighbor_nodes(&self, node: &N, direction: Direction) -> Option<Vec<N>> {
+    let id = *self.entry_id(node)?;
+    // Edges are iterated most-recently-added first: reverse them to get a stable insertion order.
+    let mut neighbor_ids = self
+      .pg
+      .neighbors_directed(id, direction)
+      .collect::<Vec<_>>();
+    neighbor_ids.reverse();
+    let mut seen = HashSet::<_, Fnv>::default();
+    Some(
+      neighbor_ids
+        .into_iter()
+        .filter(|&neighbor_id| seen.insert(neighbor_id))
+        .map(|neighbor_id| self.unsafe_entry_for_id(neighbor_id).node().clone())
+        .collect(),
+    )
+  }
+
+  fn estimated_size_bytes(&self) -> usize {
+    self
+      .pg
+      .node_indices()
+      .map(|entry_id| {
+        let entry = self.unsafe_entry_for_id(entry_id);
+        let node = entry.node();
+        let value_size = entry
+          .with_held_value(|item| std::mem::size_of::<N::Item>() + node.item_heap_size(item))
+          .unwrap_or(0);
+        std::mem::size_of::<Entry<N>>() + node.heap_size() + value_size
+      })
+      .sum()
+  }
+
+  ///
+  /// Removes all entries which are not reachable (in the Outgoing direction) from the given live
+  /// roots, returning the number of entries removed.
+  ///
+  /// Removing nodes from the underlying graph shifts the EntryIds of the remaining entries, so the
+  /// graph is rebuilt and the `nodes` map re-pointed at the new EntryIds. Because running entries
+  /// hold their own EntryIds, no entries are removed while any entry is running.
+  ///
+  fn gc_unreachable(&mut self, live_roots: &[N]) -> usize {
+    if self.running_entry_ids().next().is_some() {
+      return 0;
+    }
+
+    let root_ids = live_roots
       .iter()
-      .filter_map(|n| self.entry_id(n))
+      .filter_map(|node| self.entry_id(node))
       .cloned()
-      .collect::<Vec<_>>();
-    let src = graph.add_node(None);
-    for node in srcs {
-      graph.add_edge(
-        src,
-        node,
-        graph
-          .node_weight(node)
-          .map(|maybe_weight| {
-            maybe_weight
-              .map(duration)
-              .map(duration_into_weight)
-              .unwrap_or(0.)
-          })
-          .unwrap(),
-      );
+      .collect();
+    let reachable: HashSet<EntryId, Fnv> = self
+      .walk(root_ids, Direction::Outgoing, |_| false)
+      .collect();
+    let removed = self.pg.node_count() - reachable.len();
+    if removed == 0 {
+      return 0;
     }
 
-    let (weights, paths) =
-      petgraph::algo::bellman_ford(&graph, src).expect("The graph must be acyclic");
-    if let Some((index, total_duration)) = weights
-      .into_iter()
+    // The remaining entries keep their relative order, so re-point the metadata of each at its
+    // new EntryId, and drop the metadata of removed entries.
+    let new_ids: HashMap<EntryId, EntryId, Fnv> = self
+      .pg
+      .node_indices()
+      .filter(|entry_id| reachable.contains(entry_id))
       .enumerate()
-      .filter_map(|(i, weight)| {
-        // INFINITY is used for missing entries.
-        if weight == std::f64::INFINITY {
-          None
+      .map(|(index, entry_id)| (entry_id, EntryId::new(index)))
+      .collect();
+    self.metadata = self
+      .metadata
+      .drain()
+      .filter_map(|(entry_id, metadata)| Some((*new_ids.get(&entry_id)?, metadata)))
+      .collect();
+
+    // Because every reachable entry's dependencies are also reachable, no edges are lost other
+    // than those from unreachable entries.
+    self.pg = self.pg.filter_map(
+      |entry_id, entry| {
+        if reachable.contains(&entry_id) {
+          Some(entry.clone())
         } else {
-          Some((i, Duration::from_nanos(-weight as u64)))
-        }
-      })
-      .max_by(|(_, left_duration), (_, right_duration)| left_duration.cmp(right_duration))
-    {
-      let critical_path = {
-        let mut next = paths[index];
-        let mut path = vec![graph
-          .node_weight(petgraph::graph::NodeIndex::new(index))
-          .unwrap()
-          .unwrap()];
-        while next != Some(src) && next != None {
-          if let Some(entry) = graph.node_weight(next.unwrap()).unwrap() {
-            path.push(*entry);
-          }
-          next = paths[next.unwrap().index()];
+          None
         }
-        path.into_iter().rev().cloned().collect()
-      };
-      (total_duration, critical_path)
+      },
+      |_, &weight| Some(weight),
+    );
+    let pg = &self.pg;
+    let mut nodes = HashMap::with_hasher(self.nodes.hasher().clone());
+    nodes.extend(
+      pg.node_indices()
+        .map(|entry_id| (pg[entry_id].node().clone(), entry_id)),
+    );
+    self.nodes = nodes;
+    self.topology_reduced();
+    removed
+  }
+
+  ///
+  /// See `Graph::compact_edges`.
+  ///
+  fn compact_edges(&mut self) -> usize {
+    // Merge each group of parallel edges into the first (lowest-indexed) edge of the group.
+    let mut merged: HashMap<(EntryId, EntryId), (usize, DependencyEdge), Fnv> = HashMap::default();
+    for edge in self.pg.edge_references() {
+      let key = (edge.source(), edge.target());
+      let dependency_edge = *edge.weight();
+      merged
+        .entry(key)
+        .and_modify(|(_, kept)| {
+          kept.weight = kept.weight.max(dependency_edge.weight);
+          kept.generation = kept.generation.max(dependency_edge.generation);
+        })
+        .or_insert((edge.id().index(), dependency_edge));
+    }
+    let removed = self.pg.edge_count() - merged.len();
+    if removed == 0 {
+      return 0;
+    }
+
+    // NB: `retain_edges` visits edges in descending index order, and so the indices of the
+    // edges which have not yet been visited (including the first edge of each group) are stable.
+    self.pg.retain_edges(|pg, edge| {
+      let (src_id, dst_id) = pg.edge_endpoints(edge).unwrap();
+      merged[&(src_id, dst_id)].0 == edge.index()
+    });
+    self.topology_reduced();
+    for ((src_id, dst_id), (_, kept)) in merged {
+      if let Some(edge) = self.pg.find_edge(src_id, dst_id) {
+        self.pg[edge] = kept;
+      }
+    }
+    removed
+  }
+
+  ///
+  /// Records the Generations of the dependencies consumed by the given entry on its dependency
+  /// edges. Edges to dependencies which were not consumed (ie, which were added after the
+  /// Generations were collected) keep the Generation that they were added with.
+  ///
+  fn record_dependency_generations(
+    &mut self,
+    entry_id: EntryId,
+    dep_generations: &HashMap<EntryId, Generation, Fnv>,
+  ) {
+    let dep_edges: Vec<_> = self
+      .pg
+      .edges_directed(entry_id, Direction::Outgoing)
+      .filter_map(|edge| Some((edge.id(), *dep_generations.get(&edge.target())?)))
+      .collect();
+    for (edge_id, generation) in dep_edges {
+      self.pg[edge_id].generation = generation;
+    }
+  }
+
+  ///
+  /// Removes the outbound edges of the given Node (if it has completed, and has since been dirtied)
+  /// for which the Generation of the dependency that was consumed is older than the dependency's
+  /// current Generation.
+  ///
+  /// NB: The edges of a Node which is running or clean must not be pruned: because the Node would
+  /// not be dirtied, later invalidation of the dependency would not reach it.
+  ///
+  fn prune_stale_edges(&mut self, node: &N) {
+    let entry_id = if let Some(&entry_id) = self.entry_id(node) {
+      entry_id
     } else {
-      (Duration::from_nanos(0), vec![])
+      return;
+    };
+    if !self.unsafe_entry_for_id(entry_id).is_dirty() {
+      return;
+    }
+    let mut stale_edges: Vec<_> = self
+      .pg
+      .edges_directed(entry_id, Direction::Outgoing)
+      .filter(|edge| {
+        edge.weight().generation < self.unsafe_entry_for_id(edge.target()).generation()
+      })
+      .map(|edge| edge.id())
+      .collect();
+    // NB: Because `remove_edge` moves the last edge into the removed index, we remove edges in
+    // descending index order so that the remaining indices stay valid.
+    stale_edges.sort();
+    for edge_id in stale_edges.iter().rev() {
+      self.pg.remove_edge(*edge_id);
+    }
+    if !stale_edges.is_empty() {
+      self.topology_reduced();
     }
   }
 
   ///
-  /// Begins a Walk from the given roots.
+  /// Returns true if the given entry is within the subtree of a draining root (ie, if it is a
+  /// draining root, or a transitive dependency of one).
   ///
-  /// The Walk will iterate over all nodes that descend from the roots in the direction of
-  /// traversal but won't necessarily be in topological order.
+  fn in_draining_subtree(&self, entry_id: EntryId) -> bool {
+    self.draining_ids.contains(&entry_id)
+  }
+
+  fn running_entry_ids(&self) -> impl Iterator<Item = EntryId> + '_ {
+    self
+      .pg
+      .node_indices()
+      .filter(move |&entry_id| self.unsafe_entry_for_id(entry_id).is_running())
+  }
+
+  fn stats(&self) -> GraphStats {
+    let mut stats = GraphStats {
+      nodes: self.pg.node_count(),
+      edges: self.pg.edge_count(),
+      ..GraphStats::default()
+    };
+    for entry_id in self.pg.node_indices() {
+      let entry = self.unsafe_entry_for_id(entry_id);
+      match *entry.state.lock() {
+        EntryState::NotStarted { .. } => stats.not_started += 1,
+        EntryState::Running { .. } => stats.running += 1,
+        EntryState::Completed { .. } => stats.completed += 1,
+      }
+      if !entry.node().cacheable() {
+        stats.uncacheable += 1;
+      }
+    }
+    stats
+  }
+
   ///
-  fn walk<F: Fn(&EntryId) -> bool>(
-    &self,
-    roots: VecDeque<EntryId>,
-    direction: Direction,
-    stop_walking_predicate: F,
-  ) -> Walk<'_, N, F> {
-    Walk {
-      graph: self,
-      direction: direction,
-      deque: roots,
-      walked: self.pg.visit_map(),
-      stop_walking_predicate,
+  /// Computes the length of the longest path of outgoing edges from the given entry to a leaf.
+  ///
+  /// Uses an iterative depth first search in which the height of each visited entry is memoized.
+  /// Edges back to an entry which is still being visited are ignored, so the search terminates even
+  /// if the graph contains a cycle.
+  ///
+  fn node_height(&self, root: EntryId) -> usize {
+    let mut heights: HashMap<EntryId, usize, Fnv> = HashMap::default();
+    let mut visiting = HashSet::<EntryId, Fnv>::default();
+    let mut stack = vec![(root, false)];
+    while let Some((id, expanded)) = stack.pop() {
+      if expanded {
+        // All dependencies have been visited: the height is one more than the tallest of them.
+        visiting.remove(&id);
+        let height = self
+          .pg
+          .neighbors(id)
+          .filter_map(|dep| heights.get(&dep))
+          .max()
+          .map_or(0, |height| height + 1);
+        heights.insert(id, height);
+      } else if !heights.contains_key(&id) && visiting.insert(id) {
+        stack.push((id, true));
+        stack.extend(
+          self
+            .pg
+            .neighbors(id)
+            .filter(|dep| !visiting.contains(dep))
+            .map(|dep| (dep, false)),
+        );
+      }
     }
+    heights[&root]
   }
 
-  fn clear(&mut self) {
-    for eid in self.nodes.values() {
-      if let Some(entry) = self.pg.node_weight_mut(*eid) {
-        entry.clear(true);
+  ///
+  /// Find the longest path consisting only of uncacheable entries.
+  ///
+  /// Uses an iterative depth first search in which each entry records the length of (and next
+  /// entry in) the longest chain starting from it. Edges back to an entry which is still being
+  /// visited are ignored, so the search terminates even if the graph contains a cycle.
+  ///
+  fn longest_uncacheable_chain(&self) -> Vec<N> {
+    let uncacheable = |id: &EntryId| !self.unsafe_entry_for_id(*id).node().cacheable();
+    let mut longest: HashMap<EntryId, (usize, Option<EntryId>), Fnv> = HashMap::default();
+    let mut visiting = HashSet::<EntryId, Fnv>::default();
+
+    for root in self.pg.node_indices().filter(uncacheable) {
+      let mut stack = vec![(root, false)];
+      while let Some((id, expanded)) = stack.pop() {
+        if expanded {
+          // All dependencies have been visited: record the longest chain through any of them.
+          visiting.remove(&id);
+          let chain = self
+            .pg
+            .neighbors(id)
+            .filter_map(|dep| longest.get(&dep).map(|&(len, _)| (len, dep)))
+            .max_by_key(|&(len, _)| len)
+            .map_or((1, None), |(len, dep)| (len + 1, Some(dep)));
+          longest.insert(id, chain);
+        } else if !longest.contains_key(&id) && visiting.insert(id) {
+          stack.push((id, true));
+          stack.extend(
+            self
+              .pg
+              .neighbors(id)
+              .filter(|dep| uncacheable(dep) && !visiting.contains(dep))
+              .map(|dep| (dep, false)),
+          );
+        }
       }
     }
+
+    let mut next = self
+      .pg
+      .node_indices()
+      .filter(|id| longest.contains_key(id))
+      .max_by_key(|id| (longest[id].0, Reverse(*id)));
+    let mut chain = Vec::new();
+    while let Some(id) = next {
+      chain.push(self.unsafe_entry_for_id(id).node().clone());
+      next = longest[&id].1;
+    }
+    chain
   }
 
   ///
-  /// Clears the values of all "invalidation root" Nodes and dirties their transitive dependents.
+  /// Find all cycles that are currently present in the graph, as the members of each strongly
+  /// connected component containing more than one Node (or a single Node with an edge to itself).
   ///
-  /// An "invalidation root" is a Node in the graph which can be invalidated for a reason other
-  /// than having had its dependencies changed.
+  /// Members are returned in the order that they were added to the graph.
   ///
-  fn invalidate_from_roots<P: Fn(&N) -> bool>(&mut self, predicate: P) -> InvalidationResult {
-    // Collect all entries that will be cleared.
-    let root_ids: HashSet<_, Fnv> = self
-      .nodes
+  fn find_cycles(&self) -> Vec<Vec<N>> {
+    petgraph::algo::tarjan_scc(&self.pg)
+      .into_iter()
+      .filter(|component| {
+        component.len() > 1 || self.pg.find_edge(component[0], component[0]).is_some()
+      })
+      .map(|mut component| {
+        component.sort();
+        component
+          .into_iter()
+          .map(|id| self.unsafe_entry_for_id(id).node().clone())
+          .collect()
+      })
+      .collect()
+  }
+
+  ///
+  /// See `Graph::condensation`.
+  ///
+  /// Members of each group, and the groups themselves (by their first member), are returned in the
+  /// order that they were added to the graph.
+  ///
+  fn condensation(&self) -> (Vec<Vec<N>>, Vec<(usize, usize)>) {
+    let mut components = petgraph::algo::tarjan_scc(&self.pg);
+    for component in &mut components {
+      component.sort();
+    }
+    components.sort_by_key(|component| component[0]);
+
+    let component_indices: HashMap<EntryId, usize, Fnv> = components
       .iter()
-      .filter_map(|(node, &entry_id)| {
-        // A NotStarted entry does not need clearing, and we can assume that its dependencies are
-        // either already dirtied, or have never observed a value for it. Filtering these redundant
-        // events helps to "debounce" invalidation (ie, avoid redundant re-dirtying of dependencies).
-        if predicate(node) && self.unsafe_entry_for_id(entry_id).is_started() {
+      .enumerate()
+      .flat_map(|(index, component)| component.iter().map(move |&id| (id, index)))
+      .collect();
+    let mut edges: Vec<(usize, usize)> = self
+      .pg
+      .raw_edges()
+      .iter()
+      .map(|edge| {
+        (
+          component_indices[&edge.source()],
+          component_indices[&edge.target()],
+        )
+      })
+      .filter(|(src, dst)| src != dst)
+      .collect();
+    edges.sort_unstable();
+    edges.dedup();
+
+    let groups = components
+      .into_iter()
+      .map(|component| {
+        component
+          .into_iter()
+          .map(|id| self.unsafe_entry_for_id(id).node().clone())
+          .collect()
+      })
+      .collect();
+    (groups, edges)
+  }
+
+  ///
+  /// Returns the Nodes reachable from the given roots in topological order, with dependencies
+  /// before their dependents. If the reachable subgraph contains a cycle, returns the members of
+  /// a cycle in the order that they were added to the graph.
+  ///
+  fn topological_order(&self, roots: &[N]) -> Result<Vec<N>, Vec<N>> {
+    let root_ids = roots
+      .iter()
+      .filter_map(|node| self.entry_id(node))
+      .cloned()
+      .collect();
+    let reachable: HashSet<_, Fnv> = self
+      .walk(root_ids, Direction::Outgoing, |_| false)
+      .collect();
+    let subgraph = self.pg.filter_map(
+      |entry_id, _| {
+        if reachable.contains(&entry_id) {
           Some(entry_id)
         } else {
           None
         }
-      })
-      .collect();
+      },
+      |_, _| Some(()),
+    );
+    let node_for =
+      |index: petgraph::graph::NodeIndex| self.unsafe_entry_for_id(subgraph[index]).node().clone();
 
-    // And their transitive dependencies, which will be dirtied.
-    //
-    // NB: We do not dirty "through" a running Uncacheable node and into its dependees: this is
-    // because all Uncacheable nodes are currently also implicitly "not restartable", and thus
-    // shouldn't be interrupted unless all dependees have gone away for other reasons (such as the
-    // Session having ended).
-    //
-    // TODO: As part of #9462, we'll likely want to split the "not restartable" property from the
-    // Uncacheable property, because #9462 will deal with nodes that are Uncacheable/per-Session, but
-    // also restartable.
-    let transitive_ids: Vec<_> = self
-      .walk(
-        root_ids.iter().cloned().collect(),
-        Direction::Incoming,
-        |&entry_id| {
-          let entry = self.unsafe_entry_for_id(entry_id);
-          !entry.node().cacheable() && entry.is_running()
-        },
-      )
-      .filter(|eid| !root_ids.contains(eid))
+    match petgraph::algo::toposort(&subgraph, None) {
+      Ok(mut order) => {
+        // Dependencies are the targets of edges, and so are sorted after their dependents.
+        order.reverse();
+        Ok(order.into_iter().map(node_for).collect())
+      }
+      Err(cycle) => {
+        let mut component = petgraph::algo::tarjan_scc(&subgraph)
+          .into_iter()
+          .find(|component| component.contains(&cycle.node_id()))
+          .expect("A node in a cycle must be in a strongly connected component.");
+        component.sort();
+        Err(component.into_iter().map(node_for).collect())
+      }
+    }
+  }
+
+  ///
+  /// Detect whether adding an edge from src to dst would create a cycle.
+  ///
+  /// Returns the Cycle which would be created if an edge were added from src to dst, or None if
+  /// no cycle would be created.
+  ///
+  /// This strongly optimizes for the case of no cycles. If cycles are detected, this is very
+  /// expensive to call.
+  ///
+  fn report_cycle(&self, src_id: EntryId, dst_id: EntryId) -> Option<Cycle<N>> {
+    if src_id == dst_id {
+      let entry = self.entry_for_id(src_id).unwrap();
+      return Some(Cycle {
+        path: vec![entry.clone(), entry.clone()],
+        searched: Direction::Outgoing,
+      });
+    }
+    let searched = self.search_for_cycle(src_id, dst_id)?;
+    // The shortest path from the dst to the src is returned in reverse, ending with the dst.
+    Self::shortest_path(&self.pg, dst_id, src_id).map(|mut path| {
+      path.reverse();
+      path.insert(0, src_id);
+      Cycle {
+        path: path
+          .into_iter()
+          .map(|index| self.entry_for_id(index).unwrap().clone())
+          .collect(),
+        searched,
+      }
+    })
+  }
+
+  ///
+  /// Returns the diameter of the subgraph reachable from the given roots (treating its edges as
+  /// undirected), and an entry with the minimum eccentricity (the first added, if there are
+  /// multiple).
+  ///
+  fn diameter(&self, roots: &[N]) -> (usize, Option<EntryId>) {
+    let root_ids = roots
+      .iter()
+      .filter_map(|node| self.entry_id(node))
+      .cloned()
       .collect();
+    let reachable: HashSet<_, Fnv> = self
+      .walk(root_ids, Direction::Outgoing, |_| false)
+      .collect();
+    let mut ids = reachable.iter().cloned().collect::<Vec<_>>();
+    ids.sort();
 
-    let invalidation_result = InvalidationResult {
-      cleared: root_ids.len(),
-      dirtied: transitive_ids.len(),
+    // The greatest distance from the given entry to any other entry in its (undirected) component.
+    let eccentricity = |id: EntryId| {
+      let mut distances: HashMap<EntryId, usize, Fnv> = HashMap::default();
+      distances.insert(id, 0);
+      let mut queue = VecDeque::new();
+      queue.push_back(id);
+      let mut eccentricity = 0;
+      while let Some(current) = queue.pop_front() {
+        let distance = distances[&current];
+        eccentricity = cmp::max(eccentricity, distance);
+        for neighbor in self.pg.neighbors_undirected(current) {
+          if reachable.contains(&neighbor) && !distances.contains_key(&neighbor) {
+            distances.insert(neighbor, distance + 1);
+            queue.push_back(neighbor);
+          }
+        }
+      }
+      eccentricity
+    };
+
+    let mut diameter = 0;
+    let mut center: Option<(usize, EntryId)> = None;
+    for id in ids {
+      let eccentricity = eccentricity(id);
+      diameter = cmp::max(diameter, eccentricity);
+      if center.map_or(true, |(min_eccentricity, _)| {
+        eccentricity < min_eccentricity
+      }) {
+        center = Some((eccentricity, id));
+      }
+    }
+    (diameter, center.map(|(_, id)| id))
+  }
+
+  ///
+  /// Removes the dependency edge with the lowest weight between consecutive entries of the given
+  /// path (the first, if there are multiple), and returns its dependent and dependency. Returns
+  /// None if the path contains no edges.
+  ///
+  fn remove_lowest_weight_edge(&mut self, path: &[Entry<N>]) -> Option<(N, N)> {
+    let edge = path
+      .windows(2)
+      .filter_map(|pair| {
+        let dependent = *self.entry_id(pair[0].node())?;
+        let dependency = *self.entry_id(pair[1].node())?;
+        self.pg.find_edge(dependent, dependency)
+      })
+      .min_by(|left, right| {
+        self.pg[*left]
+          .weight
+          .partial_cmp(&self.pg[*right].weight)
+          .unwrap_or(cmp::Ordering::Equal)
+      })?;
+    let (dependent, dependency) = self.pg.edge_endpoints(edge)?;
+    let nodes = (
+      self.unsafe_entry_for_id(dependent).node().clone(),
+      self.unsafe_entry_for_id(dependency).node().clone(),
+    );
+    self.pg.remove_edge(edge);
+    self.topology_reduced();
+    Some(nodes)
+  }
+
+  ///
+  /// Detect whether adding an edge from src to dst would create a cycle.
+  ///
+  fn detect_cycle(&self, src_id: EntryId, dst_id: EntryId) -> bool {
+    self.search_for_cycle(src_id, dst_id).is_some()
+  }
+
+  ///
+  /// Search for an existing path from dst to src (which would create a cycle if an edge were added
+  /// from src to dst), and if one exists, return the direction in which it was found.
+  ///
+  fn search_for_cycle(&self, src_id: EntryId, dst_id: EntryId) -> Option<Direction> {
+    let direction = self.cycle_search_direction(src_id, dst_id);
+    self.search_for_cycle_in_direction(src_id, dst_id, direction)
+  }
+
+  ///
+  /// Chooses the direction in which to search for a cycle: forward from the dst if it has fewer
+  /// dependencies than the src has dependents, and otherwise backward from the src.
+  ///
+  /// The degrees are compared by advancing both neighbor iterators in lockstep, which costs time
+  /// proportional to the smaller of the two degrees (rather than to their sum, as counting both
+  /// would). Since the search will visit at least that many neighbors anyway, this makes choosing
+  /// a direction no more expensive than the search itself, even for Nodes with very high fan-in.
+  /// Caching the degrees would make this O(1), but would need to be maintained on every edge
+  /// mutation.
+  ///
+  fn cycle_search_direction(&self, src_id: EntryId, dst_id: EntryId) -> Direction {
+    let mut out_from_dst = self.pg.neighbors(dst_id);
+    let mut in_to_src = self.pg.neighbors_directed(src_id, Direction::Incoming);
+    loop {
+      match (out_from_dst.next(), in_to_src.next()) {
+        (None, Some(_)) => return Direction::Outgoing,
+        (_, None) => return Direction::Incoming,
+        (Some(_), Some(_)) => {}
+      }
+    }
+  }
+
+  ///
+  /// Like `search_for_cycle`, but searches in the given direction: forward from the dst
+  /// (`Outgoing`), or backward from the src (`Incoming`).
+  ///
+  /// Uses Dijkstra's algorithm, which is significantly cheaper than the Bellman-Ford, but keeps
+  /// less context around paths on the way.
+  ///
+  fn search_for_cycle_in_direction(
+    &self,
+    src_id: EntryId,
+    dst_id: EntryId,
+    direction: Direction,
+  ) -> Option<Direction> {
+    let (root, needle) = match direction {
+      Direction::Outgoing => (dst_id, src_id),
+      Direction::Incoming => (src_id, dst_id),
+    };
+
+    // Search for an existing path from dst to src.
+    let mut roots = VecDeque::new();
+    roots.push_back(root);
+    if self
+      .walk(roots, direction, |_| false)
+      .any(|eid| eid == needle)
+    {
+      Some(direction)
+    } else {
+      None
+    }
+  }
+
+  ///
+  /// Enumerates up to `max_paths` paths from `src` to `dst` by depth first search, in the order in
+  /// which they are discovered. The dependencies of each entry are visited in order of their
+  /// rendered Nodes, so that the order does not depend on the order in which edges were added.
+  ///
+  /// The search only visits entries from which `dst` is reachable: because every branch of the
+  /// search then ends in a path, the cost is bounded by `max_paths` rather than by the (possibly
+  /// exponential) number of paths which do not reach `dst`.
+  ///
+  fn all_paths(&self, src_id: EntryId, dst_id: EntryId, max_paths: usize) -> Vec<Vec<EntryId>> {
+    let mut paths = Vec::new();
+    if max_paths == 0 {
+      return paths;
+    } else if src_id == dst_id {
+      paths.push(vec![src_id]);
+      return paths;
+    }
+
+    let reaches_dst: HashSet<_, Fnv> = self
+      .walk(vec![dst_id].into(), Direction::Incoming, |_| false)
+      .collect();
+    if !reaches_dst.contains(&src_id) {
+      return paths;
+    }
+
+    // The dependencies of an entry which reach dst, sorted in reverse so that they can be popped
+    // in order.
+    let dependencies = |id: EntryId| {
+      let mut dependencies = self
+        .pg
+        .neighbors(id)
+        .filter(|dep_id| reaches_dst.contains(dep_id))
+        .collect::<Vec<_>>();
+      dependencies
+        .sort_by_cached_key(|&id| Reverse(self.unsafe_entry_for_id(id).node().to_string()));
+      dependencies
+    };
+
+    // The current path, and for each entry on it, the dependencies which remain to be visited.
+    let mut path = vec![src_id];
+    let mut remaining = vec![dependencies(src_id)];
+    while let Some(dependencies_to_visit) = remaining.last_mut() {
+      match dependencies_to_visit.pop() {
+        Some(dep_id) if dep_id == dst_id => {
+          let mut found = path.clone();
+          found.push(dst_id);
+          paths.push(found);
+          if paths.len() >= max_paths {
+            break;
+          }
+        }
+        // NB: The graph is acyclic, but we avoid revisiting entries on the path regardless, so that
+        // only simple paths are reported.
+        Some(dep_id) if path.contains(&dep_id) => {}
+        Some(dep_id) => {
+          path.push(dep_id);
+          remaining.push(dependencies(dep_id));
+        }
+        None => {
+          path.pop();
+          remaining.pop();
+        }
+      }
+    }
+    paths
+  }
+
+  ///
+  /// Compute and return one shortest path from `src` to `dst`.
+  ///
+  /// Uses Bellman-Ford, which is pretty expensive O(VE) as it has to traverse the whole graph and
+  /// keeping a lot of state on the way.
+  ///
+  fn shortest_path(graph: &PGraph<N>, src: EntryId, dst: EntryId) -> Option<Vec<EntryId>> {
+    let weighted = graph.map(|_, _| (), |_, edge| edge.weight);
+    let (path_weights, _) = petgraph::algo::bellman_ford(&weighted, src)
+      .expect("There should not be any negative edge weights");
+    let paths = Self::tie_broken_predecessors(&weighted, src, &path_weights, |id| {
+      graph[id].node().to_string()
+    });
+
+    let mut next = dst;
+    let mut path = vec![next];
+    while let Some(current) = paths[next.index()] {
+      path.push(current);
+      if current == src {
+        return Some(path);
+      }
+      next = current;
+    }
+    None
+  }
+
+  ///
+  /// Selects a predecessor for each node on a shortest path from `src`, given the distances to
+  /// each node computed by Bellman-Ford.
+  ///
+  /// The predecessors computed by Bellman-Ford depend on the order in which edges were added to
+  /// the graph, which is not deterministic. Instead, among the predecessors on equally short paths
+  /// to a node, this chooses the predecessor with the smallest `key`.
+  ///
+  fn tie_broken_predecessors<NW, W, K>(
+    graph: &DiGraph<NW, W, u32>,
+    src: EntryId,
+    distances: &[W],
+    key: impl Fn(EntryId) -> K,
+  ) -> Vec<Option<EntryId>>
+  where
+    W: petgraph::algo::FloatMeasure,
+    K: Ord,
+  {
+    graph
+      .node_indices()
+      .map(|id| {
+        if id == src {
+          return None;
+        }
+        let predecessors = graph
+          .edges_directed(id, Direction::Incoming)
+          .filter(|edge| {
+            let source_distance = distances[edge.source().index()];
+            source_distance != W::infinite()
+              && source_distance + *edge.weight() == distances[id.index()]
+          })
+          .map(|edge| edge.source())
+          .collect::<Vec<_>>();
+        // Only compute keys if there is a tie.
+        if predecessors.len() > 1 {
+          predecessors
+            .into_iter()
+            .min_by_key(|&predecessor| key(predecessor))
+        } else {
+          predecessors.into_iter().next()
+        }
+      })
+      .collect()
+  }
+
+  ///
+  /// Compute the critical path for this graph.
+  ///
+  /// The critical path is the longest path. For a directed acyclic graph, it is equivalent to a
+  /// shortest path algorithm.
+  ///
+  /// Modify the graph we have to fit into the expectations of the Bellman-Ford shortest graph
+  /// algorithm and use that to calculate the critical path.
+  ///
+  /// The weight of each edge is the duration of its target entry plus the `edge_cost` of the edge
+  /// (as src, dst, and the weight recorded for the edge by `Node::edge_weight`).
+  ///
+  fn critical_path<F, G>(&self, roots: &[N], duration: &F, edge_cost: &G) -> CriticalPath<N>
+  where
+    F: Fn(&Entry<N>) -> Duration,
+    G: Fn(&Entry<N>, &Entry<N>, f32) -> Duration,
+  {
+    let (graph, src) = self.critical_path_graph(roots, duration, edge_cost);
+    Self::critical_path_of(&graph, src)
+  }
+
+  ///
+  /// Creates an owned copy of this graph weighted for `critical_path_of`, with a synthetic source
+  /// (which is returned along with the copy) that is a parent to all of the given roots.
+  ///
+  fn critical_path_graph<F, G>(
+    &self,
+    roots: &[N],
+    duration: &F,
+    edge_cost: &G,
+  ) -> (CriticalPathGraph<N>, EntryId)
+  where
+    F: Fn(&Entry<N>) -> Duration,
+    G: Fn(&Entry<N>, &Entry<N>, f32) -> Duration,
+  {
+    fn duration_into_weight(d: Duration) -> f64 {
+      -(d.as_nanos() as f64)
+    }
+
+    // First, let's map nodes to edges
+    let mut graph = self.pg.filter_map(
+      |_node_idx, node_weight| Some(Some(node_weight.clone())),
+      |edge_idx, edge_weight| {
+        let edge = &self.pg.raw_edges()[edge_idx.index()];
+        let src_entry = self.pg.node_weight(edge.source())?;
+        let dst_entry = self.pg.node_weight(edge.target())?;
+        Some(duration_into_weight(
+          duration(dst_entry) + edge_cost(src_entry, dst_entry, edge_weight.weight),
+        ))
+      },
+    );
+
+    // Add a single source that's a parent to all roots
+    let srcs = roots
+      .iter()
+      .filter_map(|n| self.entry_id(n))
+      .cloned()
+      .collect::<Vec<_>>();
+    let src = graph.add_node(None);
+    for node in srcs {
+      let weight = graph
+        .node_weight(node)
+        .map(|maybe_weight| {
+          maybe_weight
+            .as_ref()
+            .map(duration)
+            .map(duration_into_weight)
+            .unwrap_or(0.)
+        })
+        .unwrap();
+      graph.add_edge(src, node, weight);
+    }
+    (graph, src)
+  }
+
+  ///
+  /// Computes the critical path of a graph created by `critical_path_graph`, starting from its
+  /// synthetic source.
+  ///
+  fn critical_path_of(graph: &CriticalPathGraph<N>, src: EntryId) -> CriticalPath<N> {
+    let (weights, _) = petgraph::algo::bellman_ford(graph, src).expect("The graph must be acyclic");
+    let node_key = |id: EntryId| {
+      graph[id]
+        .as_ref()
+        .map(|entry| entry.node().to_string())
+        .unwrap_or_default()
+    };
+    let paths = Self::tie_broken_predecessors(graph, src, &weights, node_key);
+    let weight_into_duration = |weight: f64| Duration::from_nanos(-weight as u64);
+    // Among equally long paths, end at the entry with the smallest key.
+    let end = weights
+      .iter()
+      .enumerate()
+      // INFINITY is used for missing entries.
+      .filter(|&(_, &weight)| weight != std::f64::INFINITY)
+      .map(|(i, &weight)| (i, weight_into_duration(weight)))
+      .max_by(|(left, left_duration), (right, right_duration)| {
+        left_duration
+          .cmp(right_duration)
+          .then_with(|| node_key(EntryId::new(*right)).cmp(&node_key(EntryId::new(*left))))
+      });
+    let (end_index, total) = if let Some(end) = end {
+      end
+    } else {
+      return CriticalPath {
+        total: Duration::from_nanos(0),
+        entries: vec![],
+      };
+    };
+
+    // Walk backward from the end of the path to the synthetic source, recording the duration that
+    // each entry contributed to the total as the difference between its distance and that of its
+    // predecessor.
+    let mut entries = vec![];
+    let mut current = petgraph::graph::NodeIndex::new(end_index);
+    while let Some(entry) = graph.node_weight(current).and_then(|entry| entry.as_ref()) {
+      let previous = paths[current.index()];
+      let previous_duration = previous
+        .map(|previous| weight_into_duration(weights[previous.index()]))
+        .unwrap_or_default();
+      entries.push((
+        entry.clone(),
+        weight_into_duration(weights[current.index()]) - previous_duration,
+      ));
+      if let Some(previous) = previous {
+        current = previous;
+      } else {
+        break;
+      }
+    }
+    entries.reverse();
+    CriticalPath { total, entries }
+  }
+
+  ///
+  /// Computes a CriticalPathCache for the subgraph reachable from the given roots.
+  ///
+  fn critical_path_cache<F>(&self, roots: &[N], duration: &F) -> CriticalPathCache<N>
+  where
+    F: Fn(&Entry<N>) -> Duration,
+  {
+    let root_ids: HashSet<_, Fnv> = roots
+      .iter()
+      .filter_map(|n| self.entry_id(n))
+      .cloned()
+      .collect();
+    let reachable: HashSet<_, Fnv> = self
+      .walk(
+        root_ids.iter().cloned().collect(),
+        Direction::Outgoing,
+        |_| false,
+      )
+      .collect();
+    let mut cache = CriticalPathCache {
+      roots: roots.to_vec(),
+      root_ids,
+      durations: reachable
+        .iter()
+        .map(|&id| (id, duration(self.unsafe_entry_for_id(id))))
+        .collect(),
+      distances: HashMap::default(),
+      topology_epoch: self.topology_epoch,
+    };
+    self.update_critical_path_distances(&mut cache, &reachable);
+    cache
+  }
+
+  ///
+  /// Updates the given cache for changes to the durations of the given Nodes, by recomputing the
+  /// distances of only the entries which are reachable from them. If the topology of the graph
+  /// has changed since the cache was created, it is recomputed from scratch instead.
+  ///
+  fn update_critical_path_cache<F>(
+    &self,
+    cache: &mut CriticalPathCache<N>,
+    changed: &[N],
+    duration: &F,
+  ) where
+    F: Fn(&Entry<N>) -> Duration,
+  {
+    if cache.topology_epoch != self.topology_epoch {
+      *cache = self.critical_path_cache(&cache.roots, duration);
+      return;
+    }
+
+    let changed_ids: VecDeque<_> = changed
+      .iter()
+      .filter_map(|n| self.entry_id(n))
+      .cloned()
+      .filter(|id| cache.durations.contains_key(id))
+      .collect();
+    for &id in &changed_ids {
+      cache
+        .durations
+        .insert(id, duration(self.unsafe_entry_for_id(id)));
+    }
+    let affected: HashSet<_, Fnv> = self
+      .walk(changed_ids, Direction::Outgoing, |_| false)
+      .collect();
+    self.update_critical_path_distances(cache, &affected);
+  }
+
+  ///
+  /// Recomputes the distances in the cache of the given entries, which must include all of the
+  /// entries reachable from any of them.
+  ///
+  fn update_critical_path_distances(
+    &self,
+    cache: &mut CriticalPathCache<N>,
+    ids: &HashSet<EntryId, Fnv>,
+  ) {
+    // Visit the entries in topological order (with dependents before their dependencies) using
+    // Kahn's algorithm, so that the distances of the predecessors of each entry are up to date
+    // when it is visited.
+    let mut in_degrees: HashMap<EntryId, usize, Fnv> = ids
+      .iter()
+      .map(|&id| {
+        let in_degree = self
+          .pg
+          .neighbors_directed(id, Direction::Incoming)
+          .filter(|predecessor| ids.contains(predecessor))
+          .count();
+        (id, in_degree)
+      })
+      .collect();
+    let mut ready: Vec<_> = in_degrees
+      .iter()
+      .filter(|&(_, &in_degree)| in_degree == 0)
+      .map(|(&id, _)| id)
+      .collect();
+    while let Some(id) = ready.pop() {
+      let longest_predecessor = self
+        .pg
+        .neighbors_directed(id, Direction::Incoming)
+        .filter_map(|predecessor| cache.distances.get(&predecessor).cloned())
+        .max();
+      // A root may additionally begin a path.
+      let root = if cache.root_ids.contains(&id) {
+        Some(Duration::from_nanos(0))
+      } else {
+        None
+      };
+      let start = longest_predecessor
+        .into_iter()
+        .chain(root)
+        .max()
+        .unwrap_or_default();
+      cache.distances.insert(id, start + cache.durations[&id]);
+
+      for dependency in self.pg.neighbors(id) {
+        if let Some(in_degree) = in_degrees.get_mut(&dependency) {
+          *in_degree -= 1;
+          if *in_degree == 0 {
+            ready.push(dependency);
+          }
+        }
+      }
+    }
+  }
+
+  ///
+  /// Returns the critical path described by the given (up to date) cache, breaking ties in the
+  /// same way as `critical_path`.
+  ///
+  fn critical_path_from_cache(&self, cache: &CriticalPathCache<N>) -> (Duration, Vec<Entry<N>>) {
+    let node_key = |id: EntryId| self.unsafe_entry_for_id(id).node().to_string();
+    // Among equally long paths, end at the entry with the smallest key.
+    let end = cache
+      .distances
+      .iter()
+      .max_by(|(left, left_duration), (right, right_duration)| {
+        left_duration
+          .cmp(right_duration)
+          .then_with(|| node_key(**right).cmp(&node_key(**left)))
+      });
+    let (mut current, total) = match end {
+      Some((&id, &total)) if total > Duration::from_nanos(0) => (id, total),
+      _ => return (Duration::from_nanos(0), vec![]),
+    };
+
+    // Walk backward from the end of the path to a root, choosing the predecessor with the smallest
+    // key among those on equally long paths.
+    let mut path = vec![self.unsafe_entry_for_id(current).clone()];
+    loop {
+      let start = cache.distances[&current] - cache.durations[&current];
+      if cache.root_ids.contains(&current) && start == Duration::from_nanos(0) {
+        break;
+      }
+      let predecessors = self
+        .pg
+        .neighbors_directed(current, Direction::Incoming)
+        .filter(|predecessor| cache.distances.get(predecessor) == Some(&start))
+        .collect::<Vec<_>>();
+      let predecessor = if predecessors.len() > 1 {
+        predecessors.into_iter().min_by_key(|&id| node_key(id))
+      } else {
+        predecessors.into_iter().next()
+      };
+      if let Some(predecessor) = predecessor {
+        current = predecessor;
+        path.push(self.unsafe_entry_for_id(current).clone());
+      } else {
+        break;
+      }
+    }
+    path.reverse();
+    (total, path)
+  }
+
+  ///
+  /// Begins a Walk from the given roots.
+  ///
+  /// The Walk will iterate over all nodes that descend from the roots in the direction of
+  /// traversal but won't necessarily be in topological order.
+  ///
+  fn walk<F: Fn(&EntryId) -> bool>(
+    &self,
+    roots: VecDeque<EntryId>,
+    direction: Direction,
+    stop_walking_predicate: F,
+  ) -> Walk<'_, N, F> {
+    self.walk_to_depth(roots, direction, None, stop_walking_predicate)
+  }
+
+  ///
+  /// Like `walk`, but does not expand past Nodes at the given (optional) depth from the roots.
+  ///
+  fn walk_to_depth<F: Fn(&EntryId) -> bool>(
+    &self,
+    roots: VecDeque<EntryId>,
+    direction: Direction,
+    max_depth: Option<usize>,
+    stop_walking_predicate: F,
+  ) -> Walk<'_, N, F> {
+    Walk {
+      graph: self,
+      direction: direction,
+      deque: roots.into_iter().map(|id| (id, 0)).collect(),
+      walked: self.pg.visit_map(),
+      max_depth,
+      prioritized: false,
+      stop_walking_predicate,
+    }
+  }
+
+  fn is_pinned(&self, entry_id: EntryId) -> bool {
+    !self.pinned.is_empty()
+      && self
+        .pinned
+        .contains(self.unsafe_entry_for_id(entry_id).node())
+  }
+
+  fn clear_matching<P: Fn(&N) -> bool>(&mut self, predicate: P) {
+    for (node, eid) in &self.nodes {
+      if self.pinned.contains(node) || !predicate(node) {
+        continue;
+      }
+      if let Some(entry) = self.pg.node_weight_mut(*eid) {
+        entry.clear(true);
+      }
+    }
+  }
+
+  fn reset_node(&mut self, node: &N) {
+    let entry_id = match self.entry_id(node) {
+      Some(&entry_id) if !self.pinned.contains(node) => entry_id,
+      _ => return,
+    };
+
+    // NB: Because `remove_edge` changes EdgeIndex values, we remove edges one at a time.
+    while let Some(dep_edge) = self
+      .pg
+      .edges_directed(entry_id, Direction::Outgoing)
+      .next()
+      .map(|edge| edge.id())
+    {
+      self.pg.remove_edge(dep_edge);
+    }
+    self.topology_reduced();
+    if let Some(entry) = self.pg.node_weight_mut(entry_id) {
+      entry.clear(false);
+    }
+  }
+
+  ///
+  /// Replaces the dependency edge from the src to the old dst (if any) with an edge to the new dst,
+  /// unless the new edge would create a cycle. The src (which must not be pinned) is dirtied such
+  /// that it will re-run (rather than be cleaned) the next time it is requested, and its transitive
+  /// dependents are dirtied.
+  ///
+  fn replace_dependency(
+    &mut self,
+    src_id: EntryId,
+    old_dst_id: Option<EntryId>,
+    new_dst_id: EntryId,
+    mut events: InvalidationEvents<N>,
+  ) -> Result<InvalidationResult, Cycle<N>> {
+    // NB: A path from the new dst back to the src cannot pass through the src's own dependency
+    // edges, so it is safe to check for a cycle before removing the old edge, which means that
+    // there is nothing to roll back if one is found.
+    if self.pg.find_edge(src_id, new_dst_id).is_none() {
+      if let Some(cycle) = self.report_cycle(src_id, new_dst_id) {
+        return Err(cycle);
+      }
+      self.add_dependency_edge(src_id, new_dst_id);
+    }
+    if let Some(old_edge) = old_dst_id
+      .filter(|&old_dst_id| old_dst_id != new_dst_id)
+      .and_then(|old_dst_id| self.pg.find_edge(src_id, old_dst_id))
+    {
+      self.pg.remove_edge(old_edge);
+      self.topology_reduced();
+    }
+
+    if let Some(events) = events.as_deref_mut() {
+      events.push((
+        self.unsafe_entry_for_id(src_id).node().clone(),
+        InvalidationKind::Dirtied,
+      ));
+    }
+    let mut invalidation_result = self.dirty_dependents(src_id, events);
+    if let Some(mut entry) = self.pg.node_weight_mut(src_id).cloned() {
+      entry.dirty_and_forget_dependencies(self);
+      invalidation_result.dirtied += 1;
+    }
+    Ok(invalidation_result)
+  }
+
+  ///
+  /// Dirties the transitive dependents of the given entry (without affecting the entry itself),
+  /// unless it is pinned.
+  ///
+  fn dirty_dependents(
+    &mut self,
+    entry_id: EntryId,
+    events: InvalidationEvents<N>,
+  ) -> InvalidationResult {
+    let mut root_ids = HashSet::default();
+    root_ids.insert(entry_id);
+    let (_, skipped_ids, transitive_ids) = self.invalidation_targets(root_ids);
+
+    if let Some(events) = events {
+      events.extend(transitive_ids.iter().map(|&id| {
+        (
+          self.unsafe_entry_for_id(id).node().clone(),
+          InvalidationKind::Dirtied,
+        )
+      }));
+    }
+
+    for id in &transitive_ids {
+      if let Some(mut entry) = self.pg.node_weight_mut(*id).cloned() {
+        entry.dirty(self);
+      }
+    }
+
+    InvalidationResult {
+      cleared: 0,
+      dirtied: transitive_ids.len(),
+      skipped: skipped_ids.len(),
+    }
+  }
+
+  ///
+  /// See `Graph::verify_invariants`.
+  ///
+  #[cfg(any(test, feature = "verify"))]
+  fn verify_invariants(&self) -> Result<(), String> {
+    for (node, &entry_id) in &self.nodes {
+      match self.entry_for_id(entry_id) {
+        Some(entry) if entry.node() == node => {}
+        Some(entry) => {
+          return Err(format!(
+            "{} maps to {:?}, which contains {}",
+            node,
+            entry_id,
+            entry.node()
+          ))
+        }
+        None => return Err(format!("{} maps to missing {:?}", node, entry_id)),
+      }
+    }
+    if self.nodes.len() != self.pg.node_count() {
+      return Err(format!(
+        "{} Nodes are mapped, but the graph contains {} entries",
+        self.nodes.len(),
+        self.pg.node_count()
+      ));
+    }
+
+    for edge in self.pg.raw_edges() {
+      let (src_id, dst_id) = (edge.source(), edge.target());
+      if self.entry_for_id(src_id).is_none() || self.entry_for_id(dst_id).is_none() {
+        return Err(format!(
+          "Edge from {:?} to {:?} references a missing entry",
+          src_id, dst_id
+        ));
+      }
+      // NB: Adding a dependency from a Node to itself is always reported as a cycle.
+      if src_id == dst_id {
+        return Err(format!(
+          "{} has a self-loop",
+          self.unsafe_entry_for_id(src_id).node()
+        ));
+      }
+    }
+
+    if let Some(entry_id) = self
+      .metadata
+      .keys()
+      .find(|&&entry_id| self.entry_for_id(entry_id).is_none())
+    {
+      return Err(format!("Metadata references missing {:?}", entry_id));
+    }
+    Ok(())
+  }
+
+  ///
+  /// See `Graph::sample_nodes_by_duration`.
+  ///
+  /// Each entry is assigned an exponentially distributed "arrival time" with a rate of its
+  /// duration, and the earliest `k` arrivals are retained in a reservoir: this is equivalent to
+  /// weighted sampling without replacement (Efraimidis and Spirakis' A-Res).
+  ///
+  fn sample_nodes_by_duration(&self, k: usize, seed: u64) -> Vec<N> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    // NB: Non-negative floats order the same as their bit patterns, which (unlike f64) are Ord.
+    let mut reservoir = BinaryHeap::with_capacity(k + 1);
+    for entry_id in self.pg.node_indices() {
+      let weight = match self.unsafe_entry_for_id(entry_id).last_run_duration() {
+        Some(duration) if duration > Duration::from_nanos(0) => duration.as_secs_f64(),
+        _ => continue,
+      };
+      let arrival = -(1.0 - rng.gen::<f64>()).ln() / weight;
+      reservoir.push((arrival.to_bits(), entry_id));
+      if reservoir.len() > k {
+        reservoir.pop();
+      }
+    }
+    reservoir
+      .into_sorted_vec()
+      .into_iter()
+      .map(|(_, entry_id)| self.unsafe_entry_for_id(entry_id).node().clone())
+      .collect()
+  }
+
+  fn dirty_all(&mut self) {
+    let entry_ids: Vec<_> = self
+      .pg
+      .node_indices()
+      .filter(|&id| !self.is_pinned(id))
+      .collect();
+    for id in entry_ids {
+      if let Some(mut entry) = self.pg.node_weight_mut(id).cloned() {
+        entry.dirty(self);
+      }
+    }
+  }
+
+  ///
+  /// Clears the values of all "invalidation root" Nodes and dirties their transitive dependents.
+  ///
+  /// An "invalidation root" is a Node in the graph which can be invalidated for a reason other
+  /// than having had its dependencies changed.
+  ///
+  /// If `events` is provided, each cleared and dirtied Node is recorded into it.
+  ///
+  fn invalidate_from_roots<P: Fn(&N) -> bool>(
+    &mut self,
+    predicate: P,
+    events: InvalidationEvents<N>,
+  ) -> InvalidationResult {
+    let root_ids = self.invalidation_root_ids(predicate);
+    self.invalidate_from_root_ids(root_ids, events)
+  }
+
+  ///
+  /// Returns the Nodes which `invalidate_from_roots` would clear or dirty for the given predicate,
+  /// without invalidating anything.
+  ///
+  fn preview_invalidation<P: Fn(&N) -> bool>(&self, predicate: P) -> Vec<N> {
+    let (root_ids, _, transitive_ids) =
+      self.invalidation_targets(self.invalidation_root_ids(predicate));
+    root_ids
+      .into_iter()
+      .chain(transitive_ids)
+      .map(|id| self.unsafe_entry_for_id(id).node().clone())
+      .collect()
+  }
+
+  ///
+  /// Collects all entries matching the predicate that will be cleared by an invalidation.
+  ///
+  fn invalidation_root_ids<P: Fn(&N) -> bool>(&self, predicate: P) -> HashSet<EntryId, Fnv> {
+    self
+      .nodes
+      .iter()
+      .filter_map(|(node, &entry_id)| {
+        // A NotStarted entry does not need clearing, and we can assume that its dependencies are
+        // either already dirtied, or have never observed a value for it. Filtering these redundant
+        // events helps to "debounce" invalidation (ie, avoid redundant re-dirtying of dependencies).
+        if predicate(node) && self.unsafe_entry_for_id(entry_id).is_started() {
+          Some(entry_id)
+        } else {
+          None
+        }
+      })
+      .collect()
+  }
+
+  ///
+  /// Clears the values of the given Nodes (those which have been started) and dirties their
+  /// transitive dependents. Equivalent to `invalidate_from_roots` with a predicate matching only
+  /// these Nodes, but without needing to scan the graph.
+  ///
+  fn invalidate_nodes(&mut self, nodes: &[N], events: InvalidationEvents<N>) -> InvalidationResult {
+    let root_ids: HashSet<_, Fnv> = nodes
+      .iter()
+      .filter_map(|node| self.entry_id(node))
+      .cloned()
+      .filter(|&entry_id| self.unsafe_entry_for_id(entry_id).is_started())
+      .collect();
+
+    self.invalidate_from_root_ids(root_ids, events)
+  }
+
+  ///
+  /// Clears the given root entries and dirties their transitive dependents.
+  ///
+  fn invalidate_from_root_ids(
+    &mut self,
+    root_ids: HashSet<EntryId, Fnv>,
+    events: InvalidationEvents<N>,
+  ) -> InvalidationResult {
+    let (root_ids, skipped_ids, transitive_ids) = self.invalidation_targets(root_ids);
+
+    let invalidation_result = InvalidationResult {
+      cleared: root_ids.len(),
+      dirtied: transitive_ids.len(),
+      skipped: skipped_ids.len(),
+    };
+
+    if let Some(events) = events {
+      events.extend(
+        root_ids
+          .iter()
+          .map(|&id| (id, InvalidationKind::Cleared))
+          .chain(
+            transitive_ids
+              .iter()
+              .map(|&id| (id, InvalidationKind::Dirtied)),
+          )
+          .map(|(id, kind)| (self.unsafe_entry_for_id(id).node().clone(), kind)),
+      );
+    }
+
+    // Clear roots and remove their outbound edges.
+    for id in &root_ids {
+      if let Some(entry) = self.pg.node_weight_mut(*id) {
+        entry.clear(false);
+      }
+    }
+    self.pg.retain_edges(|pg, edge| {
+      if let Some((src, _)) = pg.edge_endpoints(edge) {
+        !root_ids.contains(&src)
+      } else {
+        true
+      }
+    });
+    self.topology_reduced();
+
+    // Dirty transitive entries, but do not yet clear their output edges. We wait to clear
+    // outbound edges until we decide whether we can clean an entry: if we can, all edges are
+    // preserved; if we can't, they are cleared in `Graph::clear_deps`.
+    for id in &transitive_ids {
+      if let Some(mut entry) = self.pg.node_weight_mut(*id).cloned() {
+        entry.dirty(self);
+      }
+    }
+
+    invalidation_result
+  }
+
+  ///
+  /// Given the entries to clear for an invalidation, returns the (filtered) entries to clear,
+  /// the transitive entries which are already dirty, and the transitive entries to dirty.
+  ///
+  fn invalidation_targets(
+    &self,
+    root_ids: HashSet<EntryId, Fnv>,
+  ) -> (HashSet<EntryId, Fnv>, Vec<EntryId>, Vec<EntryId>) {
+    // Pinned entries are neither cleared, nor dirtied (and nor is anything only reachable through
+    // them).
+    let root_ids: HashSet<_, Fnv> = root_ids
+      .into_iter()
+      .filter(|&id| !self.is_pinned(id))
+      .collect();
+
+    // Collect the transitive dependencies of the roots, which will be dirtied.
+    //
+    // NB: We do not dirty "through" a running Uncacheable node and into its dependees: this is
+    // because all Uncacheable nodes are currently also implicitly "not restartable", and thus
+    // shouldn't be interrupted unless all dependees have gone away for other reasons (such as the
+    // Session having ended).
+    //
+    // TODO: As part of #9462, we'll likely want to split the "not restartable" property from the
+    // Uncacheable property, because #9462 will deal with nodes that are Uncacheable/per-Session, but
+    // also restartable.
+    //
+    // Transitive entries which are already dirty are skipped (although the walk continues through
+    // them), since dirtying them again would have no effect.
+    let (skipped_ids, transitive_ids): (Vec<_>, Vec<_>) = self
+      .walk(
+        root_ids.iter().cloned().collect(),
+        Direction::Incoming,
+        |&entry_id| {
+          let entry = self.unsafe_entry_for_id(entry_id);
+          (!entry.node().cacheable() && entry.is_running()) || self.is_pinned(entry_id)
+        },
+      )
+      .filter(|eid| !root_ids.contains(eid))
+      .partition(|&eid| self.unsafe_entry_for_id(eid).is_dirty());
+    (root_ids, skipped_ids, transitive_ids)
+  }
+
+  ///
+  /// Captures the subgraph reachable from the given roots for rendering by `Graph::visualize`.
+  ///
+  fn visualization_snapshot<V: NodeVisualizer<N>>(
+    &self,
+    visualizer: &mut V,
+    roots: &[N],
+    max_nodes: Option<usize>,
+    context: &N::Context,
+  ) -> VisualizationSnapshot {
+    let root_entries = roots
+      .iter()
+      .filter_map(|n| self.entry_id(n))
+      .cloned()
+      .collect();
+
+    let mut snapshot = VisualizationSnapshot {
+      nodes: Vec::new(),
+      truncated: false,
+    };
+    for (index, eid) in self
+      .walk(root_entries, Direction::Outgoing, |_| false)
+      .enumerate()
+    {
+      if max_nodes == Some(index) {
+        snapshot.truncated = true;
+        break;
+      }
+
+      let entry = self.unsafe_entry_for_id(eid);
+      let dep_strs = self
+        .pg
+        .neighbors(eid)
+        .map(|dep_id| self.unsafe_entry_for_id(dep_id).format(context))
+        .collect();
+      snapshot.nodes.push((
+        entry.format(context),
+        visualizer.color(entry, context),
+        dep_strs,
+      ));
+    }
+    snapshot
+  }
+
+  fn export_json<W: Write>(&self, roots: &[N], context: &N::Context, w: &mut W) -> io::Result<()> {
+    let root_entries = roots
+      .iter()
+      .filter_map(|n| self.entry_id(n))
+      .cloned()
+      .collect();
+    let reachable: Vec<EntryId> = self
+      .walk(root_entries, Direction::Outgoing, |_| false)
+      .collect();
+    let positions: HashMap<EntryId, usize, Fnv> = reachable
+      .iter()
+      .enumerate()
+      .map(|(position, &eid)| (eid, position))
+      .collect();
+
+    let mut nodes = Vec::with_capacity(reachable.len());
+    let mut edges = Vec::new();
+    for (position, &eid) in reachable.iter().enumerate() {
+      let entry = self.unsafe_entry_for_id(eid);
+      let state = match *entry.state.lock() {
+        EntryState::NotStarted { .. } => "not_started",
+        EntryState::Running { .. } => "running",
+        EntryState::Completed { .. } => "completed",
+      };
+      nodes.push(serde_json::json!({
+        "label": entry.format(context),
+        "state": state,
+      }));
+      edges.extend(
+        self
+          .pg
+          .neighbors(eid)
+          .map(|dep_id| serde_json::json!([position, positions[&dep_id]])),
+      );
+    }
+
+    serde_json::to_writer(w, &serde_json::json!({ "nodes": nodes, "edges": edges }))
+      .map_err(io::Error::from)
+  }
+
+  fn export_chrome_trace<W: Write>(&self, roots: &[N], w: &mut W) -> io::Result<()> {
+    let root_entries = roots
+      .iter()
+      .filter_map(|n| self.entry_id(n))
+      .cloned()
+      .collect();
+    let events = self
+      .walk(root_entries, Direction::Outgoing, |_| false)
+      .filter_map(|eid| {
+        let entry = self.unsafe_entry_for_id(eid);
+        entry.last_run_duration().map(|duration| {
+          serde_json::json!({
+            "name": entry.node().to_string(),
+            "cat": "node",
+            "ph": "X",
+            "ts": 0,
+            "dur": duration.as_micros() as u64,
+            "pid": 1,
+            "tid": 1,
+          })
+        })
+      })
+      .collect::<Vec<_>>();
+
+    serde_json::to_writer(
+      w,
+      &serde_json::json!({ "traceEvents": events, "displayTimeUnit": "ms" }),
+    )
+    .map_err(io::Error::from)
+  }
+
+  fn digest_manifest<W: Write>(
+    &self,
+    roots: &[N],
+    context: &N::Context,
+    w: &mut W,
+  ) -> io::Result<Digest> {
+    let root_entries = roots
+      .iter()
+      .filter_map(|n| self.entry_id(n))
+      .cloned()
+      .collect();
+    let mut remaining_dependencies: HashMap<EntryId, usize, Fnv> = self
+      .walk(root_entries, Direction::Outgoing, |_| false)
+      .map(|eid| (eid, self.pg.neighbors(eid).count()))
+      .collect();
+
+    // Visit entries in topological order (dependencies before dependents), breaking ties between
+    // entries which are ready at the same time by their rendering, so that the order does not
+    // depend on the order in which entries were added to the Graph.
+    let ready_entry =
+      |eid: EntryId| Reverse((self.unsafe_entry_for_id(eid).node().to_string(), eid));
+    let mut ready: BinaryHeap<_> = remaining_dependencies
+      .iter()
+      .filter(|(_, &count)| count == 0)
+      .map(|(&eid, _)| ready_entry(eid))
+      .collect();
+
+    let mut w = WriterHasher::new(w);
+    while let Some(Reverse((node_str, eid))) = ready.pop() {
+      let entry = self.unsafe_entry_for_id(eid);
+      let digests = entry
+        .peek_with(context, |item| entry.node().item_digests(item))
+        .unwrap_or_default();
+      w.write_all(node_str.as_bytes())?;
+      for digest in digests {
+        w.write_fmt(format_args!("\t{}/{}", digest.hash, digest.size_bytes))?;
+      }
+      w.write_all(b"\n")?;
+
+      for dependent_id in self.pg.neighbors_directed(eid, Direction::Incoming) {
+        if let Some(count) = remaining_dependencies.get_mut(&dependent_id) {
+          *count -= 1;
+          if *count == 0 {
+            ready.push(ready_entry(dependent_id));
+          }
+        }
+      }
+    }
+
+    let (digest, _) = w.finish();
+    Ok(digest)
+  }
+
+  ///
+  /// Returns a copy of this graph's nodes and edges, with fresh (NotStarted) entries.
+  ///
+  fn clone_topology(&self) -> InnerGraph<N> {
+    // NB: `map` preserves indices, so the `nodes` map remains valid for the copy.
+    let pg = self.pg.map(
+      |_, entry| Entry::new(entry.node().clone()),
+      |_, &edge| DependencyEdge {
+        generation: Generation::initial(),
+        ..edge
+      },
+    );
+    InnerGraph {
+      nodes: self.nodes.clone(),
+      pg,
+      draining: false,
+      draining_roots: HashSet::with_hasher(self.nodes.hasher().clone()),
+      draining_ids: HashSet::default(),
+      pinned: HashSet::with_hasher(self.nodes.hasher().clone()),
+      metadata: self.metadata.clone(),
+      topology_epoch: self.topology_epoch,
+    }
+  }
+
+  ///
+  /// Writes the identities of the Nodes in the graph and the edges between them (but not their
+  /// values) in a stable binary format.
+  ///
+  fn serialize_topology<W: Write>(&self, w: &mut W) -> io::Result<()>
+  where
+    N: Serialize,
+  {
+    let nodes: Vec<&N> = self
+      .pg
+      .node_indices()
+      .map(|id| self.unsafe_entry_for_id(id).node())
+      .collect();
+    let edges: Vec<(u32, u32)> = self
+      .pg
+      .raw_edges()
+      .iter()
+      .map(|edge| (edge.source().index() as u32, edge.target().index() as u32))
+      .collect();
+    bincode::serialize_into(w, &(nodes, edges))
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+  }
+
+  ///
+  /// Loads a topology written by `serialize_topology` into this (empty) graph. The loaded entries
+  /// are NotStarted.
+  ///
+  /// Fails if the Nodes do not round-trip to distinct entries, or if the edges reference unknown
+  /// entries or would introduce a cycle.
+  ///
+  fn load_topology<R: Read>(&mut self, r: &mut R) -> io::Result<()>
+  where
+    N: DeserializeOwned,
+  {
+    let invalid_data = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let (nodes, edges): (Vec<N>, Vec<(u32, u32)>) =
+      bincode::deserialize_from(r).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    for (index, node) in nodes.into_iter().enumerate() {
+      let entry_id = self.ensure_entry(node);
+      if entry_id.index() != index {
+        return Err(invalid_data(format!(
+          "Node {} did not round-trip to a distinct entry.",
+          self.unsafe_entry_for_id(entry_id).node()
+        )));
+      }
+    }
+    let node_count = self.pg.node_count();
+    for (src, dst) in edges {
+      let (src_id, dst_id) = (EntryId::new(src as usize), EntryId::new(dst as usize));
+      if src_id.index() >= node_count || dst_id.index() >= node_count {
+        return Err(invalid_data(format!(
+          "Edge from {} to {} referenced an unknown entry.",
+          src, dst
+        )));
+      }
+      // NB: Weights are not persisted, but are recomputed by `add_dependency_edge`, which only
+      // asserts that they are valid.
+      let (src_node, dst_node) = (
+        self.unsafe_entry_for_id(src_id).node(),
+        self.unsafe_entry_for_id(dst_id).node(),
+      );
+      let weight = src_node.edge_weight(dst_node);
+      if weight.is_nan() || weight < 0.0 {
+        return Err(invalid_data(format!(
+          "Edge from {} to {} had an invalid weight: {}.",
+          src_node, dst_node, weight
+        )));
+      }
+      self.add_dependency_edge(src_id, dst_id);
+    }
+
+    // Rather than checking each edge for a cycle as it is added, check the whole graph once.
+    if let Err(cycle) = petgraph::algo::toposort(&self.pg, None) {
+      let mut component = petgraph::algo::tarjan_scc(&self.pg)
+        .into_iter()
+        .find(|component| component.contains(&cycle.node_id()))
+        .expect("A node in a cycle must be in a strongly connected component.");
+      component.sort();
+      return Err(invalid_data(format!(
+        "Edges introduced a cycle between: {}.",
+        component
+          .into_iter()
+          .map(|entry_id| self.unsafe_entry_for_id(entry_id).node().to_string())
+          .collect::<Vec<_>>()
+          .join(", ")
+      )));
+    }
+    Ok(())
+  }
+
+  fn visit_live_reachable(&self, roots: &[N], context: &N::Context, f: impl FnMut(&N, &N::Item)) {
+    let root_ids = roots
+      .iter()
+      .filter_map(|node| self.entry_id(node))
+      .cloned()
+      .collect();
+    self.visit_live_internal(
+      self.walk(root_ids, Direction::Outgoing, |_| false),
+      context,
+      f,
+    )
+  }
+
+  fn visit_live(&self, context: &N::Context, f: impl FnMut(&N, &N::Item)) {
+    self.visit_live_internal(self.pg.node_indices(), context, f)
+  }
+
+  ///
+  /// Calls the given function with a reference to the value of each of the given entries which
+  /// currently has a clean value. Values are visited under their Entry locks rather than being
+  /// cloned, so the function must not attempt to access the Graph.
+  ///
+  fn visit_live_internal(
+    &self,
+    entryids: impl Iterator<Item = EntryId>,
+    context: &N::Context,
+    mut f: impl FnMut(&N, &N::Item),
+  ) {
+    for eid in entryids {
+      if let Some(entry) = self.entry_for_id(eid) {
+        entry.peek_with(context, |item| f(entry.node(), item));
+      }
+    }
+  }
+}
+
+///
+/// A DAG (enforced on mutation) of Entries.
+///
+pub struct Graph<N: Node> {
+  // NB: Requests for dependencies which already exist only need to read the topology of the
+  // Graph (the state of each Entry is behind its own lock), and so can proceed concurrently.
+  inner: RwLock<InnerGraph<N>>,
+  invalidation_delay: Duration,
+  // The maximum number of times that a request for a Node will be retried due to invalidation, or
+  // None to retry indefinitely.
+  max_retries: Option<usize>,
+  // If set, the initial and maximum delays of an exponential backoff between retries due to
+  // invalidation, which replaces the fixed `invalidation_delay`.
+  retry_backoff: Option<(Duration, Duration)>,
+  // The maximum number of cycle-causing paths containing dirty nodes that will be cleared (or of
+  // edges that will be broken: see `CycleStrategy`) while attempting to add a single dependency
+  // before giving up and reporting a cycle.
+  cycle_clearing_limit: usize,
+  // The maximum number of Nodes that the Graph may contain, or None for no limit.
+  max_nodes: Option<usize>,
+  // If set, the maximum number of Nodes which may run concurrently.
+  concurrency_limit: Option<ConcurrencyLimit>,
+  invalidation_observer: Mutex<Option<InvalidationObserver<N>>>,
+  completion_observer: Mutex<Option<CompletionObserver<N>>>,
+  events: broadcast::Sender<GraphEvent<N>>,
+  edge_policy: Mutex<Option<EdgePolicy<N>>>,
+  cycle_strategy: Mutex<CycleStrategy>,
+  coalesced_invalidation: Mutex<Option<CoalescedInvalidation<N>>>,
+  invalidation_metrics: Mutex<InvalidationMetrics>,
+  // The number of requests which hold EntryIds outside of the Graph lock (while waiting for or
+  // retrying their Nodes). See `Graph::gc_unreachable`.
+  outstanding_requests: Mutex<usize>,
+}
+
+///
+/// Options for the construction of a Graph. See `Graph::new_with_options`.
+///
+/// NB: These defaults are those of `Graph::new`.
+///
+#[derive(Clone)]
+pub struct GraphOptions {
+  /// The delay before a request for a Node which was invalidated while running is retried.
+  pub invalidation_delay: Duration,
+  /// The maximum number of times that a request for a Node will be retried when the Node is
+  /// invalidated while running, before the request fails with `NodeError::invalidated`, or None to
+  /// retry indefinitely.
+  pub max_retries: Option<usize>,
+  /// If set, the initial and maximum delays of an exponential backoff between retries, which
+  /// replaces the fixed `invalidation_delay`: the first retry is immediate, and subsequent retries
+  /// wait for a jittered delay which begins at the initial delay and doubles with each retry, up
+  /// to the maximum.
+  pub retry_backoff: Option<(Duration, Duration)>,
+  /// The maximum number of paths containing dirty nodes which will be cleared (or of edges which
+  /// will be broken: see `Graph::set_cycle_strategy`) while checking whether a new dependency
+  /// would create a cycle, before the cycle is reported.
+  pub cycle_clearing_limit: usize,
+  /// The maximum number of Nodes that the Graph may contain, or None for no limit. A request (or a
+  /// `poll`, `preload`, or `replace_dependency`) which would add a Node beyond the limit fails
+  /// with `NodeError::graph_full`. Nodes may be removed from a full Graph with
+  /// `Graph::gc_unreachable`.
+  pub max_nodes: Option<usize>,
+  /// If set, the maximum number of Nodes which may run at once, regardless of the number of
+  /// threads available to the runtime. Nodes which are completed from the Graph's memoized values
+  /// (or by cleaning, or `Node::try_load_cached`) do not count toward the limit, and a running
+  /// Node releases its share of the limit while it waits for its dependencies.
+  pub max_concurrency: Option<usize>,
+  /// The BuildHasher for the Graph's Node-keyed collections.
+  pub hasher: NodeHasher,
+}
+
+impl Default for GraphOptions {
+  fn default() -> Self {
+    Self {
+      invalidation_delay: Duration::from_millis(500),
+      max_retries: None,
+      retry_backoff: None,
+      cycle_clearing_limit: 10,
+      max_nodes: None,
+      max_concurrency: None,
+      hasher: NodeHasher::default(),
+    }
+  }
+}
+
+impl<N: Node> Graph<N> {
+  pub fn new() -> Graph<N> {
+    Self::new_with_options(GraphOptions::default())
+  }
+
+  pub fn new_with_invalidation_delay(invalidation_delay: Duration) -> Graph<N> {
+    Self::new_with_options(GraphOptions {
+      invalidation_delay,
+      ..GraphOptions::default()
+    })
+  }
+
+  pub fn new_with_options(options: GraphOptions) -> Graph<N> {
+    let inner = InnerGraph {
+      nodes: HashMap::with_hasher(options.hasher.clone()),
+      pg: DiGraph::new(),
+      draining: false,
+      draining_roots: HashSet::with_hasher(options.hasher.clone()),
+      draining_ids: HashSet::default(),
+      pinned: HashSet::with_hasher(options.hasher),
+      metadata: HashMap::default(),
+      topology_epoch: 0,
+    };
+    let concurrency_limit = options
+      .max_concurrency
+      .map(|max_concurrency| ConcurrencyLimit {
+        max_concurrency,
+        semaphore: Arc::new(Semaphore::new(max_concurrency)),
+        running: Mutex::new(HashMap::new()),
+      });
+    Graph {
+      inner: RwLock::new(inner),
+      invalidation_delay: options.invalidation_delay,
+      max_retries: options.max_retries,
+      retry_backoff: options.retry_backoff,
+      cycle_clearing_limit: options.cycle_clearing_limit,
+      max_nodes: options.max_nodes,
+      concurrency_limit,
+      invalidation_observer: Mutex::new(None),
+      completion_observer: Mutex::new(None),
+      events: broadcast::channel(GRAPH_EVENT_CAPACITY).0,
+      edge_policy: Mutex::new(None),
+      cycle_strategy: Mutex::new(CycleStrategy::Error),
+      coalesced_invalidation: Mutex::new(None),
+      invalidation_metrics: Mutex::new(InvalidationMetrics::default()),
+      outstanding_requests: Mutex::new(0),
+    }
+  }
+
+  ///
+  /// The options that this Graph was constructed with.
+  ///
+  fn options(&self) -> GraphOptions {
+    GraphOptions {
+      invalidation_delay: self.invalidation_delay,
+      max_retries: self.max_retries,
+      retry_backoff: self.retry_backoff,
+      cycle_clearing_limit: self.cycle_clearing_limit,
+      max_nodes: self.max_nodes,
+      max_concurrency: self
+        .concurrency_limit
+        .as_ref()
+        .map(|limit| limit.max_concurrency),
+      hasher: self.inner.read().nodes.hasher().clone(),
+    }
+  }
+
+  ///
+  /// Runs the given future for the given Node while holding a permit from the Graph's concurrency
+  /// limit (if any).
+  ///
+  pub(crate) async fn run_with_permit<F: Future>(&self, entry_id: EntryId, run: F) -> F::Output {
+    let limit = if let Some(limit) = self.concurrency_limit.as_ref() {
+      limit
+    } else {
+      return run.await;
+    };
+
+    let slot = Arc::new(Mutex::new(PermitSlot {
+      permit: Some(limit.acquire().await),
+      waiting: 0,
+    }));
+    limit.running.lock().insert(entry_id, Arc::downgrade(&slot));
+    // NB: The slot is unregistered when this guard is dropped, including if the run is canceled.
+    let _running = RunningGuard {
+      limit,
+      entry_id,
+      slot,
+    };
+    run.await
+  }
+
+  ///
+  /// Awaits the given request for dependencies of the given src Node (if any). If the src Node is
+  /// holding a permit from the Graph's concurrency limit, it is released while waiting.
+  ///
+  async fn while_waiting<F: Future>(&self, src_id: Option<EntryId>, request: F) -> F::Output {
+    let (limit, slot) = match (self.concurrency_limit.as_ref(), src_id) {
+      (Some(limit), Some(src_id)) => {
+        let slot = limit.running.lock().get(&src_id).and_then(Weak::upgrade);
+        if let Some(slot) = slot {
+          (limit, slot)
+        } else {
+          return request.await;
+        }
+      }
+      _ => return request.await,
+    };
+
+    let guard = {
+      let mut s = slot.lock();
+      s.waiting += 1;
+      s.permit = None;
+      WaitingGuard(slot.clone())
+    };
+    let res = request.await;
+    drop(guard);
+
+    // If this was the last outstanding request, re-acquire a permit before continuing.
+    let needs_permit = |s: &PermitSlot| s.waiting == 0 && s.permit.is_none();
+    let reacquire = needs_permit(&slot.lock());
+    if reacquire {
+      let permit = limit.acquire().await;
+      let mut s = slot.lock();
+      if needs_permit(&s) {
+        s.permit = Some(permit);
+      }
+    }
+    res
+  }
+
+  pub fn len(&self) -> usize {
+    let inner = self.inner.read();
+    inner.nodes.len()
+  }
+
+  ///
+  /// Returns the number of dependency edges in the Graph.
+  ///
+  pub fn edge_count(&self) -> usize {
+    let inner = self.inner.read();
+    inner.pg.edge_count()
+  }
+
+  ///
+  /// Removes duplicate (parallel) dependency edges between the same pair of Nodes, keeping a
+  /// single edge with the largest weight of the duplicates, and returns the number of edges
+  /// removed.
+  ///
+  /// NB: A completed Node which had duplicate edges will re-run rather than be cleaned the next
+  /// time it is dirtied, because its number of dependencies will have changed.
+  ///
+  pub fn compact_edges(&self) -> usize {
+    let mut inner = self.inner.write();
+    inner.compact_edges()
+  }
+
+  ///
+  /// Returns the fraction of the maximum number of Nodes (see `GraphOptions::max_nodes`) which
+  /// the Graph currently contains, or None if the Graph has no maximum.
+  ///
+  pub fn utilization(&self) -> Option<f64> {
+    let max_nodes = self.max_nodes?;
+    Some(self.len() as f64 / max_nodes as f64)
+  }
+
+  ///
+  /// Marks the Graph as draining (or not). While draining, requests for Nodes fail with
+  /// `NodeError::invalidated`, but Nodes which are already running continue to run.
+  ///
+  /// Returns Err if the Graph was already in the requested state.
+  ///
+  #[allow(clippy::result_unit_err)]
+  pub fn mark_draining(&self, draining: bool) -> Result<(), ()> {
+    let mut inner = self.inner.write();
+    if inner.draining == draining {
+      return Err(());
+    }
+    inner.draining = draining;
+    Ok(())
+  }
+
+  ///
+  /// Returns true if the Graph is draining. See `Graph::mark_draining`.
+  ///
+  pub fn is_draining(&self) -> bool {
+    self.inner.read().draining
+  }
+
+  ///
+  /// Marks the subtree beneath the given root (ie, the root and its transitive dependencies) as
+  /// draining (or not). While draining, requests for dependencies from within the subtree fail with
+  /// `NodeError::invalidated`, while the rest of the Graph continues to accept requests.
+  ///
+  pub fn mark_subtree_draining(&self, root: &N, draining: bool) {
+    let mut inner = self.inner.write();
+    if draining {
+      inner.draining_roots.insert(root.clone());
+    } else {
+      inner.draining_roots.remove(root);
+    }
+    inner.update_draining_ids();
+  }
+
+  ///
+  /// Marks the Graph as draining, and then waits (without holding the Graph lock) until none of
+  /// its entries are running, or until the given timeout elapses.
+  ///
+  pub async fn drain_and_wait(&self, timeout: Duration) -> Result<(), DrainTimeout> {
+    // It is not an error for the Graph to already be draining.
+    let _ = self.mark_draining(true);
+
+    let deadline = Instant::now() + timeout;
+    loop {
+      let running = {
+        let inner = self.inner.read();
+        inner.running_entry_ids().count()
+      };
+      if running == 0 {
+        return Ok(());
+      }
+      let now = Instant::now();
+      if now >= deadline {
+        return Err(DrainTimeout { running });
+      }
+      sleep(std::cmp::min(deadline - now, Duration::from_millis(10))).await;
+    }
+  }
+
+  ///
+  /// Returns the Nodes which are currently running.
+  ///
+  pub fn running_nodes(&self, _context: &N::Context) -> Vec<N> {
+    let inner = self.inner.read();
+    inner
+      .running_entry_ids()
+      .map(|entry_id| inner.unsafe_entry_for_id(entry_id).node().clone())
+      .collect()
+  }
+
+  ///
+  /// Returns all Nodes in the Graph ordered by their EntryIds: ie, in the order in which they were
+  /// added to the Graph. Unlike iteration over a HashMap, this order is reproducible, and it is also
+  /// the order in which `visit_live` visits Nodes.
+  ///
+  pub fn iter_nodes_ordered(&self) -> Vec<N> {
+    let inner = self.inner.read();
+    inner
+      .pg
+      .node_indices()
+      .map(|entry_id| inner.unsafe_entry_for_id(entry_id).node().clone())
+      .collect()
+  }
+
+  ///
+  /// Returns the number of Nodes in the Graph which match the given predicate.
+  ///
+  pub fn count_nodes<P: Fn(&N) -> bool>(&self, predicate: P) -> usize {
+    let inner = self.inner.read();
+    inner.nodes.keys().filter(|node| predicate(node)).count()
+  }
+
+  ///
+  /// Returns the current RunToken and Generation of the given Node, if it is present in the Graph.
+  ///
+  /// NB: This is a point-in-time snapshot for debugging purposes: the Node may be re-run or
+  /// invalidated (changing either value) as soon as the Graph lock is released, so the values may
+  /// be stale by the time they are observed.
+  ///
+  pub fn node_versions(&self, node: &N) -> Option<(RunToken, Generation)> {
+    let inner = self.inner.read();
+    inner
+      .entry_id(node)
+      .map(|&entry_id| inner.unsafe_entry_for_id(entry_id).versions())
+  }
+
+  ///
+  /// Returns a clone of the value of the given Node if it is present in the Graph and has a value
+  /// which is valid for the given context. Unlike `Graph::get`, this never starts (or cleans) the
+  /// Node.
+  ///
+  pub fn peek(&self, node: &N, context: &N::Context) -> Option<N::Item> {
+    let inner = self.inner.read();
+    inner
+      .entry_id(node)
+      .and_then(|&entry_id| inner.unsafe_entry_for_id(entry_id).peek(context))
+  }
+
+  ///
+  /// Returns the Generations of the dependencies of the given Node which were consumed by its most
+  /// recent completion, in the order of its dependency edges: these are what cleaning the Node
+  /// will compare against the current Generations of its dependencies. Returns None if the Node
+  /// is not present in the Graph, or has not completed.
+  ///
+  pub fn recorded_dep_generations(&self, node: &N) -> Option<Vec<Generation>> {
+    let inner = self.inner.read();
+    let entry_id = *inner.entry_id(node)?;
+    inner
+      .unsafe_entry_for_id(entry_id)
+      .recorded_dep_generations()
+  }
+
+  ///
+  /// If the given Node has completed but its value is no longer valid for the given context,
+  /// returns the dependencies whose Generations have advanced since it last completed (which is
+  /// the comparison that cleaning the Node will make). Returns None if the Node is not present in
+  /// the Graph, or is not dirty.
+  ///
+  /// NB: Dependencies which have been invalidated but have not yet re-run will not have advanced.
+  ///
+  pub fn dirty_reason(&self, node: &N, context: &N::Context) -> Option<Vec<N>> {
+    let inner = self.inner.read();
+    let entry_id = *inner.entry_id(node)?;
+    let previous_dep_generations = inner
+      .unsafe_entry_for_id(entry_id)
+      .stale_dep_generations(context)?;
+    let dependencies = inner
+      .pg
+      .neighbors_directed(entry_id, Direction::Outgoing)
+      .map(|dep_id| inner.unsafe_entry_for_id(dep_id))
+      .collect::<Vec<_>>();
+
+    let changed = if dependencies.len() == previous_dep_generations.len() {
+      dependencies
+        .into_iter()
+        .zip(previous_dep_generations)
+        .filter(|(entry, previous_generation)| entry.generation() != *previous_generation)
+        .map(|(entry, _)| entry.node().clone())
+        .collect()
+    } else {
+      // If the dependencies have changed, the Generations cannot be matched up: as when cleaning,
+      // consider all of them to have changed.
+      dependencies
+        .into_iter()
+        .map(|entry| entry.node().clone())
+        .collect()
+    };
+    Some(changed)
+  }
+
+  ///
+  /// Returns true if the given Node has a value which is valid for the given context (and so would
+  /// be returned without running or cleaning the Node if it were requested), or None if the Node
+  /// is not present in the Graph.
+  ///
+  pub fn is_clean(&self, node: &N, context: &N::Context) -> Option<bool> {
+    let inner = self.inner.read();
+    // NB: Unlike `Entry::is_clean` (which considers whether an entry needs cleaning), a Node which
+    // has not completed does not have a clean value.
+    inner.entry_id(node).map(|&entry_id| {
+      inner
+        .unsafe_entry_for_id(entry_id)
+        .peek_with(context, |_| ())
+        .is_some()
+    })
+  }
+
+  ///
+  /// If the given Node is Running, or has a value which is valid for the given context, returns a
+  /// Future for its result. Otherwise (including if the Node is not present in the Graph), returns
+  /// None without starting the Node.
+  ///
+  /// If the run that the Future is attached to is canceled, it fails with
+  /// `NodeError::invalidated`.
+  ///
+  pub fn try_get(
+    &self,
+    node: &N,
+    context: &N::Context,
+  ) -> Option<BoxFuture<'static, Result<N::Item, N::Error>>> {
+    let completion = {
+      let inner = self.inner.read();
+      let entry_id = *inner.entry_id(node)?;
+      inner.unsafe_entry_for_id(entry_id).completion(context)?
+    };
+    Some(
+      async move {
+        match completion.await {
+          Some(result) => result.map(|(item, _)| item),
+          None => Err(N::Error::invalidated()),
+        }
+      }
+      .boxed(),
+    )
+  }
+
+  ///
+  /// Waits for the given Node to have a value (without requesting it, and so without starting it),
+  /// and returns its result, or None if the Node is not present in the Graph.
+  ///
+  /// If the Node is not currently running, this waits until some other caller requests it.
+  ///
+  pub async fn wait_for_completion(
+    &self,
+    node: &N,
+    context: &N::Context,
+  ) -> Option<Result<N::Item, N::Error>> {
+    let entry = {
+      let inner = self.inner.read();
+      let entry_id = *inner.entry_id(node)?;
+      inner.unsafe_entry_for_id(entry_id).clone()
+    };
+    loop {
+      match entry.completion_or_start(context) {
+        Ok(completion) => {
+          if let Some(result) = completion.await {
+            return Some(result.map(|(item, _)| item));
+          }
+          // Else: the run was canceled, and we wait for the Node to be started again.
+        }
+        Err(started) => {
+          let _ = started.await;
+        }
+      }
+    }
+  }
+
+  ///
+  /// Returns the number of times that the given Node has been started (either to run or to be
+  /// cleaned), or None if it is not present in the Graph. A Node which is started unusually often
+  /// may be being invalidated more often than necessary.
+  ///
+  pub fn run_count(&self, node: &N) -> Option<u64> {
+    let inner = self.inner.read();
+    inner
+      .entry_id(node)
+      .map(|&entry_id| inner.unsafe_entry_for_id(entry_id).run_count())
+  }
+
+  ///
+  /// Returns the Nodes whose most recently completed run failed.
+  ///
+  /// NB: This is independent of whether a Node is clean: a failed Node will have no value to
+  /// consume, but will still be reported here until it next completes successfully.
+  ///
+  pub fn failed_nodes(&self, _context: &N::Context) -> Vec<N> {
+    let inner = self.inner.read();
+    inner
+      .pg
+      .node_weights()
+      .filter(|entry| entry.last_run_failed())
+      .map(|entry| entry.node().clone())
+      .collect()
+  }
+
+  ///
+  /// Returns the wall-clock time taken by the most recently completed run of the given Node, or
+  /// None if it is not present in the Graph or has never completed.
+  ///
+  pub fn last_run_duration(&self, node: &N) -> Option<Duration> {
+    let inner = self.inner.read();
+    inner
+      .entry_id(node)
+      .and_then(|&entry_id| inner.unsafe_entry_for_id(entry_id).last_run_duration())
+  }
+
+  ///
+  /// Returns a random sample of up to `k` distinct Nodes, in which the likelihood of each Node
+  /// being sampled is proportional to its `last_run_duration`. Nodes which have never completed
+  /// are not sampled. The sample is ordered from the most to the least likely to have been
+  /// sampled, and is deterministic for a given seed and Graph.
+  ///
+  pub fn sample_nodes_by_duration(&self, k: usize, seed: u64) -> Vec<N> {
+    let inner = self.inner.read();
+    inner.sample_nodes_by_duration(k, seed)
+  }
+
+  ///
+  /// Returns the current Generation of every Node in the Graph, for use with `diff_generations`.
+  ///
+  pub fn snapshot_generations(&self) -> HashMap<N, Generation> {
+    let inner = self.inner.read();
+    inner
+      .nodes
+      .iter()
+      .map(|(node, &entry_id)| {
+        (
+          node.clone(),
+          inner.unsafe_entry_for_id(entry_id).generation(),
+        )
+      })
+      .collect()
+  }
+
+  ///
+  /// Returns a rough estimate of the memory held by the Graph, based on the size of its entries and
+  /// the `Node::heap_size` and `Node::item_heap_size` of the Nodes and any values that they hold.
+  ///
+  pub fn estimated_size_bytes(&self, _context: &N::Context) -> usize {
+    let inner = self.inner.read();
+    inner.estimated_size_bytes()
+  }
+
+  ///
+  /// Removes all Nodes which are not reachable from the given live roots via their dependencies,
+  /// returning the number of Nodes removed. Live roots which are not present in the Graph are
+  /// ignored.
+  ///
+  /// Removal invalidates the EntryIds of the remaining Nodes, so it is skipped entirely (returning
+  /// 0) if any Node is currently running, or if any request is outstanding (including a request
+  /// which is waiting to retry a Node).
+  ///
+  pub fn gc_unreachable(&self, live_roots: &[N]) -> usize {
+    let mut inner = self.inner.write();
+    // NB: Requests are marked outstanding before they resolve any EntryIds under the lock, so a
+    // request which is not yet counted here will observe the renumbered entries.
+    if *self.outstanding_requests.lock() > 0 {
+      return 0;
+    }
+    inner.gc_unreachable(live_roots)
+  }
+
+  ///
+  /// Returns statistics about the size and state of the Graph, gathered under a single acquisition
+  /// of the Graph lock so that they are consistent with one another.
+  ///
+  pub fn stats(&self) -> GraphStats {
+    let inner = self.inner.read();
+    inner.stats()
+  }
+
+  ///
+  /// Returns every Node in the Graph with its current status relative to the given context,
+  /// gathered under a single acquisition of the Graph lock.
+  ///
+  pub fn snapshot(&self, context: &N::Context) -> Vec<(N, NodeStatus)> {
+    let inner = self.inner.read();
+    inner
+      .pg
+      .node_weights()
+      .map(|entry| (entry.node().clone(), entry.status(context)))
+      .collect()
+  }
+
+  ///
+  /// Returns the direct dependencies of the given Node in the order that they were first requested,
+  /// or None if the Node is not present in the Graph.
+  ///
+  pub fn dependencies(&self, node: &N, _context: &N::Context) -> Option<Vec<N>> {
+    let inner = self.inner.read();
+    inner.neighbor_nodes(node, Direction::Outgoing)
+  }
+
+  ///
+  /// Returns the direct dependents of the given Node in the order that they first requested it, or
+  /// None if the Node is not present in the Graph.
+  ///
+  pub fn dependents(&self, node: &N) -> Option<Vec<N>> {
+    let inner = self.inner.read();
+    inner.neighbor_nodes(node, Direction::Incoming)
+  }
+
+  ///
+  /// Returns the given roots and all of their transitive dependencies. Roots which are not present
+  /// in the Graph are ignored.
+  ///
+  pub fn transitive_dependencies(&self, roots: &[N]) -> HashSet<N> {
+    let inner = self.inner.read();
+    let root_ids = roots
+      .iter()
+      .filter_map(|node| inner.entry_id(node))
+      .cloned()
+      .collect();
+    inner
+      .walk(root_ids, Direction::Outgoing, |_| false)
+      .map(|eid| inner.unsafe_entry_for_id(eid).node().clone())
+      .collect()
+  }
+
+  ///
+  /// Returns the Nodes reachable from the given roots which have no dependencies, in the breadth
+  /// first order in which they are reached. Roots which are not present in the Graph are ignored.
+  ///
+  pub fn leaves(&self, roots: &[N]) -> Vec<N> {
+    let inner = self.inner.read();
+    let root_ids = roots
+      .iter()
+      .filter_map(|node| inner.entry_id(node))
+      .cloned()
+      .collect();
+    inner
+      .walk(root_ids, Direction::Outgoing, |_| false)
+      .filter(|&eid| inner.pg.neighbors(eid).next().is_none())
+      .map(|eid| inner.unsafe_entry_for_id(eid).node().clone())
+      .collect()
+  }
+
+  ///
+  /// Returns the Nodes in the Graph which have no dependents, ordered by their EntryIds (see
+  /// `iter_nodes_ordered`).
+  ///
+  pub fn sources(&self) -> Vec<N> {
+    let inner = self.inner.read();
+    inner
+      .pg
+      .node_indices()
+      .filter(|&eid| {
+        inner
+          .pg
+          .neighbors_directed(eid, Direction::Incoming)
+          .next()
+          .is_none()
+      })
+      .map(|eid| inner.unsafe_entry_for_id(eid).node().clone())
+      .collect()
+  }
+
+  ///
+  /// Returns the Nodes reachable from the given roots in the given direction, in breadth first
+  /// order (visiting siblings in order of their `Node::priority`). The walk does not visit (or
+  /// continue past) Nodes matching the `stop` predicate.
+  ///
+  /// The walk is computed eagerly under the Graph lock, so the returned iterator is a snapshot.
+  ///
+  pub fn walk(
+    &self,
+    roots: Vec<N>,
+    direction: Direction,
+    stop: impl Fn(&N) -> bool,
+  ) -> impl Iterator<Item = N> {
+    let inner = self.inner.read();
+    let root_ids = roots
+      .iter()
+      .filter_map(|node| inner.entry_id(node))
+      .cloned()
+      .collect();
+    inner
+      .walk(root_ids, direction, |&entry_id| {
+        stop(inner.unsafe_entry_for_id(entry_id).node())
+      })
+      .prioritized()
+      .map(|entry_id| inner.unsafe_entry_for_id(entry_id).node().clone())
+      .collect::<Vec<_>>()
+      .into_iter()
+  }
+
+  ///
+  /// Returns each Node reachable from the given roots in the given direction, paired with its
+  /// minimum depth from a root (roots themselves have depth 0). Nodes further than `max_depth`
+  /// from all roots are not visited.
+  ///
+  pub fn walk_from(&self, roots: &[N], direction: Direction, max_depth: usize) -> Vec<(N, usize)> {
+    let inner = self.inner.read();
+    let root_ids = roots
+      .iter()
+      .filter_map(|node| inner.entry_id(node))
+      .cloned()
+      .collect();
+    let mut walk = inner.walk_to_depth(root_ids, direction, Some(max_depth), |_| false);
+    let mut reachable = Vec::new();
+    while let Some((id, depth)) = walk.next_with_depth() {
+      reachable.push((inner.unsafe_entry_for_id(id).node().clone(), depth));
+    }
+    reachable
+  }
+
+  async fn get_inner(
+    &self,
+    src_id: Option<EntryId>,
+    context: &N::Context,
+    dst_node: N,
+  ) -> Result<(N::Item, Generation), N::Error> {
+    let _request = self.outstanding_request();
+
+    // Compute information about the dst under the Graph lock, and then release it. If the
+    // dependency already exists, a shared lock is sufficient.
+    let existing_dependency = {
+      let inner = self.inner.read();
+      self.existing_dependency(&inner, src_id, &dst_node)?
+    };
+    let (dst_retry, entry, entry_id) = match existing_dependency {
+      Some(existing_dependency) => existing_dependency,
+      None => {
+        let mut inner = self.inner.write();
+        self.add_dependency(&mut inner, src_id, context, dst_node)?
+      }
+    };
+
+    self
+      .get_entry_result(dst_retry, entry, entry_id, context)
+      .await
+  }
+
+  ///
+  /// Marks a request as outstanding until the returned guard is dropped, which prevents
+  /// `Graph::gc_unreachable` from invalidating the EntryIds that it holds. Must be called before
+  /// the request resolves any EntryIds.
+  ///
+  fn outstanding_request(&self) -> OutstandingRequest<'_> {
+    *self.outstanding_requests.lock() += 1;
+    OutstandingRequest(&self.outstanding_requests)
+  }
+
+  ///
+  /// Returns an error if adding the given Node would exceed the Graph's maximum number of Nodes.
+  ///
+  /// NB: This must be checked under the same (exclusive) lock as the insertion, so that concurrent
+  /// requests cannot exceed the limit.
+  ///
+  fn check_max_nodes(&self, inner: &InnerGraph<N>, node: &N) -> Result<(), N::Error> {
+    if let Some(max_nodes) = self.max_nodes {
+      if inner.entry_id(node).is_none() && inner.nodes.len() >= max_nodes {
+        return Err(N::Error::graph_full());
+      }
+    }
+    Ok(())
+  }
+
+  ///
+  /// Returns an error if a dependency from the src (if any) to the dst may not be requested.
+  ///
+  fn check_dependency(
+    &self,
+    inner: &InnerGraph<N>,
+    src_id: Option<EntryId>,
+    dst_node: &N,
+  ) -> Result<(), N::Error> {
+    if inner.draining || src_id.map_or(false, |src_id| inner.in_draining_subtree(src_id)) {
+      return Err(N::Error::invalidated());
+    }
+
+    if let (Some(src_id), Some(edge_policy)) = (src_id, self.edge_policy.lock().as_ref()) {
+      let src_node = inner.unsafe_entry_for_id(src_id).node();
+      if !edge_policy(src_node, dst_node) {
+        return Err(N::Error::forbidden_edge(
+          src_node.to_string(),
+          dst_node.to_string(),
+        ));
+      }
+    }
+    Ok(())
+  }
+
+  ///
+  /// If the destination exists, and the dep (if there is a src) has already been inserted, returns
+  /// the same state as `add_dependency` without needing to modify the Graph. Otherwise, returns
+  /// None.
+  ///
+  fn existing_dependency(
+    &self,
+    inner: &InnerGraph<N>,
+    src_id: Option<EntryId>,
+    dst_node: &N,
+  ) -> Result<Option<(bool, Entry<N>, EntryId)>, N::Error> {
+    self.check_dependency(inner, src_id, dst_node)?;
+
+    let dst_id = match inner.entry_id(dst_node) {
+      Some(&dst_id) => dst_id,
+      None => return Ok(None),
+    };
+    let dst_retry = if let Some(src_id) = src_id {
+      if inner.pg.find_edge(src_id, dst_id).is_none() {
+        return Ok(None);
+      }
+      !inner.unsafe_entry_for_id(src_id).node().cacheable()
+    } else {
+      true
+    };
+
+    let dst_entry = inner.unsafe_entry_for_id(dst_id).clone();
+    Ok(Some((dst_retry, dst_entry, dst_id)))
+  }
+
+  ///
+  /// Get or create the destination, and then insert the dep (if there is a src) and return its
+  /// state: whether it should be retried for invalidation, its Entry, and its EntryId.
+  ///
+  fn add_dependency(
+    &self,
+    inner: &mut InnerGraph<N>,
+    src_id: Option<EntryId>,
+    context: &N::Context,
+    dst_node: N,
+  ) -> Result<(bool, Entry<N>, EntryId), N::Error> {
+    self.check_dependency(inner, src_id, &dst_node)?;
+    self.check_max_nodes(inner, &dst_node)?;
+
+    // TODO: doing cycle detection under the lock... unfortunate, but probably unavoidable
+    // without a much more complicated algorithm.
+    let dst_id = inner.ensure_entry(dst_node);
+    let dst_retry = if let Some(src_id) = src_id {
+      // A dependency which already exists cannot introduce a cycle, and need not be added again.
+      if inner.pg.find_edge(src_id, dst_id).is_none() {
+        if let Some(cycle) = self.report_cycle(src_id, dst_id, inner, context) {
+          // Cyclic dependency: render an error.
+          let path_strs = cycle
+            .path
+            .into_iter()
+            .map(|e| e.node().to_string())
+            .collect();
+          return Err(N::Error::cyclic(path_strs));
+        }
+
+        // Valid dependency.
+        test_trace_log!(
+          "Adding dependency from {:?} to {:?}",
+          inner.entry_for_id(src_id).unwrap().node(),
+          inner.entry_for_id(dst_id).unwrap().node()
+        );
+        inner.add_dependency_edge(src_id, dst_id);
+      }
+
+      // We can retry the dst Node if the src Node is not cacheable. If the src is not cacheable,
+      // it only be allowed to run once, and so Node invalidation does not pass through it.
+      !inner.entry_for_id(src_id).unwrap().node().cacheable()
+    } else {
+      // Otherwise, this is an external request: always retry.
+      test_trace_log!(
+        "Requesting node {:?}",
+        inner.entry_for_id(dst_id).unwrap().node()
+      );
+      true
+    };
+
+    let dst_entry = inner.entry_for_id(dst_id).cloned().unwrap();
+    Ok((dst_retry, dst_entry, dst_id))
+  }
+
+  ///
+  /// Return the state of the destination, (optionally) retrying for invalidation.
+  ///
+  async fn get_entry_result(
+    &self,
+    dst_retry: bool,
+    mut entry: Entry<N>,
+    entry_id: EntryId,
+    context: &N::Context,
+  ) -> Result<(N::Item, Generation), N::Error> {
+    if dst_retry {
+      // Retry the dst a number of times to handle Node invalidation.
+      let context = context.clone();
+      let mut retries = 0;
+      loop {
+        match entry.get_node_result(&context, entry_id).await {
+          Ok(r) => break Ok(r),
+          Err(err) if err == N::Error::invalidated() => {
+            if self
+              .max_retries
+              .map_or(false, |max_retries| retries >= max_retries)
+            {
+              break Err(err);
+            }
+            let delay = self.retry_delay(retries);
+            retries += 1;
+            info!(
+              "Filesystem changed during run: retrying `{}` in {:?}...",
+              entry.node(),
+              delay
+            );
+            sleep(delay).await;
+            continue;
+          }
+          Err(other_err) => break Err(other_err),
+        }
+      }
+    } else {
+      // Not retriable.
+      entry.get_node_result(context, entry_id).await
+    }
+  }
+
+  ///
+  /// Returns the delay before the given (zero-indexed) retry of a request due to invalidation.
+  ///
+  fn retry_delay(&self, retry: usize) -> Duration {
+    let (initial, max) = if let Some(retry_backoff) = self.retry_backoff {
+      retry_backoff
+    } else {
+      return self.invalidation_delay;
+    };
+    if retry == 0 {
+      return Duration::from_millis(0);
+    }
+    let multiplier = 2_u32.saturating_pow(cmp::min(retry - 1, 31) as u32);
+    let delay = initial
+      .checked_mul(multiplier)
+      .map_or(max, |delay| cmp::min(delay, max));
+    // Jitter the delay to between half and all of its value, so that concurrent retries spread
+    // out.
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+  }
+
+  ///
+  /// Request the given dst Node, optionally in the context of the given src Node.
+  ///
+  /// If there is no src Node, or the src Node is not cacheable, this method will retry for
+  /// invalidation until the Node completes.
+  ///
+  /// Invalidation events in the graph (generally, filesystem changes) will cause cacheable Nodes
+  /// to be retried here for up to `invalidation_timeout`.
+  ///
+  pub async fn get(
+    &self,
+    src_id: Option<EntryId>,
+    context: &N::Context,
+    dst_node: N,
+  ) -> Result<N::Item, N::Error> {
+    let (res, _generation) = self
+      .while_waiting(src_id, self.get_inner(src_id, context, dst_node))
+      .await?;
+    Ok(res)
+  }
+
+  ///
+  /// Request all of the given dst Nodes, optionally in the context of the given src Node. Like
+  /// calling `get` for each Node concurrently, but adds all of the dependencies (including
+  /// checking them for cycles) under a single acquisition of the Graph lock.
+  ///
+  /// The Nodes are started in order of their `Node::priority`, but the results are returned in
+  /// the same order as the given Nodes. If adding any of the dependencies would create a cycle, the
+  /// entire batch fails with the cyclic error for the first such dependency, and none of the
+  /// batch's dependencies are added.
+  ///
+  pub async fn get_batch(
+    &self,
+    src_id: Option<EntryId>,
+    context: &N::Context,
+    dst_nodes: Vec<N>,
+  ) -> Result<Vec<N::Item>, N::Error> {
+    let _request = self.outstanding_request();
+    let dsts = {
+      let mut inner = self.inner.write();
+      let mut dsts = Vec::with_capacity(dst_nodes.len());
+      // The dsts to which this batch has added an edge, which are removed if the batch fails.
+      let mut added_dst_ids = Vec::new();
+      for dst_node in dst_nodes {
+        let had_edge = src_id.map_or(true, |src_id| {
+          inner.entry_id(&dst_node).map_or(false, |&dst_id| {
+            inner.pg.find_edge(src_id, dst_id).is_some()
+          })
+        });
+        match self.add_dependency(&mut inner, src_id, context, dst_node) {
+          Ok(dst) => {
+            if !had_edge {
+              added_dst_ids.push(dst.2);
+            }
+            dsts.push(dst);
+          }
+          Err(e) => {
+            if let Some(src_id) = src_id {
+              inner.remove_dependency_edges(src_id, &added_dst_ids);
+            }
+            return Err(e);
+          }
+        }
+      }
+      dsts
+    };
+
+    // Start the Nodes in priority order, and then restore the original order.
+    let mut dsts: Vec<_> = dsts.into_iter().enumerate().collect();
+    dsts.sort_by_key(|(_, (_, entry, _))| entry.node().priority());
+    let (indices, requests): (Vec<_>, Vec<_>) = dsts
+      .into_iter()
+      .map(|(index, (dst_retry, entry, entry_id))| {
+        (
+          index,
+          self.get_entry_result(dst_retry, entry, entry_id, context),
+        )
+      })
+      .unzip();
+    let mut results: Vec<_> = indices
+      .into_iter()
+      .zip(
+        self
+          .while_waiting(src_id, future::try_join_all(requests))
+          .await?,
+      )
+      .collect();
+    results.sort_by_key(|(index, _)| *index);
+    Ok(
+      results
+        .into_iter()
+        .map(|(_, (res, _generation))| res)
+        .collect(),
+    )
+  }
+
+  ///
+  /// Like `create`, but forces the given Node to re-run (even if it has a clean value), without
+  /// invalidating it: for example, to check that it is deterministic. The Node's dependents are
+  /// dirtied only if it computes a different value than it previously had, or if it fails (since
+  /// failures are not memoized, the previous value is gone).
+  ///
+  /// If the Node is already running, the in-progress run is awaited instead. Pinned Nodes are not
+  /// re-run, and a Node may load its value via `Node::try_load_cached` rather than running.
+  ///
+  pub async fn get_forced(&self, context: &N::Context, node: N) -> Result<N::Item, N::Error> {
+    let previous_generation = {
+      let mut inner = self.inner.write();
+      match inner.entry_id(&node).cloned() {
+        Some(entry_id) if !inner.unsafe_entry_for_id(entry_id).is_running() => {
+          let generation = inner.unsafe_entry_for_id(entry_id).generation();
+          inner.reset_node(&node);
+          Some(generation)
+        }
+        _ => None,
+      }
+    };
+
+    let result = self.get_inner(None, context, node.clone()).await;
+    let changed = match (&result, previous_generation) {
+      (_, None) => false,
+      (Ok((_, generation)), Some(previous)) => *generation != previous,
+      (Err(_), Some(_)) => true,
+    };
+    if changed {
+      self.invalidate_and_observe(|inner, events| match inner.entry_id(&node).cloned() {
+        Some(entry_id) => inner.dirty_dependents(entry_id, events),
+        None => InvalidationResult::default(),
+      });
+    }
+    result.map(|(res, _)| res)
+  }
+
+  ///
+  /// Return the value of the given Node. Shorthand for `self.get(None, context, node)`.
+  ///
+  pub async fn create(&self, node: N, context: &N::Context) -> Result<N::Item, N::Error> {
+    self.get(None, context, node).await
+  }
+
+  ///
+  /// Gets the value of the given Node (optionally waiting for it to have changed since the given
+  /// LastObserved token), and then returns its new value and a new LastObserved token.
+  ///
+  /// This method is cancellation safe: dropping the returned future (while waiting for a change, or
+  /// while re-requesting the Node) does not modify the Node or invalidate any LastObserved token,
+  /// which may be passed to a later call. A caller which would like to resynchronize with the
+  /// current version of the Node without waiting can use `observe_latest`.
+  ///
+  pub async fn poll(
+    &self,
+    node: N,
+    token: Option<LastObserved>,
+    delay: Option<Duration>,
+    context: &N::Context,
+  ) -> Result<(N::Item, LastObserved), N::Error> {
+    // If the node is currently clean at the given token, Entry::poll will delay until it has
+    // changed in some way.
+    if let Some(LastObserved(generation)) = token {
+      let entry = {
+        let mut inner = self.inner.write();
+        self.check_max_nodes(&inner, &node)?;
+        let entry_id = inner.ensure_entry(node.clone());
+        inner.unsafe_entry_for_id(entry_id).clone()
+      };
+      entry.poll(context, generation).await;
+      if let Some(delay) = delay {
+        sleep(delay).await;
+      }
+    };
+
+    // Re-request the Node.
+    let (res, generation) = self.get_inner(None, context, node).await?;
+    Ok((res, LastObserved(generation)))
+  }
+
+  ///
+  /// Returns a LastObserved token for the current version of the given Node (if it is present in
+  /// the Graph), without waiting for or requesting it.
+  ///
+  pub fn observe_latest(&self, node: &N) -> Option<LastObserved> {
+    let inner = self.inner.read();
+    inner
+      .entry_id(node)
+      .map(|&entry_id| LastObserved(inner.unsafe_entry_for_id(entry_id).generation()))
+  }
+
+  fn report_cycle(
+    &self,
+    src_id: EntryId,
+    potential_dst_id: EntryId,
+    inner: &mut InnerGraph<N>,
+    context: &N::Context,
+  ) -> Option<Cycle<N>> {
+    let mut counter = 0;
+    loop {
+      // Find one cycle if any cycles exist.
+      if let Some(cycle) = inner.report_cycle(src_id, potential_dst_id) {
+        // See if the cycle contains any dirty nodes. If there are dirty nodes, we can try clearing
+        // them, and then check if there are still any cycles in the graph.
+        let dirty_nodes: HashSet<_> = cycle
+          .path
+          .iter()
+          .filter(|n| !n.is_clean(context))
+          .map(|n| n.node().clone())
+          .collect();
+        if dirty_nodes.is_empty() {
+          // We detected a cycle with no dirty nodes. If configured to, break it by removing an
+          // edge (the first entry in the path is the src, and the edge from it to the dst does not
+          // exist yet), and then check whether there are still any cycles in the graph.
+          if *self.cycle_strategy.lock() == CycleStrategy::BreakLowestWeightEdge
+            && counter < self.cycle_clearing_limit
+          {
+            if let Some((dependent, dependency)) = inner.remove_lowest_weight_edge(&cycle.path[1..])
+            {
+              warn!(
+                "Broke a cycle considering adding edge from {} to {} by removing the edge from {} to {}",
+                inner.unsafe_entry_for_id(src_id).node(),
+                inner.unsafe_entry_for_id(potential_dst_id).node(),
+                dependent,
+                dependency,
+              );
+              counter += 1;
+              continue;
+            }
+          }
+          // Otherwise, there's a cycle and there's nothing we can do to remove it. We only log at
+          // debug because the UI will render the cycle.
+          debug!(
+            "Detected cycle considering adding edge from {:?} to {:?}; existing path: {:?}",
+            inner.entry_for_id(src_id).unwrap(),
+            inner.entry_for_id(potential_dst_id).unwrap(),
+            cycle.path
+          );
+          return Some(cycle);
+        }
+        counter += 1;
+        // Obsolete edges from a dirty node may cause fake cycles to be detected if there was a
+        // dirty dep from A to B, and we're trying to add a dep from B to A.
+        // If we detect a cycle that contains dirty nodes (and so potentially obsolete edges),
+        // we repeatedly cycle-detect, clearing (and re-running) and dirty nodes (and their edges)
+        // that we encounter.
+        //
+        // We do this repeatedly, because there may be multiple paths which would cause cycles,
+        // which contain dirty nodes. If we've cleared `cycle_clearing_limit` separate paths which
+        // contain dirty nodes, and are still detecting cycle-causing paths containing dirty nodes,
+        // give up. The default of 10 is a very arbitrary number, which can be increased (via
+        // `GraphOptions::cycle_clearing_limit`) for real graphs which hit this limit.
+        if counter > self.cycle_clearing_limit {
+          warn!(
+            "Couldn't remove cycle containing dirty nodes after {} attempts; nodes in cycle: {:?}",
+            counter, cycle.path
+          );
+          return Some(cycle);
+        }
+        // Clear the dirty nodes, removing the edges from them, and try again.
+        inner.invalidate_from_roots(|node| dirty_nodes.contains(node), None);
+      } else {
+        return None;
+      }
+    }
+  }
+
+  ///
+  /// Returns the Nodes reachable from the given roots in topological order (with dependencies
+  /// before their dependents), or the members of a cycle if the reachable subgraph is cyclic.
+  ///
+  pub fn topological_order(&self, roots: &[N]) -> Result<Vec<N>, Vec<N>> {
+    let inner = self.inner.read();
+    inner.topological_order(roots)
+  }
+
+  ///
+  /// Returns the diameter of the subgraph reachable from the given roots (the greatest number of
+  /// edges on a shortest path between any two of its Nodes, treating the edges as undirected), and
+  /// a Node at its center (a Node with the least greatest distance to any other Node), or None if
+  /// none of the roots are present.
+  ///
+  /// This is computed exactly, with a breadth first search from every reachable Node, and so costs
+  /// O(V * (V + E)) time for a subgraph with V Nodes and E edges: it is intended for moderately
+  /// sized subgraphs.
+  ///
+  pub fn diameter(&self, roots: &[N]) -> (usize, Option<N>) {
+    let inner = self.inner.read();
+    let (diameter, center) = inner.diameter(roots);
+    (
+      diameter,
+      center.map(|id| inner.unsafe_entry_for_id(id).node().clone()),
+    )
+  }
+
+  ///
+  /// Returns the EntryId of the given Node in the Graph (which is also its index in the PGraph
+  /// lent by `with_raw_graph`), or None if it is not present.
+  ///
+  pub fn node_index(&self, node: &N) -> Option<EntryId> {
+    self.inner.read().entry_id(node).cloned()
+  }
+
+  ///
+  /// Lends the underlying petgraph representation of the Graph to the given function while
+  /// holding the Graph's (shared) lock: this is an escape hatch for algorithms which are not
+  /// otherwise exposed by the Graph. Edges point from dependents to their dependencies.
+  ///
+  /// The PGraph is lent immutably, so it cannot be mutated in a way which would violate the
+  /// invariants of the Graph. But because the lock is held, the function must not call back into
+  /// the Graph at all: the lock is fair, so even a nested shared acquisition will deadlock if a
+  /// writer is waiting.
+  ///
+  pub fn with_raw_graph<R>(&self, f: impl FnOnce(&PGraph<N>) -> R) -> R {
+    f(&self.inner.read().pg)
+  }
+
+  ///
+  /// Returns true if src directly depends on dst, or false if either Node is not present.
+  ///
+  pub fn has_edge(&self, src: &N, dst: &N) -> bool {
+    let inner = self.inner.read();
+    match (inner.entry_id(src), inner.entry_id(dst)) {
+      (Some(&src_id), Some(&dst_id)) => inner.pg.find_edge(src_id, dst_id).is_some(),
+      _ => false,
+    }
+  }
+
+  ///
+  /// Returns true if adding an edge from src to dst would create a cycle in the current state of
+  /// the Graph, or false if either Node is not present.
+  ///
+  /// Unlike requesting the dependency, this never clears dirty Nodes in order to break the cycle.
+  ///
+  pub fn would_create_cycle(&self, src: &N, dst: &N) -> bool {
+    let inner = self.inner.read();
+    match (inner.entry_id(src), inner.entry_id(dst)) {
+      (Some(&src_id), Some(&dst_id)) => src_id == dst_id || inner.detect_cycle(src_id, dst_id),
+      _ => false,
+    }
+  }
+
+  ///
+  /// Returns up to `max_paths` paths of dependencies from src to dst (inclusive), in a
+  /// deterministic order: the order in which a depth first search of the dependencies of src
+  /// (visiting the dependencies of each Node in order of their rendering) discovers them.
+  ///
+  /// The number of paths between two Nodes may be exponential in the size of the Graph, so the
+  /// search stops once `max_paths` paths have been found.
+  ///
+  pub fn all_paths(&self, src: &N, dst: &N, max_paths: usize) -> Vec<Vec<N>> {
+    let inner = self.inner.read();
+    match (inner.entry_id(src), inner.entry_id(dst)) {
+      (Some(&src_id), Some(&dst_id)) => inner
+        .all_paths(src_id, dst_id, max_paths)
+        .into_iter()
+        .map(|path| {
+          path
+            .into_iter()
+            .map(|id| inner.unsafe_entry_for_id(id).node().clone())
+            .collect()
+        })
+        .collect(),
+      _ => vec![],
+    }
+  }
+
+  ///
+  /// Like `would_create_cycle`, but rather than choosing the direction to search in based on the
+  /// degrees of the Nodes, searches in the given direction: forward from the dst (`Outgoing`), or
+  /// backward from the src (`Incoming`). The result is the same in either direction, but the cost
+  /// of the search may differ significantly.
+  ///
+  pub fn detect_cycle_with_direction(&self, src: &N, dst: &N, prefer: Direction) -> bool {
+    let inner = self.inner.read();
+    match (inner.entry_id(src), inner.entry_id(dst)) {
+      (Some(&src_id), Some(&dst_id)) => {
+        src_id == dst_id
+          || inner
+            .search_for_cycle_in_direction(src_id, dst_id, prefer)
+            .is_some()
+      }
+      _ => false,
+    }
+  }
+
+  ///
+  /// Removes the dependency edges of the given dirty Node on any dependencies which have changed
+  /// since the Node last consumed them (ie, which have a newer Generation than was recorded on the
+  /// edge when the Node last completed: see `DependencyEdge::generation`). Nodes which are not
+  /// dirty are unaffected.
+  ///
+  /// Because the edges are removed, the Node will re-run (rather than being cleaned) the next time
+  /// it is requested.
+  ///
+  pub fn prune_stale_edges(&self, node: &N) {
+    let mut inner = self.inner.write();
+    inner.prune_stale_edges(node)
+  }
+
+  ///
+  /// Returns the height of the given Node: the length of the longest path from it to a Node
+  /// without dependencies (which has a height of 0). Returns None if the Node is not present.
+  ///
+  pub fn node_height(&self, node: &N) -> Option<usize> {
+    let inner = self.inner.read();
+    inner
+      .entry_id(node)
+      .map(|&entry_id| inner.node_height(entry_id))
+  }
+
+  ///
+  /// Returns the longest chain of dependencies consisting only of uncacheable Nodes, from
+  /// dependent to dependency, or an empty Vec if the Graph contains no uncacheable Nodes.
+  ///
+  pub fn longest_uncacheable_chain(&self) -> Vec<N> {
+    self.inner.read().longest_uncacheable_chain()
+  }
+
+  ///
+  /// Find all cycles that are currently present in the graph. See `InnerGraph::find_cycles`.
+  ///
+  pub fn find_cycles(&self) -> Vec<Vec<N>> {
+    self.inner.read().find_cycles()
+  }
+
+  ///
+  /// Returns the Nodes which have a dependency edge to themselves, in the order that they were
+  /// added to the graph. A self-loop should never exist, so this is intended for use in assertions
+  /// to catch bugs in the construction of the graph.
+  ///
+  pub fn self_loops(&self) -> Vec<N> {
+    let inner = self.inner.read();
+    let mut self_loops = inner
+      .pg
+      .raw_edges()
+      .iter()
+      .filter(|edge| edge.source() == edge.target())
+      .map(|edge| edge.source())
+      .collect::<Vec<_>>();
+    self_loops.sort();
+    self_loops.dedup();
+    self_loops
+      .into_iter()
+      .map(|id| inner.unsafe_entry_for_id(id).node().clone())
+      .collect()
+  }
+
+  ///
+  /// Checks the internal consistency of the Graph: that every Node maps to an entry for that Node
+  /// (and vice versa), and that every edge is between two distinct entries which are present.
+  /// Returns a description of the first inconsistency found.
+  ///
+  /// This is intended for use in tests, and is only available to other crates via the `verify`
+  /// feature.
+  ///
+  #[cfg(any(test, feature = "verify"))]
+  pub fn verify_invariants(&self) -> Result<(), String> {
+    self.inner.read().verify_invariants()
+  }
+
+  ///
+  /// Returns the strongly connected components of the graph (each of which will contain a single
+  /// Node unless the graph contains a cycle), and the edges between them as pairs of indexes into
+  /// the list of components. See `InnerGraph::condensation`.
+  ///
+  pub fn condensation(&self) -> (Vec<Vec<N>>, Vec<(usize, usize)>) {
+    self.inner.read().condensation()
+  }
+
+  ///
+  /// Calculate the critical path for the subset of the graph that descends from these roots,
+  /// assuming this mapping between entries and durations.
+  ///
+  pub fn critical_path<F>(&self, roots: &[N], duration: &F) -> (Duration, Vec<Entry<N>>)
+  where
+    F: Fn(&Entry<N>) -> Duration,
+  {
+    self.critical_path_with_edge_costs(roots, duration, &|_: &Entry<N>, _: &Entry<N>| {
+      Duration::from_nanos(0)
+    })
+  }
+
+  ///
+  /// Calculate the critical path for the subset of the graph that descends from these roots,
+  /// using the recorded duration of the most recently completed run of each entry. Entries which
+  /// have never completed are treated as taking no time.
+  ///
+  pub fn critical_path_by_last_run_duration(&self, roots: &[N]) -> (Duration, Vec<Entry<N>>) {
+    self.critical_path(roots, &|entry: &Entry<N>| {
+      entry.last_run_duration().unwrap_or_default()
+    })
+  }
+
+  ///
+  /// Like `critical_path`, but the duration of each entry is computed with access to the given
+  /// context: for example, to look up durations which are recorded by the context.
+  ///
+  pub fn critical_path_with_context<F>(
+    &self,
+    roots: &[N],
+    context: &N::Context,
+    duration: &F,
+  ) -> (Duration, Vec<Entry<N>>)
+  where
+    F: Fn(&Entry<N>, &N::Context) -> Duration,
+  {
+    self.critical_path(roots, &|entry: &Entry<N>| duration(entry, context))
+  }
+
+  ///
+  /// Like `critical_path`, but additionally charges the given cost for each dependency edge (as
+  /// src, dst) traversed by a path: for example, to model the cost of transferring a value between
+  /// two Nodes.
+  ///
+  pub fn critical_path_with_edge_costs<F, G>(
+    &self,
+    roots: &[N],
+    duration: &F,
+    edge_cost: &G,
+  ) -> (Duration, Vec<Entry<N>>)
+  where
+    F: Fn(&Entry<N>) -> Duration,
+    G: Fn(&Entry<N>, &Entry<N>) -> Duration,
+  {
+    let critical_path = self.inner.read().critical_path(
+      roots,
+      duration,
+      &|src: &Entry<N>, dst: &Entry<N>, _: f32| edge_cost(src, dst),
+    );
+    (
+      critical_path.total,
+      critical_path
+        .entries
+        .into_iter()
+        .map(|(entry, _)| entry)
+        .collect(),
+    )
+  }
+
+  ///
+  /// Like `critical_path`, but additionally charges `cost_per_weight` multiplied by the weight of
+  /// each dependency edge (see `Node::edge_weight`) traversed by a path.
+  ///
+  pub fn critical_path_with_edge_weights<F>(
+    &self,
+    roots: &[N],
+    duration: &F,
+    cost_per_weight: Duration,
+  ) -> (Duration, Vec<Entry<N>>)
+  where
+    F: Fn(&Entry<N>) -> Duration,
+  {
+    let critical_path = self.inner.read().critical_path(
+      roots,
+      duration,
+      &|_: &Entry<N>, _: &Entry<N>, weight: f32| cost_per_weight.mul_f32(weight),
+    );
+    (
+      critical_path.total,
+      critical_path
+        .entries
+        .into_iter()
+        .map(|(entry, _)| entry)
+        .collect(),
+    )
+  }
+
+  ///
+  /// Like `critical_path`, but additionally returns the duration that each entry on the path
+  /// contributed to the total.
+  ///
+  pub fn critical_path_detailed<F>(&self, roots: &[N], duration: &F) -> CriticalPath<N>
+  where
+    F: Fn(&Entry<N>) -> Duration,
+  {
+    self
+      .inner
+      .read()
+      .critical_path(roots, duration, &|_: &Entry<N>, _: &Entry<N>, _: f32| {
+        Duration::from_nanos(0)
+      })
+  }
+
+  ///
+  /// Returns up to `top` Nodes which are on the critical paths of the most roots (see
+  /// `critical_path`), with the number of roots whose critical path includes them and their
+  /// durations. Nodes are ranked by that number, then by their durations (both descending), and
+  /// then by their rendering.
+  ///
+  /// The critical path of each root is computed separately, so this is expensive for many roots.
+  ///
+  pub fn hot_dependencies<F>(
+    &self,
+    roots: &[N],
+    duration: &F,
+    top: usize,
+  ) -> Vec<(N, usize, Duration)>
+  where
+    F: Fn(&Entry<N>) -> Duration,
+  {
+    let mut counts: HashMap<N, (usize, Duration)> = HashMap::new();
+    {
+      let inner = self.inner.read();
+      for root in roots {
+        let critical_path = inner.critical_path(
+          std::slice::from_ref(root),
+          duration,
+          &|_: &Entry<N>, _: &Entry<N>, _: f32| Duration::from_nanos(0),
+        );
+        for (entry, _) in critical_path.entries {
+          let count = counts
+            .entry(entry.node().clone())
+            .or_insert_with(|| (0, duration(&entry)));
+          count.0 += 1;
+        }
+      }
+    }
+
+    let mut hot_dependencies = counts
+      .into_iter()
+      .map(|(node, (count, node_duration))| (node, count, node_duration))
+      .collect::<Vec<_>>();
+    hot_dependencies.sort_by_cached_key(|(node, count, node_duration)| {
+      (Reverse(*count), Reverse(*node_duration), node.to_string())
+    });
+    hot_dependencies.truncate(top);
+    hot_dependencies
+  }
+
+  ///
+  /// Computes a CriticalPathCache for the subset of the graph that descends from these roots,
+  /// assuming this mapping between entries and durations. See `critical_path_incremental`.
+  ///
+  pub fn critical_path_cache<F>(&self, roots: &[N], duration: &F) -> CriticalPathCache<N>
+  where
+    F: Fn(&Entry<N>) -> Duration,
+  {
+    self.inner.read().critical_path_cache(roots, duration)
+  }
+
+  ///
+  /// Like `critical_path` (for the roots of the given cache), but rather than recomputing the
+  /// longest path to every entry, updates the cache for changes to the durations of only the
+  /// given Nodes, which must include every Node whose duration has changed since the cache was
+  /// last updated. Only the entries reachable from the changed Nodes are revisited.
+  ///
+  /// If the topology of the graph has changed since the cache was created (ie, if any entry or edge
+  /// has been added or removed, or entries have been renumbered by `gc_unreachable`: each of which
+  /// advances the graph's topology epoch), the cache is recomputed from scratch.
+  ///
+  pub fn critical_path_incremental<F>(
+    &self,
+    cache: &mut CriticalPathCache<N>,
+    changed: &[N],
+    duration: &F,
+  ) -> (Duration, Vec<Entry<N>>)
+  where
+    F: Fn(&Entry<N>) -> Duration,
+  {
+    let inner = self.inner.read();
+    inner.update_critical_path_cache(cache, changed, duration);
+    inner.critical_path_from_cache(cache)
+  }
+
+  ///
+  /// Returns up to `n` longest paths for the subset of the graph that descends from these roots,
+  /// in descending order of duration. The paths are vertex-disjoint: each is the critical path
+  /// of the graph that remains after removing the entries of the paths before it (and so a path
+  /// through an entry that was already used is never reported, even if it is long). Entries which
+  /// are only reachable through a used entry become roots of the remaining graph.
+  ///
+  pub fn top_critical_paths<F>(
+    &self,
+    roots: &[N],
+    duration: &F,
+    n: usize,
+  ) -> Vec<(Duration, Vec<Entry<N>>)>
+  where
+    F: Fn(&Entry<N>) -> Duration,
+  {
+    let (mut graph, src) = self.inner.read().critical_path_graph(
+      roots,
+      duration,
+      &|_: &Entry<N>, _: &Entry<N>, _: f32| Duration::from_nanos(0),
+    );
+    let mut reachable_ids = HashSet::<_, Fnv>::default();
+    let mut dfs = petgraph::visit::Dfs::new(&graph, src);
+    while let Some(id) = dfs.next(&graph) {
+      if id != src {
+        reachable_ids.insert(id);
+      }
+    }
+
+    let mut paths = Vec::new();
+    while paths.len() < n {
+      let critical_path = InnerGraph::critical_path_of(&graph, src);
+      if critical_path.entries.is_empty() {
+        break;
+      }
+
+      // Disconnect the entries of the path so that no later path can use them.
+      let used_nodes = critical_path
+        .entries
+        .iter()
+        .map(|(entry, _)| entry.node())
+        .collect::<HashSet<_>>();
+      let used_ids = graph
+        .node_indices()
+        .filter(|&id| {
+          graph[id]
+            .as_ref()
+            .map_or(false, |entry| used_nodes.contains(entry.node()))
+        })
+        .collect::<HashSet<_, Fnv>>();
+      graph.retain_edges(|g, edge| {
+        if let Some((src, dst)) = g.edge_endpoints(edge) {
+          !used_ids.contains(&src) && !used_ids.contains(&dst)
+        } else {
+          true
+        }
+      });
+
+      // Reconnect the synthetic source to the remaining entries which lost all of their in-edges,
+      // so that the entries below a used entry remain reachable.
+      reachable_ids.retain(|id| !used_ids.contains(id));
+      let orphaned_ids = reachable_ids
+        .iter()
+        .filter(|&&id| {
+          !graph
+            .neighbors_directed(id, Direction::Incoming)
+            .any(|dependent| dependent == src || reachable_ids.contains(&dependent))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+      for id in orphaned_ids {
+        let weight = graph[id]
+          .as_ref()
+          .map_or(0., |entry| -(duration(entry).as_nanos() as f64));
+        graph.add_edge(src, id, weight);
+      }
+
+      paths.push((
+        critical_path.total,
+        critical_path
+          .entries
+          .into_iter()
+          .map(|(entry, _)| entry)
+          .collect(),
+      ));
+    }
+    paths
+  }
+
+  ///
+  /// Like `critical_path`, but only holds the Graph's lock while copying the relevant topology
+  /// and durations: the (expensive) computation of the path itself runs on a blocking thread
+  /// without the lock, so that it does not stall concurrent requests to the Graph.
+  ///
+  pub async fn critical_path_async<F>(&self, roots: &[N], duration: &F) -> (Duration, Vec<Entry<N>>)
+  where
+    F: Fn(&Entry<N>) -> Duration,
+  {
+    let (graph, src) = self.inner.read().critical_path_graph(
+      roots,
+      duration,
+      &|_: &Entry<N>, _: &Entry<N>, _: f32| Duration::from_nanos(0),
+    );
+    let critical_path =
+      tokio::task::spawn_blocking(move || InnerGraph::critical_path_of(&graph, src))
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+    (
+      critical_path.total,
+      critical_path
+        .entries
+        .into_iter()
+        .map(|(entry, _)| entry)
+        .collect(),
+    )
+  }
+
+  ///
+  /// Compares the generations of the dependencies of the given EntryId to their previous
+  /// generation values (re-computing or cleaning them first if necessary), and returns true if any
+  /// dependency has changed.
+  ///
+  async fn dependencies_changed(
+    &self,
+    entry_id: EntryId,
+    previous_dep_generations: Vec<Generation>,
+    context: &N::Context,
+  ) -> bool {
+    let generation_matches = {
+      let inner = self.inner.read();
+      let dependency_ids = inner
+        .pg
+        .neighbors_directed(entry_id, Direction::Outgoing)
+        .collect::<Vec<_>>();
+
+      if dependency_ids.len() != previous_dep_generations.len() {
+        // If we don't have the same number of current dependencies as there were generations
+        // previously, then they cannot match.
+        return true;
+      }
+
+      dependency_ids
+        .into_iter()
+        .zip(previous_dep_generations.into_iter())
+        .map(|(dep_id, previous_dep_generation)| {
+          let mut entry = inner
+            .entry_for_id(dep_id)
+            .unwrap_or_else(|| panic!("Dependency not present in Graph."))
+            .clone();
+          async move {
+            let (_, generation) = entry
+              .get_node_result(context, dep_id)
+              .await
+              .map_err(|_| ())?;
+            if generation == previous_dep_generation {
+              // Matched.
+              Ok(())
+            } else {
+              // Did not match. We error here to trigger fail-fast in `try_join_all`.
+              Err(())
+            }
+          }
+        })
+        .collect::<Vec<_>>()
+    };
+
+    // We use try_join_all in order to speculatively execute all branches, and to fail fast if any
+    // generation mismatches. The first mismatch encountered will cause any extraneous cleaning
+    // work to be canceled. See #11290 for more information about the tradeoffs inherent in
+    // speculation.
+    future::try_join_all(generation_matches).await.is_err()
+  }
+
+  ///
+  /// Clears the dependency edges of the given EntryId if the RunToken matches.
+  ///
+  fn clear_deps(&self, entry_id: EntryId, run_token: RunToken) {
+    let mut inner = self.inner.write();
+    // If the RunToken mismatches, return.
+    if let Some(entry) = inner.entry_for_id(entry_id) {
+      if entry.run_token() != run_token {
+        return;
+      }
+    }
+
+    // Otherwise, clear the deps.
+    // NB: Because `remove_edge` changes EdgeIndex values, we remove edges one at a time.
+    while let Some(dep_edge) = inner
+      .pg
+      .edges_directed(entry_id, Direction::Outgoing)
+      .next()
+      .map(|edge| edge.id())
+    {
+      inner.pg.remove_edge(dep_edge);
+    }
+    inner.topology_reduced();
+  }
+
+  ///
+  /// When a Node is canceled because all receivers go away, the Executor for that Node will call
+  /// back to ensure that it is canceled.
+  ///
+  /// See also: `Self::complete`.
+  ///
+  fn cancel(&self, entry_id: EntryId, run_token: RunToken) {
+    let mut inner = self.inner.write();
+    if let Some(ref mut entry) = inner.entry_for_id_mut(entry_id) {
+      entry.cancel(run_token);
+    }
+  }
+
+  ///
+  /// When the Executor finishes executing a Node it calls back to store the result value. We use
+  /// the run_token and dirty bits to determine whether the Node changed while we were busy
+  /// executing it, so that we can discard the work.
+  ///
+  /// We use the dirty bit in addition to the RunToken in order to avoid cases where dependencies
+  /// change while we're running. In order for a dependency to "change" it must have been cleared
+  /// or been marked dirty. But if our dependencies have been cleared or marked dirty, then we will
+  /// have been as well. We can thus use the dirty bit as a signal that the generation values of
+  /// our dependencies are still accurate. The dirty bit is safe to rely on as it is only ever
+  /// mutated, and dependencies' dirty bits are only read, under the InnerGraph lock - this is only
+  /// reliably the case because Entry happens to require a &mut InnerGraph reference; it would be
+  /// great not to violate that in the future.
+  ///
+  /// See also: `Self::cancel`.
+  ///
+  fn complete(
+    &self,
+    context: &N::Context,
+    entry_id: EntryId,
+    run_token: RunToken,
+    sender: AsyncValueSender<NodeResult<N>>,
+    result: Option<Result<N::Item, N::Error>>,
+  ) {
+    let (entry, has_uncacheable_deps, dep_generations) = {
+      let inner = self.inner.read();
+      let mut has_uncacheable_deps = false;
+      // Get the Generations of all dependencies of the Node. We can trust that these have not changed
+      // since we began executing, as long as we are not currently marked dirty (see the method doc).
+      let dep_generations: Vec<_> = inner
+        .pg
+        .neighbors_directed(entry_id, Direction::Outgoing)
+        .filter_map(|dep_id| Some((dep_id, inner.entry_for_id(dep_id)?)))
+        .map(|(dep_id, entry)| {
+          // If a dependency is itself uncacheable or has uncacheable deps, this Node should
+          // also complete as having uncacheable dpes, independent of matching Generation values.
+          // This is to allow for the behaviour that an uncacheable Node should always have "dirty"
+          // (marked as UncacheableDependencies) dependents, transitively.
+          if entry.has_uncacheable_deps() {
+            has_uncacheable_deps = true;
+          }
+          (dep_id, entry.generation())
+        })
+        .collect();
+      (
+        inner.entry_for_id(entry_id).cloned(),
+        has_uncacheable_deps,
+        dep_generations,
+      )
     };
-
-    // Clear roots and remove their outbound edges.
-    for id in &root_ids {
-      if let Some(entry) = self.pg.node_weight_mut(*id) {
-        entry.clear(false);
+    if let Some(mut entry) = entry {
+      let succeeded = {
+        let mut inner = self.inner.write();
+        let succeeded = entry.complete(
+          context,
+          run_token,
+          dep_generations
+            .iter()
+            .map(|(_, generation)| *generation)
+            .collect(),
+          sender,
+          result,
+          has_uncacheable_deps,
+          &mut inner,
+        );
+        if succeeded.is_some() {
+          // NB: The edges are matched up with the Generations by their dependency (rather than by
+          // position), because the edge list may have been reordered since they were collected.
+          inner.record_dependency_generations(entry_id, &dep_generations.into_iter().collect());
+        }
+        succeeded
+      };
+      // Notify the observer (if any) and subscribers outside of the Graph lock.
+      if let Some(succeeded) = succeeded {
+        let observer = self.completion_observer.lock().clone();
+        if let Some(observer) = observer {
+          observer(entry.node(), succeeded);
+        }
+        self.publish_event(|| GraphEvent::NodeCompleted {
+          node: entry.node().clone(),
+          ok: succeeded,
+        });
       }
     }
-    self.pg.retain_edges(|pg, edge| {
-      if let Some((src, _)) = pg.edge_endpoints(edge) {
-        !root_ids.contains(&src)
-      } else {
-        true
-      }
-    });
+  }
 
-    // Dirty transitive entries, but do not yet clear their output edges. We wait to clear
-    // outbound edges until we decide whether we can clean an entry: if we can, all edges are
-    // preserved; if we can't, they are cleared in `Graph::clear_deps`.
-    for id in &transitive_ids {
-      if let Some(mut entry) = self.pg.node_weight_mut(*id).cloned() {
-        entry.dirty(self);
+  ///
+  /// If the given Node is running, cancels it and returns true. Dependencies which were requested
+  /// only by the canceled run are canceled in turn (due to the loss of interest in them).
+  ///
+  /// Any requests waiting for the Node fail with `NodeError::invalidated`, and so may be retried
+  /// (see `GraphOptions::max_retries`).
+  ///
+  pub fn cancel_node(&self, node: &N) -> bool {
+    let mut entry = {
+      let inner = self.inner.read();
+      match inner.entry_id(node) {
+        Some(&entry_id) => inner.unsafe_entry_for_id(entry_id).clone(),
+        None => return false,
       }
+    };
+    let canceled = entry.cancel_running();
+    if canceled {
+      self.publish_event(|| GraphEvent::NodeCanceled(node.clone()));
     }
-
-    invalidation_result
+    canceled
   }
 
-  fn visualize<V: NodeVisualizer<N>>(
-    &self,
-    mut visualizer: V,
-    roots: &[N],
-    path: &Path,
-    context: &N::Context,
-  ) -> io::Result<()> {
-    let file = File::create(path)?;
-    let mut f = BufWriter::new(file);
-
-    f.write_all(b"digraph plans {\n")?;
-    f.write_fmt(format_args!(
-      "  node[colorscheme={}];\n",
-      visualizer.color_scheme()
-    ))?;
-    f.write_all(b"  concentrate=true;\n")?;
-    f.write_all(b"  rankdir=TB;\n")?;
-
-    let mut format_color = |entry: &Entry<N>| visualizer.color(entry, context);
-
-    let root_entries = roots
-      .iter()
-      .filter_map(|n| self.entry_id(n))
-      .cloned()
-      .collect();
-
-    for eid in self.walk(root_entries, Direction::Outgoing, |_| false) {
-      let entry = self.unsafe_entry_for_id(eid);
-      let node_str = entry.format(context);
+  ///
+  /// Clears the state of all Nodes in the Graph (other than those which are pinned) by dropping
+  /// their state fields.
+  ///
+  pub fn clear(&self) {
+    self.clear_matching(|_| true)
+  }
 
-      // Write the node header.
-      f.write_fmt(format_args!(
-        "  \"{}\" [style=filled, fillcolor={}];\n",
-        node_str,
-        format_color(entry)
-      ))?;
+  ///
+  /// Like `clear`, but clears only the Nodes which match the given predicate: all other Nodes
+  /// retain their values and Generations.
+  ///
+  pub fn clear_matching<P: Fn(&N) -> bool>(&self, predicate: P) {
+    let mut inner = self.inner.write();
+    inner.clear_matching(predicate)
+  }
 
-      for dep_id in self.pg.neighbors(eid) {
-        let dep_entry = self.unsafe_entry_for_id(dep_id);
+  ///
+  /// Clears the value of only the given Node (if it is present and not pinned), so that it will
+  /// re-run the next time it is requested, and removes its dependency edges. Unlike
+  /// `invalidate_from_roots`, its dependents are not dirtied.
+  ///
+  /// NB: Because its dependents are not dirtied, they will continue to hold their values (computed
+  /// from a stale generation of this Node) until they next run for some other reason, even if this
+  /// Node computes a different value when it re-runs.
+  ///
+  pub fn reset_node(&self, node: &N) {
+    let mut inner = self.inner.write();
+    inner.reset_node(node)
+  }
 
-        // Write an entry per edge.
-        let dep_str = dep_entry.format(context);
-        f.write_fmt(format_args!("    \"{}\" -> \"{}\"\n", node_str, dep_str))?;
-      }
-    }
+  ///
+  /// Atomically replaces the dependency of the src Node on the old dst Node (if any) with a
+  /// dependency on the new dst Node, failing with `NodeError::cyclic` (and leaving the Graph
+  /// unchanged) if the new dependency would create a cycle. The new dependency is subject to the
+  /// same checks as a dependency requested by the src Node while running.
+  ///
+  /// Because its dependencies have changed, the src Node is dirtied such that it will re-run the
+  /// next time it is requested, and its transitive dependents are dirtied (which is reported to
+  /// the invalidation observer like any other invalidation). If the src Node is not present in the
+  /// Graph, this has no effect.
+  ///
+  /// A pinned src Node cannot be dirtied, and so its dependencies cannot be replaced: this fails
+  /// with `NodeError::forbidden_edge` (see `Graph::pin`).
+  ///
+  pub fn replace_dependency(&self, src: &N, old_dst: &N, new_dst: &N) -> Result<(), N::Error> {
+    self
+      .try_invalidate_and_observe(|inner, events| {
+        let src_id = match inner.entry_id(src) {
+          Some(&src_id) => src_id,
+          None => return Ok(InvalidationResult::default()),
+        };
+        if inner.is_pinned(src_id) {
+          return Err(N::Error::forbidden_edge(
+            src.to_string(),
+            new_dst.to_string(),
+          ));
+        }
+        self.check_dependency(inner, Some(src_id), new_dst)?;
+        self.check_max_nodes(inner, new_dst)?;
 
-    f.write_all(b"}\n")?;
-    Ok(())
+        let old_dst_id = inner.entry_id(old_dst).cloned();
+        let new_dst_id = inner.ensure_entry(new_dst.clone());
+        inner
+          .replace_dependency(src_id, old_dst_id, new_dst_id, events)
+          .map_err(|cycle| {
+            N::Error::cyclic(
+              cycle
+                .path
+                .into_iter()
+                .map(|e| e.node().to_string())
+                .collect(),
+            )
+          })
+      })
+      .map(|_| ())
   }
 
-  fn live_reachable<'g>(
-    &'g self,
-    roots: &[N],
-    context: &N::Context,
-  ) -> impl Iterator<Item = (&N, N::Item)> + 'g {
-    // TODO: This is a surprisingly expensive method, because it will clone all reachable values by
-    // calling `peek` on them.
-    let root_ids = roots
-      .iter()
-      .filter_map(|node| self.entry_id(node))
-      .cloned()
-      .collect();
-    self.live_internal(
-      self
-        .walk(root_ids, Direction::Outgoing, |_| false)
-        .collect(),
-      context.clone(),
-    )
+  ///
+  /// Pins the given Node, which exempts it from `clear`, `clear_values_preserving_edges`,
+  /// `reset_node`, and all invalidation until it is unpinned: this is useful for Nodes whose values
+  /// are known to be constant for the lifetime of the Graph. A Node need not be present in the
+  /// Graph to be pinned.
+  ///
+  /// NB: Because a pinned Node is never invalidated, it will continue to hold its value even if its
+  /// dependencies change, and invalidation does not pass through it to its dependents.
+  ///
+  pub fn pin(&self, node: &N) {
+    let mut inner = self.inner.write();
+    inner.pinned.insert(node.clone());
   }
 
-  fn live<'g>(&'g self, context: &N::Context) -> impl Iterator<Item = (&N, N::Item)> + 'g {
-    self.live_internal(self.pg.node_indices().collect(), context.clone())
+  ///
+  /// Unpins the given Node. See `Graph::pin`.
+  ///
+  pub fn unpin(&self, node: &N) {
+    let mut inner = self.inner.write();
+    inner.pinned.remove(node);
   }
 
-  fn live_internal<'g>(
-    &'g self,
-    entryids: Vec<EntryId>,
-    context: N::Context,
-  ) -> impl Iterator<Item = (&N, N::Item)> + 'g {
-    entryids
-      .into_iter()
-      .filter_map(move |eid| self.entry_for_id(eid))
-      .filter_map(move |entry| entry.peek(&context).map(|i| (entry.node(), i)))
+  ///
+  /// Sets the given metadata key for the given Node (if it is present in the Graph) to the given
+  /// value. Metadata is opaque to the Graph, and is preserved across invalidation and clearing of
+  /// the Node, but is discarded if the Node is removed from the Graph (see `Graph::gc_unreachable`).
+  ///
+  pub fn set_metadata(&self, node: &N, key: String, value: String) {
+    let mut inner = self.inner.write();
+    if let Some(&entry_id) = inner.entry_id(node) {
+      inner
+        .metadata
+        .entry(entry_id)
+        .or_default()
+        .insert(key, value);
+    }
   }
-}
 
-///
-/// A DAG (enforced on mutation) of Entries.
-///
-pub struct Graph<N: Node> {
-  inner: Mutex<InnerGraph<N>>,
-  invalidation_delay: Duration,
-}
+  ///
+  /// Gets the value of the given metadata key for the given Node. See `Graph::set_metadata`.
+  ///
+  pub fn get_metadata(&self, node: &N, key: &str) -> Option<String> {
+    let inner = self.inner.read();
+    let entry_id = inner.entry_id(node)?;
+    inner.metadata.get(entry_id)?.get(key).cloned()
+  }
 
-impl<N: Node> Graph<N> {
-  pub fn new() -> Graph<N> {
-    Self::new_with_invalidation_delay(Duration::from_millis(500))
+  ///
+  /// Dirties the values of all Nodes in the Graph, while retaining all of their dependency edges.
+  ///
+  /// Unlike `clear`, which moves every Node back to a NotStarted state so that it will re-run
+  /// unconditionally when next requested, a dirtied Node will use its retained dependency edges
+  /// to check whether its dependencies have changed, and will only re-run if they have.
+  ///
+  pub fn clear_values_preserving_edges(&self) {
+    let mut inner = self.inner.write();
+    inner.dirty_all()
   }
 
-  pub fn new_with_invalidation_delay(invalidation_delay: Duration) -> Graph<N> {
-    let inner = InnerGraph {
-      nodes: HashMap::default(),
-      pg: DiGraph::new(),
-    };
-    Graph {
-      inner: Mutex::new(inner),
-      invalidation_delay,
-    }
+  pub fn invalidate_from_roots<P: Fn(&N) -> bool>(&self, predicate: P) -> InvalidationResult {
+    self.invalidate_and_observe(|inner, events| inner.invalidate_from_roots(predicate, events))
   }
 
-  pub fn len(&self) -> usize {
-    let inner = self.inner.lock();
-    inner.nodes.len()
+  ///
+  /// Returns the Nodes which `invalidate_from_roots` would clear or dirty for the given predicate,
+  /// without invalidating anything: for example, in order to report the cost of an invalidation
+  /// before it is applied. Nodes which are already dirty are not included.
+  ///
+  pub fn preview_invalidation<P: Fn(&N) -> bool>(&self, predicate: P) -> Vec<N> {
+    self.inner.read().preview_invalidation(predicate)
   }
 
-  async fn get_inner(
+  ///
+  /// Like `invalidate_from_roots`, but coalesces calls which arrive within `window` of the first
+  /// call of a batch, and applies them as a single invalidation once the window has elapsed. All
+  /// calls in a batch return the combined InvalidationResult.
+  ///
+  /// A Node is invalidated by the batch if it matches any of the predicates, so the Nodes cleared
+  /// and dirtied are the same as if each of the invalidations had been applied individually.
+  ///
+  pub async fn invalidate_from_roots_coalesced<P>(
     &self,
-    src_id: Option<EntryId>,
-    context: &N::Context,
-    dst_node: N,
-  ) -> Result<(N::Item, Generation), N::Error> {
-    // Compute information about the dst under the Graph lock, and then release it.
-    let (dst_retry, mut entry, entry_id) = {
-      // Get or create the destination, and then insert the dep and return its state.
-      let mut inner = self.inner.lock();
-
-      // TODO: doing cycle detection under the lock... unfortunate, but probably unavoidable
-      // without a much more complicated algorithm.
-      let dst_id = inner.ensure_entry(dst_node);
-      let dst_retry = if let Some(src_id) = src_id {
-        if let Some(cycle_path) = Self::report_cycle(src_id, dst_id, &mut inner, context) {
-          // Cyclic dependency: render an error.
-          let path_strs = cycle_path
-            .into_iter()
-            .map(|e| e.node().to_string())
-            .collect();
-          return Err(N::Error::cyclic(path_strs));
+    window: Duration,
+    predicate: P,
+  ) -> InvalidationResult
+  where
+    P: Fn(&N) -> bool + Send + 'static,
+  {
+    // Join the pending batch, or start a new one.
+    let (deadline, mut receiver, caller) = {
+      let mut pending = self.coalesced_invalidation.lock();
+      let batch = pending.get_or_insert_with(|| {
+        let (sender, receiver) = watch::channel(None);
+        CoalescedInvalidation {
+          deadline: Instant::now() + window,
+          predicates: Vec::new(),
+          callers: 0,
+          sender: Arc::new(sender),
+          receiver,
         }
-
-        // Valid dependency.
-        test_trace_log!(
-          "Adding dependency from {:?} to {:?}",
-          inner.entry_for_id(src_id).unwrap().node(),
-          inner.entry_for_id(dst_id).unwrap().node()
-        );
-        // All edges get a weight of 1.0 so that we can Bellman-Ford over the graph, treating each
-        // edge as having equal weight.
-        inner.pg.add_edge(src_id, dst_id, 1.0);
-
-        // We can retry the dst Node if the src Node is not cacheable. If the src is not cacheable,
-        // it only be allowed to run once, and so Node invalidation does not pass through it.
-        !inner.entry_for_id(src_id).unwrap().node().cacheable()
-      } else {
-        // Otherwise, this is an external request: always retry.
-        test_trace_log!(
-          "Requesting node {:?}",
-          inner.entry_for_id(dst_id).unwrap().node()
-        );
-        true
+      });
+      batch.predicates.push(Box::new(predicate));
+      batch.callers += 1;
+      let caller = CoalescedCaller {
+        graph: self,
+        sender: batch.sender.clone(),
       };
-
-      let dst_entry = inner.entry_for_id(dst_id).cloned().unwrap();
-      (dst_retry, dst_entry, dst_id)
+      (batch.deadline, batch.receiver.clone(), caller)
     };
 
-    // Return the state of the destination.
-    if dst_retry {
-      // Retry the dst a number of times to handle Node invalidation.
-      let context = context.clone();
-      loop {
-        match entry.get_node_result(&context, entry_id).await {
-          Ok(r) => break Ok(r),
-          Err(err) if err == N::Error::invalidated() => {
-            let node = {
-              let inner = self.inner.lock();
-              inner.unsafe_entry_for_id(entry_id).node().clone()
-            };
-            info!(
-              "Filesystem changed during run: retrying `{}` in {:?}...",
-              node, self.invalidation_delay
-            );
-            sleep(self.invalidation_delay).await;
-            continue;
-          }
-          Err(other_err) => break Err(other_err),
-        }
+    sleep(deadline.saturating_duration_since(Instant::now())).await;
+
+    // The first caller to wake applies the batch. Because this does not depend on any particular
+    // caller waking, the batch is applied even if some (or all) of the callers are canceled.
+    if let Some(batch) = caller.take_batch(false) {
+      return self.apply_coalesced_invalidation(batch);
+    }
+    // Otherwise, wait for the caller which took the batch to apply it.
+    loop {
+      if let Some(invalidation_result) = *receiver.borrow() {
+        return invalidation_result;
       }
-    } else {
-      // Not retriable.
-      entry.get_node_result(context, entry_id).await
+      // NB: The channel cannot close while `caller` holds the Sender.
+      let _ = receiver.changed().await;
     }
   }
 
   ///
-  /// Request the given dst Node, optionally in the context of the given src Node.
+  /// Applies a batch of coalesced invalidations, which must already have been removed from the
+  /// Graph so that the invalidation (and any observer, which might itself invalidate) runs without
+  /// holding the lock on the pending batch.
   ///
-  /// If there is no src Node, or the src Node is not cacheable, this method will retry for
-  /// invalidation until the Node completes.
+  fn apply_coalesced_invalidation(&self, batch: CoalescedInvalidation<N>) -> InvalidationResult {
+    let invalidation_result =
+      self.invalidate_from_roots(|node| batch.predicates.iter().any(|predicate| predicate(node)));
+    let _ = batch.sender.send(Some(invalidation_result));
+    invalidation_result
+  }
+
   ///
-  /// Invalidation events in the graph (generally, filesystem changes) will cause cacheable Nodes
-  /// to be retried here for up to `invalidation_timeout`.
+  /// Invalidates every Node which declares itself to be an invalidation root via
+  /// `Node::is_invalidation_root`.
   ///
-  pub async fn get(
-    &self,
-    src_id: Option<EntryId>,
-    context: &N::Context,
-    dst_node: N,
-  ) -> Result<N::Item, N::Error> {
-    let (res, _generation) = self.get_inner(src_id, context, dst_node).await?;
-    Ok(res)
+  pub fn invalidate_all_roots(&self) -> InvalidationResult {
+    self.invalidate_from_roots(N::is_invalidation_root)
   }
 
   ///
-  /// Return the value of the given Node. Shorthand for `self.get(None, context, node)`.
+  /// Clears the value of exactly the given Node and dirties its transitive dependents.
   ///
-  pub async fn create(&self, node: N, context: &N::Context) -> Result<N::Item, N::Error> {
-    self.get(None, context, node).await
+  pub fn invalidate_node(&self, node: &N) -> InvalidationResult {
+    self.invalidate_nodes(std::slice::from_ref(node))
   }
 
   ///
-  /// Gets the value of the given Node (optionally waiting for it to have changed since the given
-  /// LastObserved token), and then returns its new value and a new LastObserved token.
+  /// Clears the values of exactly the given Nodes and dirties their transitive dependents, with a
+  /// single walk of the dependents. Unlike `invalidate_from_roots`, the cost is independent of the
+  /// size of the Graph (other than the number of dependents which are dirtied).
   ///
-  pub async fn poll(
-    &self,
-    node: N,
-    token: Option<LastObserved>,
-    delay: Option<Duration>,
-    context: &N::Context,
-  ) -> Result<(N::Item, LastObserved), N::Error> {
-    // If the node is currently clean at the given token, Entry::poll will delay until it has
-    // changed in some way.
-    if let Some(LastObserved(generation)) = token {
-      let entry = {
-        let mut inner = self.inner.lock();
-        let entry_id = inner.ensure_entry(node.clone());
-        inner.unsafe_entry_for_id(entry_id).clone()
-      };
-      entry.poll(context, generation).await;
-      if let Some(delay) = delay {
-        sleep(delay).await;
-      }
-    };
-
-    // Re-request the Node.
-    let (res, generation) = self.get_inner(None, context, node).await?;
-    Ok((res, LastObserved(generation)))
+  pub fn invalidate_nodes(&self, nodes: &[N]) -> InvalidationResult {
+    self.invalidate_and_observe(|inner, events| inner.invalidate_nodes(nodes, events))
   }
 
-  fn report_cycle(
-    src_id: EntryId,
-    potential_dst_id: EntryId,
-    inner: &mut InnerGraph<N>,
-    context: &N::Context,
-  ) -> Option<Vec<Entry<N>>> {
-    let mut counter = 0;
-    loop {
-      // Find one cycle if any cycles exist.
-      if let Some(cycle_path) = inner.report_cycle(src_id, potential_dst_id) {
-        // See if the cycle contains any dirty nodes. If there are dirty nodes, we can try clearing
-        // them, and then check if there are still any cycles in the graph.
-        let dirty_nodes: HashSet<_> = cycle_path
-          .iter()
-          .filter(|n| !n.is_clean(context))
-          .map(|n| n.node().clone())
-          .collect();
-        if dirty_nodes.is_empty() {
-          // We detected a cycle with no dirty nodes - there's a cycle and there's nothing we can do
-          // to remove it. We only log at debug because the UI will render the cycle.
-          debug!(
-            "Detected cycle considering adding edge from {:?} to {:?}; existing path: {:?}",
-            inner.entry_for_id(src_id).unwrap(),
-            inner.entry_for_id(potential_dst_id).unwrap(),
-            cycle_path
-          );
-          return Some(cycle_path);
-        }
-        counter += 1;
-        // Obsolete edges from a dirty node may cause fake cycles to be detected if there was a
-        // dirty dep from A to B, and we're trying to add a dep from B to A.
-        // If we detect a cycle that contains dirty nodes (and so potentially obsolete edges),
-        // we repeatedly cycle-detect, clearing (and re-running) and dirty nodes (and their edges)
-        // that we encounter.
-        //
-        // We do this repeatedly, because there may be multiple paths which would cause cycles,
-        // which contain dirty nodes. If we've cleared 10 separate paths which contain dirty nodes,
-        // and are still detecting cycle-causing paths containing dirty nodes, give up. 10 is a very
-        // arbitrary number, which we can increase if we find real graphs in the wild which hit this
-        // limit.
-        if counter > 10 {
-          warn!(
-            "Couldn't remove cycle containing dirty nodes after {} attempts; nodes in cycle: {:?}",
-            counter, cycle_path
-          );
-          return Some(cycle_path);
-        }
-        // Clear the dirty nodes, removing the edges from them, and try again.
-        inner.invalidate_from_roots(|node| dirty_nodes.contains(node));
-      } else {
-        return None;
-      }
-    }
+  ///
+  /// Returns the totals of all calls to `invalidate_from_roots` (including via
+  /// `invalidate_all_roots` and `invalidate_from_roots_coalesced`), `invalidate_node` and
+  /// `invalidate_nodes` over the lifetime of the Graph. A high ratio of dirtied to cleared Nodes may
+  /// indicate over-invalidation.
+  ///
+  pub fn invalidation_metrics(&self) -> InvalidationMetrics {
+    *self.invalidation_metrics.lock()
   }
 
   ///
-  /// Calculate the critical path for the subset of the graph that descends from these roots,
-  /// assuming this mapping between entries and durations.
+  /// Sets an observer which will be called for each Node that is cleared or dirtied by a call to
+  /// `invalidate_from_roots`, `invalidate_node` or `invalidate_nodes`, replacing any previous
+  /// observer.
   ///
-  pub fn critical_path<F>(&self, roots: &[N], duration: &F) -> (Duration, Vec<Entry<N>>)
-  where
-    F: Fn(&Entry<N>) -> Duration,
-  {
-    self.inner.lock().critical_path(roots, duration)
+  /// The observer is called after the Graph lock has been released, and so may call back into the
+  /// Graph.
+  ///
+  pub fn set_invalidation_observer(&self, obs: InvalidationObserver<N>) {
+    *self.invalidation_observer.lock() = Some(obs);
   }
 
   ///
-  /// Compares the generations of the dependencies of the given EntryId to their previous
-  /// generation values (re-computing or cleaning them first if necessary), and returns true if any
-  /// dependency has changed.
+  /// Sets an observer which will be called each time a Node completes (including when a dirty
+  /// Node is cleaned without re-running), replacing any previous observer.
   ///
-  async fn dependencies_changed(
-    &self,
-    entry_id: EntryId,
-    previous_dep_generations: Vec<Generation>,
-    context: &N::Context,
-  ) -> bool {
-    let generation_matches = {
-      let inner = self.inner.lock();
-      let dependency_ids = inner
-        .pg
-        .neighbors_directed(entry_id, Direction::Outgoing)
-        .collect::<Vec<_>>();
+  /// The observer is called after the Graph lock has been released, and so may call back into the
+  /// Graph.
+  ///
+  pub fn set_completion_observer(&self, obs: CompletionObserver<N>) {
+    *self.completion_observer.lock() = Some(obs);
+  }
 
-      if dependency_ids.len() != previous_dep_generations.len() {
-        // If we don't have the same number of current dependencies as there were generations
-        // previously, then they cannot match.
-        return true;
+  ///
+  /// Returns a Stream of the GraphEvents which occur after this call: Nodes starting and
+  /// completing, Nodes being cleared or dirtied by `invalidate_from_roots`, `invalidate_node`
+  /// or `invalidate_nodes`, and Nodes being canceled by `cancel_node`. The Stream ends when the
+  /// Graph is dropped.
+  ///
+  /// Events are published without blocking the Graph, and are buffered for each subscriber up to
+  /// a fixed capacity: if a subscriber falls further behind than that, the oldest events are
+  /// dropped, and the Stream continues from the oldest event which was retained.
+  ///
+  pub fn subscribe(&self) -> impl Stream<Item = GraphEvent<N>> {
+    stream::unfold(self.events.subscribe(), |mut receiver| async move {
+      loop {
+        match receiver.recv().await {
+          Ok(event) => return Some((event, receiver)),
+          Err(broadcast::error::RecvError::Lagged(_)) => continue,
+          Err(broadcast::error::RecvError::Closed) => return None,
+        }
       }
+    })
+  }
 
-      dependency_ids
-        .into_iter()
-        .zip(previous_dep_generations.into_iter())
-        .map(|(dep_id, previous_dep_generation)| {
-          let mut entry = inner
-            .entry_for_id(dep_id)
-            .unwrap_or_else(|| panic!("Dependency not present in Graph."))
-            .clone();
-          async move {
-            let (_, generation) = entry
-              .get_node_result(context, dep_id)
-              .await
-              .map_err(|_| ())?;
-            if generation == previous_dep_generation {
-              // Matched.
-              Ok(())
-            } else {
-              // Did not match. We error here to trigger fail-fast in `try_join_all`.
-              Err(())
-            }
-          }
-        })
-        .collect::<Vec<_>>()
-    };
+  ///
+  /// Publishes the GraphEvent created by the given function to any subscribers. The function is
+  /// not called if there are no subscribers.
+  ///
+  pub(crate) fn publish_event<F: FnOnce() -> GraphEvent<N>>(&self, event: F) {
+    if self.events.receiver_count() > 0 {
+      // NB: Sending fails only if all subscribers have been dropped since we checked.
+      let _ = self.events.send(event());
+    }
+  }
 
-    // We use try_join_all in order to speculatively execute all branches, and to fail fast if any
-    // generation mismatches. The first mismatch encountered will cause any extraneous cleaning
-    // work to be canceled. See #11290 for more information about the tradeoffs inherent in
-    // speculation.
-    future::try_join_all(generation_matches).await.is_err()
+  ///
+  /// Sets a policy which will be consulted each time a Node requests a dependency, replacing any
+  /// previous policy. If the policy returns false for a src and dst Node, the request fails with
+  /// `NodeError::forbidden_edge` (without creating the dst Node).
+  ///
+  /// The policy is called while holding the Graph lock, and so must not call back into the Graph.
+  ///
+  pub fn set_edge_policy(&self, policy: EdgePolicy<N>) {
+    *self.edge_policy.lock() = Some(policy);
   }
 
   ///
-  /// Clears the dependency edges of the given EntryId if the RunToken matches.
+  /// Sets the strategy used when a request for a dependency would create a cycle which does not
+  /// contain any dirty Nodes (which are instead cleared to break the cycle), replacing any
+  /// previous strategy. The default is `CycleStrategy::Error`.
   ///
-  fn clear_deps(&self, entry_id: EntryId, run_token: RunToken) {
-    let mut inner = self.inner.lock();
-    // If the RunToken mismatches, return.
-    if let Some(entry) = inner.entry_for_id(entry_id) {
-      if entry.run_token() != run_token {
-        return;
-      }
-    }
+  /// NB: When an edge is broken, its dependent will not observe changes to that dependency until
+  /// it next runs. And if the dependent is still running, it will continue to wait for the
+  /// dependency, so breaking a cycle between running Nodes will not allow them to complete.
+  ///
+  pub fn set_cycle_strategy(&self, strategy: CycleStrategy) {
+    *self.cycle_strategy.lock() = strategy;
+  }
 
-    // Otherwise, clear the deps.
-    // NB: Because `remove_edge` changes EdgeIndex values, we remove edges one at a time.
-    while let Some(dep_edge) = inner
-      .pg
-      .edges_directed(entry_id, Direction::Outgoing)
-      .next()
-      .map(|edge| edge.id())
-    {
-      inner.pg.remove_edge(dep_edge);
+  ///
+  /// Runs the given invalidation under the Graph lock, and then notifies any observer and
+  /// subscribers of the affected Nodes after the lock has been released.
+  ///
+  fn invalidate_and_observe<F>(&self, f: F) -> InvalidationResult
+  where
+    F: FnOnce(&mut InnerGraph<N>, InvalidationEvents<N>) -> InvalidationResult,
+  {
+    match self.try_invalidate_and_observe(|inner, events| Ok::<_, Infallible>(f(inner, events))) {
+      Ok(invalidation_result) => invalidation_result,
+      Err(infallible) => match infallible {},
     }
   }
 
   ///
-  /// When a Node is canceled because all receivers go away, the Executor for that Node will call
-  /// back to ensure that it is canceled.
-  ///
-  /// See also: `Self::complete`.
+  /// Like `invalidate_and_observe`, but for an invalidation which may fail. A failed invalidation
+  /// must not have invalidated anything, and is neither counted nor observed.
   ///
-  fn cancel(&self, entry_id: EntryId, run_token: RunToken) {
-    let mut inner = self.inner.lock();
-    if let Some(ref mut entry) = inner.entry_for_id_mut(entry_id) {
-      entry.cancel(run_token);
+  fn try_invalidate_and_observe<F, E>(&self, f: F) -> Result<InvalidationResult, E>
+  where
+    F: FnOnce(&mut InnerGraph<N>, InvalidationEvents<N>) -> Result<InvalidationResult, E>,
+  {
+    let observer = self.invalidation_observer.lock().clone();
+    let subscribed = self.events.receiver_count() > 0;
+    let mut events = Vec::new();
+    let invalidation_result = {
+      let mut inner = self.inner.write();
+      f(
+        &mut inner,
+        if observer.is_some() || subscribed {
+          Some(&mut events)
+        } else {
+          None
+        },
+      )?
+    };
+    {
+      let mut metrics = self.invalidation_metrics.lock();
+      metrics.invalidations += 1;
+      metrics.cleared += invalidation_result.cleared;
+      metrics.dirtied += invalidation_result.dirtied;
+      metrics.skipped += invalidation_result.skipped;
+    }
+    if let Some(observer) = observer {
+      for (node, kind) in &events {
+        observer(node, *kind);
+      }
     }
+    for (node, kind) in events {
+      self.publish_event(|| match kind {
+        InvalidationKind::Cleared => GraphEvent::NodeCleared(node),
+        InvalidationKind::Dirtied => GraphEvent::NodeDirtied(node),
+      });
+    }
+    Ok(invalidation_result)
+  }
+
+  pub fn visualize<V: NodeVisualizer<N>>(
+    &self,
+    visualizer: V,
+    roots: &[N],
+    max_nodes: Option<usize>,
+    path: &Path,
+    context: &N::Context,
+  ) -> io::Result<()> {
+    let file = File::create(path)?;
+    let mut f = BufWriter::new(file);
+    self.visualize_to_writer(visualizer, roots, max_nodes, context, &mut f)
   }
 
   ///
-  /// When the Executor finishes executing a Node it calls back to store the result value. We use
-  /// the run_token and dirty bits to determine whether the Node changed while we were busy
-  /// executing it, so that we can discard the work.
+  /// Writes a DOT/GraphViz rendering of the subgraph reachable from the given roots to the given
+  /// Write.
   ///
-  /// We use the dirty bit in addition to the RunToken in order to avoid cases where dependencies
-  /// change while we're running. In order for a dependency to "change" it must have been cleared
-  /// or been marked dirty. But if our dependencies have been cleared or marked dirty, then we will
-  /// have been as well. We can thus use the dirty bit as a signal that the generation values of
-  /// our dependencies are still accurate. The dirty bit is safe to rely on as it is only ever
-  /// mutated, and dependencies' dirty bits are only read, under the InnerGraph lock - this is only
-  /// reliably the case because Entry happens to require a &mut InnerGraph reference; it would be
-  /// great not to violate that in the future.
+  /// If `max_nodes` is set, at most that many Nodes are rendered (in a breadth first walk from the
+  /// roots, which is deterministic for a given Graph and roots), followed by a placeholder Node
+  /// indicating that the rendering was truncated.
   ///
-  /// See also: `Self::cancel`.
+  /// The lock on the Graph is held only while the rendering is captured, and not while it is
+  /// written.
   ///
-  fn complete(
+  pub fn visualize_to_writer<V: NodeVisualizer<N>, W: Write>(
     &self,
+    mut visualizer: V,
+    roots: &[N],
+    max_nodes: Option<usize>,
     context: &N::Context,
-    entry_id: EntryId,
-    run_token: RunToken,
-    sender: AsyncValueSender<NodeResult<N>>,
-    result: Option<Result<N::Item, N::Error>>,
-  ) {
-    let (entry, has_uncacheable_deps, dep_generations) = {
-      let inner = self.inner.lock();
-      let mut has_uncacheable_deps = false;
-      // Get the Generations of all dependencies of the Node. We can trust that these have not changed
-      // since we began executing, as long as we are not currently marked dirty (see the method doc).
-      let dep_generations = inner
-        .pg
-        .neighbors_directed(entry_id, Direction::Outgoing)
-        .filter_map(|dep_id| inner.entry_for_id(dep_id))
-        .map(|entry| {
-          // If a dependency is itself uncacheable or has uncacheable deps, this Node should
-          // also complete as having uncacheable dpes, independent of matching Generation values.
-          // This is to allow for the behaviour that an uncacheable Node should always have "dirty"
-          // (marked as UncacheableDependencies) dependents, transitively.
-          if entry.has_uncacheable_deps() {
-            has_uncacheable_deps = true;
-          }
-          entry.generation()
-        })
-        .collect();
-      (
-        inner.entry_for_id(entry_id).cloned(),
-        has_uncacheable_deps,
-        dep_generations,
-      )
+    w: &mut W,
+  ) -> io::Result<()> {
+    let snapshot = {
+      let inner = self.inner.read();
+      inner.visualization_snapshot(&mut visualizer, roots, max_nodes, context)
     };
-    if let Some(mut entry) = entry {
-      let mut inner = self.inner.lock();
-      entry.complete(
-        context,
-        run_token,
-        dep_generations,
-        sender,
-        result,
-        has_uncacheable_deps,
-        &mut inner,
-      );
-    }
+    snapshot.write_to(visualizer.color_scheme(), w)
   }
 
   ///
-  /// Clears the state of all Nodes in the Graph by dropping their state fields.
+  /// Writes the timings of the subgraph reachable from the given roots to the given Write, as
+  /// complete ("X") events in the Chrome Trace Event Format (as consumed by `chrome://tracing`).
   ///
-  pub fn clear(&self) {
-    let mut inner = self.inner.lock();
-    inner.clear()
+  /// Only the duration of the most recently completed run of each Node is recorded (and not when
+  /// it started), so every Node is laid out to start at the same time: because a Node runs for at
+  /// least as long as the dependencies that it waits for, this nests dependencies within their
+  /// dependents. Nodes which have never completed are omitted.
+  ///
+  pub fn export_chrome_trace<W: Write>(&self, roots: &[N], w: &mut W) -> io::Result<()> {
+    let inner = self.inner.read();
+    inner.export_chrome_trace(roots, w)
   }
 
-  pub fn invalidate_from_roots<P: Fn(&N) -> bool>(&self, predicate: P) -> InvalidationResult {
-    let mut inner = self.inner.lock();
-    inner.invalidate_from_roots(predicate)
+  ///
+  /// Writes a manifest of the subgraph reachable from the given roots to the given Write, and
+  /// returns the Digest of the manifest.
+  ///
+  /// The manifest contains a line per Node, in topological order (dependencies first, with ties
+  /// broken by the rendering of the Node), containing the rendering of the Node and the
+  /// `Node::item_digests` of its value (if it has a value which is valid for the given context).
+  /// Graphs containing the same Nodes, edges and values will produce identical manifests.
+  ///
+  pub fn digest_manifest<W: Write>(
+    &self,
+    roots: &[N],
+    context: &N::Context,
+    w: &mut W,
+  ) -> io::Result<Digest> {
+    let inner = self.inner.read();
+    inner.digest_manifest(roots, context, w)
   }
 
-  pub fn visualize<V: NodeVisualizer<N>>(
+  ///
+  /// Returns the number of Nodes reachable from the given roots via their dependencies (including
+  /// the roots themselves). Only the topology of the Graph is visited, so this is cheap enough to
+  /// compute (for example) the denominator of a progress indicator for each run.
+  ///
+  pub fn reachable_node_count(&self, roots: &[N]) -> usize {
+    let inner = self.inner.read();
+    let root_ids = roots
+      .iter()
+      .filter_map(|node| inner.entry_id(node))
+      .cloned()
+      .collect();
+    inner.walk(root_ids, Direction::Outgoing, |_| false).count()
+  }
+
+  ///
+  /// Writes a JSON rendering of the subgraph reachable from the given roots to the given Write.
+  ///
+  /// The rendering is an object containing a list of `nodes` (each with a `label` and a `state`),
+  /// and a list of `edges` from dependent to dependency as pairs of indexes into the `nodes` list.
+  ///
+  pub fn export_json<W: Write>(
     &self,
-    visualizer: V,
     roots: &[N],
-    path: &Path,
     context: &N::Context,
+    w: &mut W,
   ) -> io::Result<()> {
-    let inner = self.inner.lock();
-    inner.visualize(visualizer, roots, path, context)
+    let inner = self.inner.read();
+    inner.export_json(roots, context, w)
+  }
+
+  ///
+  /// Writes the topology of the graph (the identities of its Nodes and the dependencies between
+  /// them, but not their values) to the given Write, to be loaded by `deserialize_topology`.
+  ///
+  pub fn serialize_topology<W: Write>(&self, w: &mut W) -> io::Result<()>
+  where
+    N: Serialize,
+  {
+    let inner = self.inner.read();
+    inner.serialize_topology(w)
+  }
+
+  ///
+  /// Adds the given Nodes, and dependency edges (as src, dst) between Nodes, to the Graph without
+  /// running any of them, under a single acquisition of the Graph lock.
+  ///
+  /// If any of the edges would introduce a cycle, the Graph is left unmodified, and this fails with
+  /// `NodeError::cyclic` for the Nodes in the cycle (beginning and ending with the src of the edge
+  /// which introduced it). If adding the Nodes would exceed the Graph's maximum number of Nodes (see
+  /// `GraphOptions::max_nodes`), the Graph is left unmodified, and this fails with
+  /// `NodeError::graph_full`.
+  ///
+  pub fn preload(&self, nodes: Vec<N>, edges: Vec<(N, N)>) -> Result<(), N::Error> {
+    let mut inner = self.inner.write();
+    if let Some(max_nodes) = self.max_nodes {
+      let new_nodes = nodes
+        .iter()
+        .chain(edges.iter().flat_map(|(src, dst)| vec![src, dst]))
+        .filter(|node| inner.entry_id(node).is_none())
+        .collect::<HashSet<_>>();
+      if inner.nodes.len() + new_nodes.len() > max_nodes {
+        return Err(N::Error::graph_full());
+      }
+    }
+    inner
+      .preload(nodes, edges)
+      .map_err(|cycle| N::Error::cyclic(cycle.into_iter().map(|node| node.to_string()).collect()))
+  }
+
+  ///
+  /// Creates a Graph containing the same Nodes and dependency edges as this one, for speculative
+  /// analysis which should not affect this Graph.
+  ///
+  /// Only the shape of the Graph (and the options that it was constructed with: see
+  /// `GraphOptions`) is copied: none of the Nodes in the new Graph will have been started, and so
+  /// no cached values are shared with this Graph.
+  ///
+  pub fn clone_topology(&self) -> Graph<N> {
+    let graph = Self::new_with_options(self.options());
+    *graph.inner.write() = self.inner.read().clone_topology();
+    graph
+  }
+
+  ///
+  /// Creates a Graph containing the topology written by `serialize_topology`. None of the Nodes in
+  /// the new Graph will have been started.
+  ///
+  pub fn deserialize_topology<R: Read>(r: &mut R) -> io::Result<Graph<N>>
+  where
+    N: DeserializeOwned,
+  {
+    Self::deserialize_topology_with_options(r, GraphOptions::default())
+  }
+
+  ///
+  /// Like `deserialize_topology`, but creates the Graph with the given options. Loading the
+  /// topology is not subject to `GraphOptions::max_nodes`.
+  ///
+  pub fn deserialize_topology_with_options<R: Read>(
+    r: &mut R,
+    options: GraphOptions,
+  ) -> io::Result<Graph<N>>
+  where
+    N: DeserializeOwned,
+  {
+    let graph = Self::new_with_options(options);
+    graph.inner.write().load_topology(r)?;
+    Ok(graph)
   }
 
+  ///
+  /// Calls the given function with each Node reachable from the given roots that currently has a
+  /// clean value, and a reference to that value.
+  ///
+  /// The function is called while holding both the Graph lock and the lock of the Node's Entry,
+  /// neither of which is reentrant: it must not call back into the Graph (for example, to `peek`
+  /// or `poll` the Node), or it will deadlock.
+  ///
   pub fn visit_live_reachable(
     &self,
     roots: &[N],
     context: &N::Context,
-    mut f: impl FnMut(&N, N::Item),
+    f: impl FnMut(&N, &N::Item),
   ) {
-    let inner = self.inner.lock();
-    for (n, v) in inner.live_reachable(roots, context) {
-      f(n, v);
-    }
+    let inner = self.inner.read();
+    inner.visit_live_reachable(roots, context, f)
   }
 
-  pub fn visit_live(&self, context: &N::Context, mut f: impl FnMut(&N, N::Item)) {
-    let inner = self.inner.lock();
-    for (n, v) in inner.live(context) {
-      f(n, v);
-    }
+  ///
+  /// Calls the given function with each Node in the Graph that currently has a clean value, and a
+  /// reference to that value.
+  ///
+  /// As with `visit_live_reachable`, the function must not call back into the Graph.
+  ///
+  pub fn visit_live(&self, context: &N::Context, f: impl FnMut(&N, &N::Item)) {
+    let inner = self.inner.read();
+    inner.visit_live(context, f)
   }
 
   ///
@@ -903,11 +5064,26 @@ impl<N: Node> Graph<N> {
   where
     F: FnOnce() -> T,
   {
-    let _inner = self.inner.lock();
+    let _inner = self.inner.write();
     f()
   }
 }
 
+///
+/// Given two snapshots from `Graph::snapshot_generations`, returns the Nodes in the `after`
+/// snapshot which were not present in the `before` snapshot, or whose Generation has changed.
+///
+pub fn diff_generations<N: Node>(
+  before: &HashMap<N, Generation>,
+  after: &HashMap<N, Generation>,
+) -> Vec<N> {
+  after
+    .iter()
+    .filter(|&(node, generation)| before.get(node) != Some(generation))
+    .map(|(node, _)| node.clone())
+    .collect()
+}
+
 ///
 /// An opaque token that represents a particular observed "version" of a Node.
 ///
@@ -924,16 +5100,37 @@ where
 {
   graph: &'a InnerGraph<N>,
   direction: Direction,
-  deque: VecDeque<EntryId>,
+  // Entries to visit, paired with their depth from the nearest root.
+  deque: VecDeque<(EntryId, usize)>,
   walked: FixedBitSet,
+  max_depth: Option<usize>,
+  // True if the roots and the neighbors of each entry are visited in order of `Node::priority`.
+  prioritized: bool,
   stop_walking_predicate: F,
 }
 
-impl<'a, N: Node + 'a, F: Fn(&EntryId) -> bool> Iterator for Walk<'a, N, F> {
-  type Item = EntryId;
+impl<'a, N: Node + 'a, F: Fn(&EntryId) -> bool> Walk<'a, N, F> {
+  ///
+  /// Visits the roots, and then the neighbors of each entry, in order of their `Node::priority`.
+  /// Because this sorts the neighbors of every entry, it should only be used when the order of the
+  /// walk is observable.
+  ///
+  fn prioritized(mut self) -> Self {
+    let graph = self.graph;
+    self
+      .deque
+      .make_contiguous()
+      .sort_by_key(|&(id, _)| graph.unsafe_entry_for_id(id).node().priority());
+    self.prioritized = true;
+    self
+  }
 
-  fn next(&mut self) -> Option<Self::Item> {
-    while let Some(id) = self.deque.pop_front() {
+  ///
+  /// Returns the next EntryId in the walk, paired with its depth from the nearest root. Because the
+  /// walk is breadth first, this is the minimum depth at which the entry is reachable.
+  ///
+  fn next_with_depth(&mut self) -> Option<(EntryId, usize)> {
+    while let Some((id, depth)) = self.deque.pop_front() {
       // Visit this node and it neighbors if this node has not yet be visited and we aren't
       // stopping our walk at this node, based on if it satisfies the stop_walking_predicate.
       // This mechanism gives us a way to selectively dirty parts of the graph respecting node boundaries
@@ -942,16 +5139,36 @@ impl<'a, N: Node + 'a, F: Fn(&EntryId) -> bool> Iterator for Walk<'a, N, F> {
         continue;
       }
 
-      self
-        .deque
-        .extend(self.graph.pg.neighbors_directed(id, self.direction));
-      return Some(id);
+      if self.max_depth.map_or(true, |max_depth| depth < max_depth) {
+        let graph = self.graph;
+        let neighbors = graph.pg.neighbors_directed(id, self.direction);
+        if self.prioritized {
+          let mut neighbors: Vec<_> = neighbors.collect();
+          neighbors.sort_by_key(|&neighbor| graph.unsafe_entry_for_id(neighbor).node().priority());
+          self
+            .deque
+            .extend(neighbors.into_iter().map(|neighbor| (neighbor, depth + 1)));
+        } else {
+          self
+            .deque
+            .extend(neighbors.map(|neighbor| (neighbor, depth + 1)));
+        }
+      }
+      return Some((id, depth));
     }
 
     None
   }
 }
 
+impl<'a, N: Node + 'a, F: Fn(&EntryId) -> bool> Iterator for Walk<'a, N, F> {
+  type Item = EntryId;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.next_with_depth().map(|(id, _)| id)
+  }
+}
+
 ///
 /// Logs at trace level, but only in `cfg(test)`.
 ///
@@ -5,8 +5,10 @@ use std::fmt::{Debug, Display};
 use std::future::Future;
 use std::hash::Hash;
 use std::ops::DerefMut;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use hashing::Digest;
 
 use petgraph::stable_graph;
 
@@ -14,6 +16,9 @@ use crate::entry::Entry;
 use crate::Graph;
 
 // 2^32 Nodes ought to be more than enough for anyone!
+//
+// NB: This is the same type as `petgraph::graph::NodeIndex<u32>`, and so can be used directly to
+// index the PGraph lent by `Graph::with_raw_graph`.
 pub type EntryId = stable_graph::NodeIndex<u32>;
 
 ///
@@ -22,7 +27,7 @@ pub type EntryId = stable_graph::NodeIndex<u32>;
 /// Note that it is assumed that Nodes are very cheap to clone.
 ///
 #[async_trait]
-pub trait Node: Clone + Debug + Display + Eq + Hash + Send + 'static {
+pub trait Node: Clone + Debug + Display + Eq + Hash + Send + Sync + 'static {
   type Context: NodeContext<Node = Self>;
 
   type Item: Clone + Debug + Eq + Send + Sync + 'static;
@@ -41,6 +46,83 @@ pub trait Node: Clone + Debug + Display + Eq + Hash + Send + 'static {
   fn cacheable_item(&self, _item: &Self::Item) -> bool {
     self.cacheable()
   }
+
+  ///
+  /// Called before a Node which has not been started would run: if this returns a value (for
+  /// example, from a remote cache), the Node completes with that value without running.
+  ///
+  /// This is not called for a dirty Node which fails to be cleaned, because its value was computed
+  /// from dependencies which have since changed.
+  ///
+  fn try_load_cached(&self, _context: &Self::Context) -> Option<Self::Item> {
+    None
+  }
+
+  ///
+  /// True if the given result of running this Node should be memoized in the Graph. If false, the
+  /// result is returned to the Node's current waiters, but the Node will run again the next time
+  /// it is requested (for example: because the result represents a transient failure).
+  ///
+  /// Unlike `cacheable_item`, this applies in every Run. Errors are never memoized, regardless of
+  /// this method.
+  ///
+  fn should_cache_result(&self, _result: &Result<Self::Item, Self::Error>) -> bool {
+    true
+  }
+
+  ///
+  /// True if this Node is an "invalidation root": a Node which can be invalidated for a reason
+  /// other than having had its dependencies changed (such as a change to a file that it reads).
+  /// See `Graph::invalidate_all_roots`.
+  ///
+  fn is_invalidation_root(&self) -> bool {
+    false
+  }
+
+  ///
+  /// An estimate of the number of bytes of heap memory held by this Node, used by
+  /// `Graph::estimated_size_bytes`.
+  ///
+  fn heap_size(&self) -> usize {
+    0
+  }
+
+  ///
+  /// An estimate of the number of bytes of heap memory held by the given output of this Node,
+  /// used by `Graph::estimated_size_bytes`.
+  ///
+  fn item_heap_size(&self, _item: &Self::Item) -> usize {
+    0
+  }
+
+  ///
+  /// The Digests of any content referenced by the given output of this Node, used by
+  /// `Graph::digest_manifest`.
+  ///
+  fn item_digests(&self, _item: &Self::Item) -> Vec<Digest> {
+    vec![]
+  }
+
+  ///
+  /// The weight of the dependency edge from this Node to the given dependency, which is recorded
+  /// when the dependency is added to the Graph. Weights must be non-negative.
+  ///
+  /// Weights are charged to the paths which traverse their edges by
+  /// `Graph::critical_path_with_edge_weights`, and are used to choose which edge to break by
+  /// `CycleStrategy::BreakLowestWeightEdge`.
+  ///
+  fn edge_weight(&self, _dep: &Self) -> f32 {
+    1.0
+  }
+
+  ///
+  /// The priority of this Node relative to other Nodes which are ready at the same time: Nodes
+  /// with lower values are started (or visited) first. Nodes with equal priorities retain their
+  /// relative order.
+  ///
+  fn priority(&self) -> i32 {
+    0
+  }
 }
 
 pub trait NodeError: Clone + Debug + Eq + Send + Sync {
@@ -54,6 +136,18 @@ pub trait NodeError: Clone + Debug + Eq + Send + Sync {
   /// Creates an instance that represents that a Node dependency was cyclic along the given path.
   ///
   fn cyclic(path: Vec<String>) -> Self;
+
+  ///
+  /// Creates an instance that represents that a dependency from the src Node to the dst Node was
+  /// rejected by the Graph's edge policy. See `Graph::set_edge_policy`.
+  ///
+  fn forbidden_edge(src: String, dst: String) -> Self;
+
+  ///
+  /// Creates an instance that represents that a Node could not be added to the Graph because it
+  /// already contains its maximum number of Nodes. See `GraphOptions::max_nodes`.
+  ///
+  fn graph_full() -> Self;
 }
 
 ///
@@ -71,6 +165,44 @@ pub trait NodeVisualizer<N: Node> {
   fn color(&mut self, entry: &Entry<N>, context: &N::Context) -> String;
 }
 
+///
+/// A NodeVisualizer which renders a heat map of how recently each Node completed, relative to the
+/// time at which the visualizer was created: Nodes which completed most recently are rendered
+/// with the darkest color of the scheme, and Nodes which completed at least `max_age` ago with the
+/// lightest. Nodes which have never completed are rendered in white.
+///
+pub struct StateAgeVisualizer {
+  now: Instant,
+  max_age: Duration,
+}
+
+impl StateAgeVisualizer {
+  pub fn new(max_age: Duration) -> StateAgeVisualizer {
+    StateAgeVisualizer {
+      now: Instant::now(),
+      max_age,
+    }
+  }
+}
+
+impl<N: Node> NodeVisualizer<N> for StateAgeVisualizer {
+  fn color_scheme(&self) -> &str {
+    "reds9"
+  }
+
+  fn color(&mut self, entry: &Entry<N>, _context: &N::Context) -> String {
+    match entry.last_run_completed() {
+      None => "white".to_owned(),
+      Some(completed) => {
+        let age = self.now.saturating_duration_since(completed);
+        let fraction = (age.as_secs_f64() / self.max_age.as_secs_f64()).min(1.0);
+        // The colors of the scheme are numbered from 1 (lightest) to 9 (darkest).
+        (9 - (fraction * 8.0).round() as usize).to_string()
+      }
+    }
+  }
+}
+
 ///
 /// A context passed between Nodes that also stores an EntryId to uniquely identify them.
 ///
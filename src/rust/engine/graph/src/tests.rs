@@ -1,19 +1,31 @@
 use std::cmp;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
 use std::future::Future;
-use std::hash::{Hash, Hasher};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io;
 use std::ops::DerefMut;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use futures::future;
+use futures::{FutureExt, StreamExt};
+use hashing::Digest;
 use parking_lot::Mutex;
+use petgraph::visit::EdgeRef;
 use rand::{self, Rng};
+use serde::{Deserialize, Serialize};
 use tokio::time::{error::Elapsed, sleep, timeout};
 
-use crate::{EntryId, Graph, InvalidationResult, Node, NodeContext, NodeError, Stats};
+use crate::entry::{Entry, EntryResult, EntryState, Generation, NodeStatus, RunToken};
+use crate::{
+  diff_generations, CycleStrategy, DependencyEdge, Direction, DrainTimeout, EntryId, Graph,
+  GraphEvent, GraphOptions, GraphStats, InvalidationKind, InvalidationMetrics, InvalidationResult,
+  Node, NodeContext, NodeError, NodeHasher, NodeVisualizer, StateAgeVisualizer, Stats,
+};
 
 #[tokio::test]
 async fn create() {
@@ -45,7 +57,8 @@ async fn invalidate_and_clean() {
     graph.invalidate_from_roots(|&TNode(n, _)| n == 1),
     InvalidationResult {
       cleared: 1,
-      dirtied: 1
+      dirtied: 1,
+      skipped: 0,
     }
   );
 
@@ -80,7 +93,8 @@ async fn invalidate_and_rerun() {
     graph.invalidate_from_roots(|&TNode(n, _)| n == 1),
     InvalidationResult {
       cleared: 1,
-      dirtied: 1
+      dirtied: 1,
+      skipped: 0,
     }
   );
 
@@ -110,7 +124,8 @@ async fn invalidate_with_changed_dependencies() {
     graph.invalidate_from_roots(|&TNode(n, _)| n == 1),
     InvalidationResult {
       cleared: 1,
-      dirtied: 1
+      dirtied: 1,
+      skipped: 0,
     }
   );
 
@@ -129,6 +144,7 @@ async fn invalidate_with_changed_dependencies() {
     InvalidationResult {
       cleared: 1,
       dirtied: 0,
+      skipped: 0,
     }
   );
 }
@@ -704,7 +720,7 @@ async fn critical_path() {
   // Construct a graph and populate it with the nodes and edges prettily defined above.
   let graph = Graph::new();
   {
-    let inner = &mut graph.inner.lock();
+    let inner = &mut graph.inner.write();
     for (node, _) in &nodes {
       let node_index = inner.pg.add_node(node_entry(node));
       inner.nodes.insert(node_key(node), node_index);
@@ -712,7 +728,14 @@ async fn critical_path() {
     for (src, dst) in &deps {
       let src = inner.nodes[&node_key(src)];
       let dst = inner.nodes[&node_key(dst)];
-      inner.pg.add_edge(src, dst, 1.0);
+      inner.pg.add_edge(
+        src,
+        dst,
+        DependencyEdge {
+          weight: 1.0,
+          generation: Generation::initial(),
+        },
+      );
     }
   }
 
@@ -755,6 +778,3485 @@ async fn critical_path() {
   }
 }
 
+#[tokio::test]
+async fn dependencies_and_dependents() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  assert_eq!(
+    graph.dependencies(&TNode::new(2), &context),
+    Some(vec![TNode::new(1)])
+  );
+  assert_eq!(graph.dependencies(&TNode::new(0), &context), Some(vec![]));
+  assert_eq!(graph.dependents(&TNode::new(1)), Some(vec![TNode::new(2)]));
+  assert_eq!(graph.dependents(&TNode::new(2)), Some(vec![]));
+
+  // Nodes which are not present in the graph have neither.
+  assert_eq!(graph.dependencies(&TNode::new(3), &context), None);
+  assert_eq!(graph.dependents(&TNode::new(3)), None);
+}
+
+#[tokio::test]
+async fn transitive_dependencies() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  // The roots are included, and absent roots are ignored.
+  assert_eq!(
+    graph.transitive_dependencies(&[TNode::new(1), TNode::new(3)]),
+    vec![TNode::new(1), TNode::new(0)]
+      .into_iter()
+      .collect::<HashSet<_>>()
+  );
+  assert_eq!(
+    graph.transitive_dependencies(&[TNode::new(2)]),
+    vec![TNode::new(2), TNode::new(1), TNode::new(0)]
+      .into_iter()
+      .collect::<HashSet<_>>()
+  );
+  assert!(graph.transitive_dependencies(&[TNode::new(3)]).is_empty());
+}
+
+#[tokio::test]
+async fn visit_live() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  // Clear the bottom node, so that it is not visited.
+  graph.invalidate_from_roots(|&TNode(n, _)| n == 0);
+
+  let mut visited = Vec::new();
+  graph.visit_live_reachable(&[TNode::new(1)], &context, |n, v| {
+    visited.push((n.clone(), v.len()));
+  });
+  assert_eq!(visited, vec![]);
+
+  let context = context.new_run(1);
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  graph.visit_live_reachable(&[TNode::new(1)], &context, |n, v| {
+    visited.push((n.clone(), v.len()));
+  });
+  assert_eq!(visited, vec![(TNode::new(1), 2), (TNode::new(0), 1)]);
+
+  let mut visited = HashSet::new();
+  graph.visit_live(&context, |n, _| {
+    visited.insert(n.clone());
+  });
+  assert_eq!(
+    visited,
+    vec![TNode::new(2), TNode::new(1), TNode::new(0)]
+      .into_iter()
+      .collect::<HashSet<_>>()
+  );
+}
+
+#[tokio::test]
+async fn visit_live_does_not_clone() {
+  let graph = Arc::new(Graph::new());
+  let context = PContext::new(graph.clone());
+  let nodes = (0..10).map(|id| PNode(id, 0)).collect::<Vec<_>>();
+  assert!(graph.get_batch(None, &context, nodes.clone()).await.is_ok());
+  let clones = *context.clones.lock();
+
+  // Visiting values borrows them, without any per-Node clones.
+  let mut visited = 0;
+  graph.visit_live_reachable(&nodes, &context, |_, item| visited += item.0);
+  graph.visit_live(&context, |_, item| visited += item.0);
+  assert_eq!(visited, 90);
+  assert_eq!(*context.clones.lock(), clones);
+
+  // While peeking clones each value.
+  for node in &nodes {
+    assert_eq!(graph.peek(node, &context).map(|item| item.0), Some(node.0));
+  }
+  assert_eq!(*context.clones.lock(), clones + nodes.len());
+}
+
+#[test]
+fn critical_path_with_edge_costs() {
+  // A diamond, where the left branch is slower than the right.
+  let durations = [1, 5, 3, 1];
+  let graph = graph_with_edges(&[0, 1, 2, 3], &[(0, 1), (0, 2), (1, 3), (2, 3)]);
+  let duration = |entry: &Entry<TNode>| Duration::from_secs(durations[entry.node().0]);
+  let path_ids = |path: Vec<Entry<TNode>>| path.iter().map(|e| e.node().0).collect::<Vec<_>>();
+
+  let (total, path) = graph.critical_path(&[TNode::new(0)], &duration);
+  assert_eq!(Duration::from_secs(7), total);
+  assert_eq!(vec![0, 1, 3], path_ids(path));
+
+  // But a high enough cost on an edge to the right branch makes it critical.
+  let edge_cost = |src: &Entry<TNode>, dst: &Entry<TNode>| {
+    if (src.node().0, dst.node().0) == (0, 2) {
+      Duration::from_secs(10)
+    } else {
+      Duration::from_secs(0)
+    }
+  };
+  let (total, path) = graph.critical_path_with_edge_costs(&[TNode::new(0)], &duration, &edge_cost);
+  assert_eq!(Duration::from_secs(15), total);
+  assert_eq!(vec![0, 2, 3], path_ids(path));
+}
+
+#[test]
+fn critical_path_with_edge_weights() {
+  // A diamond, where the left branch is slower than the right.
+  let durations = [1, 5, 3, 1];
+  let graph = graph_with_edges(&[0, 1, 2, 3], &[(0, 1), (0, 2), (1, 3), (2, 3)]);
+  let duration = |entry: &Entry<TNode>| Duration::from_secs(durations[entry.node().0]);
+  let path_ids = |path: Vec<Entry<TNode>>| path.iter().map(|e| e.node().0).collect::<Vec<_>>();
+
+  // With the default weights, each edge is charged the same cost.
+  let (total, path) =
+    graph.critical_path_with_edge_weights(&[TNode::new(0)], &duration, Duration::from_secs(1));
+  assert_eq!(Duration::from_secs(9), total);
+  assert_eq!(vec![0, 1, 3], path_ids(path));
+
+  // But a heavy enough edge to the right branch makes it critical.
+  {
+    let mut inner = graph.inner.write();
+    let (src_id, dst_id) = (inner.nodes[&TNode::new(0)], inner.nodes[&TNode::new(2)]);
+    let edge = inner.pg.find_edge(src_id, dst_id).unwrap();
+    inner.pg[edge].weight = 4.0;
+  }
+  let (total, path) =
+    graph.critical_path_with_edge_weights(&[TNode::new(0)], &duration, Duration::from_secs(1));
+  assert_eq!(Duration::from_secs(10), total);
+  assert_eq!(vec![0, 2, 3], path_ids(path));
+
+  // While weights do not affect the critical path otherwise.
+  let (total, path) = graph.critical_path(&[TNode::new(0)], &duration);
+  assert_eq!(Duration::from_secs(7), total);
+  assert_eq!(vec![0, 1, 3], path_ids(path));
+}
+
+#[test]
+fn critical_path_detailed() {
+  let durations = [1, 5, 3, 1];
+  let graph = graph_with_edges(&[0, 1, 2, 3], &[(0, 1), (0, 2), (1, 3), (2, 3)]);
+  let duration = |entry: &Entry<TNode>| Duration::from_secs(durations[entry.node().0]);
+
+  let critical_path = graph.critical_path_detailed(&[TNode::new(0)], &duration);
+  assert_eq!(Duration::from_secs(7), critical_path.total);
+  assert_eq!(
+    vec![
+      (0, Duration::from_secs(1)),
+      (1, Duration::from_secs(5)),
+      (3, Duration::from_secs(1))
+    ],
+    critical_path
+      .entries
+      .iter()
+      .map(|(entry, duration)| (entry.node().0, *duration))
+      .collect::<Vec<_>>()
+  );
+  assert_eq!(
+    critical_path.total,
+    critical_path.entries.iter().map(|(_, d)| *d).sum()
+  );
+
+  // A graph without any of the roots has an empty critical path.
+  let critical_path = graph.critical_path_detailed(&[TNode::new(4)], &duration);
+  assert_eq!(Duration::from_secs(0), critical_path.total);
+  assert!(critical_path.entries.is_empty());
+}
+
+#[test]
+fn find_cycles() {
+  let graph = graph_with_edges(
+    &[0, 1, 2, 3, 4, 5],
+    &[(0, 1), (1, 2), (2, 0), (2, 3), (4, 4)],
+  );
+  let mut cycles = graph.find_cycles();
+  cycles.sort_by_key(|cycle| cycle.len());
+  assert_eq!(
+    vec![
+      vec![TNode::new(4)],
+      vec![TNode::new(0), TNode::new(1), TNode::new(2)],
+    ],
+    cycles
+  );
+
+  // An acyclic graph has no cycles.
+  let graph = graph_with_edges(&[0, 1, 2], &[(0, 1), (1, 2), (0, 2)]);
+  assert!(graph.find_cycles().is_empty());
+}
+
+#[tokio::test]
+async fn invalidate_node() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+
+  // Create three nodes.
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  // Clear the middle Node, which dirties the upper node.
+  assert_eq!(
+    graph.invalidate_node(&TNode::new(1)),
+    InvalidationResult {
+      cleared: 1,
+      dirtied: 1,
+      skipped: 0,
+    }
+  );
+
+  // Confirm that the cleared Node re-runs, and the upper node is cleaned without re-running.
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(
+    context.runs(),
+    vec![TNode::new(2), TNode::new(1), TNode::new(0), TNode::new(1)]
+  );
+
+  // A Node which is not in the graph is not cleared.
+  assert_eq!(
+    graph.invalidate_node(&TNode::new(3)),
+    InvalidationResult {
+      cleared: 0,
+      dirtied: 0,
+      skipped: 0,
+    }
+  );
+}
+
+#[tokio::test]
+async fn retries_are_limited() {
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    invalidation_delay: Duration::from_millis(10),
+    max_retries: Some(2),
+    ..GraphOptions::default()
+  }));
+
+  // A Node which is invalidated twice is retried twice, and then succeeds.
+  let context =
+    TContext::new(graph.clone()).with_invalidations(vec![(TNode::new(0), 2)].into_iter().collect());
+  assert_eq!(
+    graph.create(TNode::new(0), &context).await,
+    Ok(vec![T(0, 0)])
+  );
+  assert_eq!(context.runs(), vec![TNode::new(0); 3]);
+
+  // But a Node which is invalidated three times exhausts its retries.
+  let context = TContext::new(graph.clone())
+    .with_salt(1)
+    .with_invalidations(vec![(TNode::new(1), 3)].into_iter().collect());
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Err(TError::Invalidated)
+  );
+  assert_eq!(context.runs(), vec![TNode::new(1); 3]);
+}
+
+#[tokio::test]
+async fn invalidation_observer() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+
+  // Create three nodes.
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  // Register an observer which calls back into the Graph, to confirm that the lock is not held.
+  let events = Arc::new(Mutex::new(Vec::new()));
+  let observer_graph = Arc::downgrade(&graph);
+  let observer_events = events.clone();
+  graph.set_invalidation_observer(Arc::new(move |node: &TNode, kind: InvalidationKind| {
+    assert_eq!(3, observer_graph.upgrade().unwrap().len());
+    observer_events.lock().push((node.clone(), kind));
+  }));
+
+  // Clear the bottom Node, which dirties the middle and upper nodes.
+  assert_eq!(
+    graph.invalidate_from_roots(|&TNode(n, _)| n == 0),
+    InvalidationResult {
+      cleared: 1,
+      dirtied: 2,
+      skipped: 0,
+    }
+  );
+  let mut observed = events.lock().drain(..).collect::<Vec<_>>();
+  observed.sort_by_key(|(node, _)| node.0);
+  assert_eq!(
+    vec![
+      (TNode::new(0), InvalidationKind::Cleared),
+      (TNode::new(1), InvalidationKind::Dirtied),
+      (TNode::new(2), InvalidationKind::Dirtied),
+    ],
+    observed
+  );
+}
+
+#[test]
+fn walk_from() {
+  // 0 -> 1 -> 2 -> 3, with a shortcut from 0 -> 2.
+  let graph = graph_with_edges(&[0, 1, 2, 3], &[(0, 1), (1, 2), (2, 3), (0, 2)]);
+  let walk = |roots: &[usize], direction, max_depth| {
+    let roots = roots.iter().map(|&id| TNode::new(id)).collect::<Vec<_>>();
+    let mut reachable = graph
+      .walk_from(&roots, direction, max_depth)
+      .into_iter()
+      .map(|(node, depth)| (node.0, depth))
+      .collect::<Vec<_>>();
+    reachable.sort();
+    reachable
+  };
+
+  assert_eq!(vec![(0, 0)], walk(&[0], Direction::Outgoing, 0));
+  // Node 2 is reachable at depths 1 and 2, and reports the minimum.
+  assert_eq!(
+    vec![(0, 0), (1, 1), (2, 1)],
+    walk(&[0], Direction::Outgoing, 1)
+  );
+  assert_eq!(
+    vec![(0, 0), (1, 1), (2, 1), (3, 2)],
+    walk(&[0], Direction::Outgoing, 5)
+  );
+  assert_eq!(
+    vec![(0, 1), (1, 1), (2, 0), (3, 0)],
+    walk(&[3, 2], Direction::Incoming, 1)
+  );
+  assert_eq!(
+    Vec::<(usize, usize)>::new(),
+    walk(&[4], Direction::Outgoing, 1)
+  );
+}
+
+#[test]
+fn serialize_topology() {
+  let graph = graph_with_edges(&[0, 1, 2, 3], &[(0, 1), (0, 2), (1, 3), (2, 3)]);
+  let mut bytes = Vec::new();
+  graph.serialize_topology(&mut bytes).unwrap();
+
+  let restored: Graph<TNode> = Graph::deserialize_topology(&mut bytes.as_slice()).unwrap();
+  assert_eq!(4, restored.len());
+  assert_eq!(
+    Some(vec![TNode::new(1), TNode::new(2)]),
+    restored.dependents(&TNode::new(3))
+  );
+  assert_eq!(
+    (0..4).map(TNode::new).collect::<HashSet<_>>(),
+    restored.transitive_dependencies(&[TNode::new(0)])
+  );
+
+  // The serialized form is stable.
+  let mut restored_bytes = Vec::new();
+  restored.serialize_topology(&mut restored_bytes).unwrap();
+  assert_eq!(bytes, restored_bytes);
+
+  // And may be loaded into a Graph with options.
+  let restored: Graph<TNode> = Graph::deserialize_topology_with_options(
+    &mut bytes.as_slice(),
+    GraphOptions {
+      max_nodes: Some(4),
+      ..GraphOptions::default()
+    },
+  )
+  .unwrap();
+  assert_eq!(4, restored.len());
+  assert_eq!(restored.utilization(), Some(1.0));
+}
+
+#[test]
+fn deserialize_topology_invalid() {
+  let deserialize = |nodes: Vec<TNode>, edges: Vec<(u32, u32)>| {
+    let bytes = bincode::serialize(&(nodes, edges)).unwrap();
+    Graph::<TNode>::deserialize_topology(&mut bytes.as_slice())
+      .err()
+      .map(|e| e.kind())
+  };
+
+  assert_eq!(
+    None,
+    deserialize(vec![TNode::new(0), TNode::new(1)], vec![(0, 1)])
+  );
+  // Duplicate Nodes.
+  assert_eq!(
+    Some(io::ErrorKind::InvalidData),
+    deserialize(vec![TNode::new(0), TNode::new(0)], vec![])
+  );
+  // Unknown entries.
+  assert_eq!(
+    Some(io::ErrorKind::InvalidData),
+    deserialize(vec![TNode::new(0)], vec![(0, 1)])
+  );
+  // Cycles, which are reported with the Nodes involved.
+  assert_eq!(
+    Some(io::ErrorKind::InvalidData),
+    deserialize(vec![TNode::new(0), TNode::new(1)], vec![(0, 1), (1, 0)])
+  );
+  let bytes = bincode::serialize(&(
+    (0..3).map(TNode::new).collect::<Vec<_>>(),
+    vec![(0_u32, 1_u32), (1, 2), (2, 1)],
+  ))
+  .unwrap();
+  assert_eq!(
+    Graph::<TNode>::deserialize_topology(&mut bytes.as_slice())
+      .err()
+      .map(|e| e.to_string()),
+    Some("Edges introduced a cycle between: TNode(1, true), TNode(2, true).".to_owned())
+  );
+  assert_eq!(
+    Some(io::ErrorKind::InvalidData),
+    deserialize(vec![TNode::new(0)], vec![(0, 0)])
+  );
+  // Truncated input.
+  assert_eq!(
+    Some(io::ErrorKind::InvalidData),
+    Graph::<TNode>::deserialize_topology(&mut &[1u8, 2][..])
+      .err()
+      .map(|e| e.kind())
+  );
+}
+
+#[tokio::test]
+async fn stats() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone())
+    .with_delays(
+      vec![(TNode::new(2), Duration::from_millis(500))]
+        .into_iter()
+        .collect(),
+    )
+    .with_uncacheable(vec![TNode::new(2)].into_iter().collect());
+
+  // Complete two Nodes, and create one NotStarted Node.
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  graph.inner.write().ensure_entry(TNode::new(10));
+
+  // Then start two more Nodes, one of which is uncacheable and will delay before requesting its
+  // dependency.
+  let running = {
+    let graph = graph.clone();
+    let context = context.clone();
+    tokio::spawn(async move { graph.create(TNode::new(3), &context).await })
+  };
+  sleep(Duration::from_millis(100)).await;
+  assert_eq!(
+    GraphStats {
+      nodes: 5,
+      edges: 2,
+      not_started: 1,
+      running: 2,
+      completed: 2,
+      uncacheable: 1,
+    },
+    graph.stats()
+  );
+
+  // Once the Nodes complete, their dependency is recorded.
+  assert_eq!(
+    running.await.unwrap(),
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0), T(3, 0)])
+  );
+  assert_eq!(
+    GraphStats {
+      nodes: 5,
+      edges: 3,
+      not_started: 1,
+      running: 0,
+      completed: 4,
+      uncacheable: 1,
+    },
+    graph.stats()
+  );
+}
+
+#[test]
+fn visualize_to_writer() {
+  let graph = Arc::new(graph_with_edges(&[0, 1], &[(0, 1)]));
+  let context = TContext::new(graph.clone());
+
+  let mut rendered = Vec::new();
+  graph
+    .visualize_to_writer(TVisualizer, &[TNode::new(0)], None, &context, &mut rendered)
+    .unwrap();
+  let expected = [
+    "digraph plans {",
+    "  node[colorscheme=set19];",
+    "  concentrate=true;",
+    "  rankdir=TB;",
+    "  \"TNode(0, true) == <None>\" [style=filled, fillcolor=1];",
+    "    \"TNode(0, true) == <None>\" -> \"TNode(1, true) == <None>\"",
+    "  \"TNode(1, true) == <None>\" [style=filled, fillcolor=1];",
+    "}",
+  ]
+  .iter()
+  .map(|line| format!("{}\n", line))
+  .collect::<String>();
+  assert_eq!(expected, String::from_utf8(rendered.clone()).unwrap());
+
+  // Visualizing to a file renders identically.
+  let path = std::env::temp_dir().join(format!("visualize_to_writer-{}.dot", std::process::id()));
+  graph
+    .visualize(TVisualizer, &[TNode::new(0)], None, &path, &context)
+    .unwrap();
+  let written = std::fs::read(&path).unwrap();
+  std::fs::remove_file(&path).unwrap();
+  assert_eq!(rendered, written);
+}
+
+#[tokio::test]
+async fn export_json() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  graph.inner.write().ensure_entry(TNode::new(2));
+
+  let mut exported = Vec::new();
+  graph
+    .export_json(&[TNode::new(1)], &context, &mut exported)
+    .unwrap();
+  let exported: serde_json::Value = serde_json::from_slice(&exported).unwrap();
+  assert_eq!(
+    serde_json::json!({
+      "nodes": [
+        {"label": "TNode(1, true) == [T(0, 0), T(1, 0)]", "state": "completed"},
+        {"label": "TNode(0, true) == [T(0, 0)]", "state": "completed"},
+      ],
+      "edges": [[0, 1]],
+    }),
+    exported
+  );
+}
+
+#[test]
+fn cycle_clearing_limit() {
+  // Create 12 paths from node 1 to node 0, each via a dirty node.
+  let dirty_ids = (2..14).collect::<Vec<_>>();
+  let edges = dirty_ids
+    .iter()
+    .flat_map(|&id| vec![(1, id), (id, 0)])
+    .collect::<Vec<_>>();
+  let node_ids = (0..14).collect::<Vec<_>>();
+  let graph_with_dirty_paths = |mut graph: Graph<TNode>| {
+    graph.inner = graph_with_edges(&node_ids, &edges).inner;
+    {
+      let inner = graph.inner.read();
+      for &id in &dirty_ids {
+        let entry = inner.unsafe_entry_for_id(inner.nodes[&TNode::new(id)]);
+        *entry.state.lock() = EntryState::Completed {
+          run_token: RunToken::initial(),
+          generation: Generation::initial(),
+          pollers: Vec::new(),
+          result: EntryResult::Dirty(vec![T(id, 0)]),
+          dep_generations: Vec::new(),
+        };
+      }
+    }
+    Arc::new(graph)
+  };
+  let report_cycle = |graph: &Arc<Graph<TNode>>| {
+    let context = TContext::new(graph.clone());
+    let mut inner = graph.inner.write();
+    let (src, dst) = (inner.nodes[&TNode::new(0)], inner.nodes[&TNode::new(1)]);
+    graph.report_cycle(src, dst, &mut inner, &context)
+  };
+
+  // Adding an edge from 0 to 1 requires clearing all 12 paths, which exceeds the default limit.
+  let graph = graph_with_dirty_paths(Graph::new());
+  assert!(report_cycle(&graph).is_some());
+
+  // But succeeds with a higher limit.
+  let graph = graph_with_dirty_paths(Graph::new_with_options(GraphOptions {
+    cycle_clearing_limit: 12,
+    ..GraphOptions::default()
+  }));
+  assert!(report_cycle(&graph).is_none());
+}
+
+#[tokio::test]
+async fn node_versions() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(None, graph.node_versions(&TNode::new(0)));
+
+  assert_eq!(
+    graph.create(TNode::new(0), &context).await,
+    Ok(vec![T(0, 0)])
+  );
+  let (run_token1, generation1) = graph.node_versions(&TNode::new(0)).unwrap();
+  assert_ne!(RunToken::initial(), run_token1);
+  assert_ne!(Generation::initial(), generation1);
+
+  // Re-running with the same output changes only the RunToken.
+  graph.invalidate_node(&TNode::new(0));
+  assert_eq!(
+    graph.create(TNode::new(0), &context).await,
+    Ok(vec![T(0, 0)])
+  );
+  let (run_token2, generation2) = graph.node_versions(&TNode::new(0)).unwrap();
+  assert_ne!(run_token1, run_token2);
+  assert_eq!(generation1, generation2);
+
+  // Re-running with a different output changes both.
+  graph.invalidate_node(&TNode::new(0));
+  let context = context.new_run(1).with_salt(1);
+  assert_eq!(
+    graph.create(TNode::new(0), &context).await,
+    Ok(vec![T(0, 1)])
+  );
+  let (run_token3, generation3) = graph.node_versions(&TNode::new(0)).unwrap();
+  assert_ne!(run_token2, run_token3);
+  assert_ne!(generation2, generation3);
+}
+
+#[tokio::test]
+async fn get_batch() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+
+  // Results are returned in the requested order.
+  assert_eq!(
+    graph
+      .get_batch(
+        None,
+        &context,
+        vec![TNode::new(1), TNode::new(0), TNode::new(2)]
+      )
+      .await,
+    Ok(vec![
+      vec![T(0, 0), T(1, 0)],
+      vec![T(0, 0)],
+      vec![T(0, 0), T(1, 0), T(2, 0)],
+    ])
+  );
+
+  // A cycle in any one of the requested dependencies fails the batch.
+  let src_id = graph.inner.read().nodes[&TNode::new(0)];
+  assert_eq!(
+    graph
+      .get_batch(Some(src_id), &context, vec![TNode::new(3), TNode::new(2)])
+      .await,
+    Err(TError::Cyclic)
+  );
+  // And the dependencies which were added before the cycle was detected are removed.
+  assert!(!graph.has_edge(&TNode::new(0), &TNode::new(3)));
+  assert!(!graph.has_edge(&TNode::new(0), &TNode::new(2)));
+}
+
+#[test]
+fn walk() {
+  // 0 -> 1 -> 2 -> 3, with a shortcut from 0 -> 2.
+  let graph = graph_with_edges(&[0, 1, 2, 3], &[(0, 1), (1, 2), (2, 3), (0, 2)]);
+
+  assert_eq!(
+    vec![0, 2, 1, 3],
+    graph
+      .walk(vec![TNode::new(0)], Direction::Outgoing, |_| false)
+      .map(|node| node.0)
+      .collect::<Vec<_>>()
+  );
+  // Stopping at a Node excludes it and anything only reachable through it.
+  assert_eq!(
+    vec![0, 1],
+    graph
+      .walk(vec![TNode::new(0)], Direction::Outgoing, |node| node.0 == 2)
+      .map(|node| node.0)
+      .collect::<Vec<_>>()
+  );
+  assert_eq!(
+    vec![3, 2, 0, 1],
+    graph
+      .walk(vec![TNode::new(3)], Direction::Incoming, |_| false)
+      .map(|node| node.0)
+      .collect::<Vec<_>>()
+  );
+}
+
+#[test]
+fn report_cycle_orientation() {
+  let report_cycle = |edges: &[(usize, usize)]| {
+    let graph = graph_with_edges(&[0, 1, 2, 3, 4], edges);
+    let inner = graph.inner.read();
+    let (src, dst) = (inner.nodes[&TNode::new(0)], inner.nodes[&TNode::new(1)]);
+    let cycle = inner.report_cycle(src, dst).unwrap();
+    (
+      cycle.path.iter().map(|e| e.node().0).collect::<Vec<_>>(),
+      cycle.searched,
+    )
+  };
+
+  // An existing path from 1 to 0 means that adding a dependency from 0 to 1 creates a cycle. Extra
+  // dependents of the src cause the path to be discovered by searching forward from the dst...
+  assert_eq!(
+    (vec![0, 1, 2, 0], Direction::Outgoing),
+    report_cycle(&[(1, 2), (2, 0), (3, 0), (4, 0)])
+  );
+  // ...while extra dependencies of the dst cause it to be discovered by searching backward from
+  // the src, but the path has the same orientation.
+  assert_eq!(
+    (vec![0, 1, 2, 0], Direction::Incoming),
+    report_cycle(&[(1, 2), (2, 0), (1, 3), (1, 4)])
+  );
+}
+
+#[test]
+fn count_nodes() {
+  let graph = graph_with_edges(&[0, 1, 2, 3, 4], &[(0, 1), (1, 2)]);
+  assert_eq!(5, graph.count_nodes(|_| true));
+  assert_eq!(3, graph.count_nodes(|node| node.0 % 2 == 0));
+  assert_eq!(0, graph.count_nodes(|node| node.0 > 4));
+}
+
+#[tokio::test]
+async fn drain_and_wait() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone()).with_delays(
+    vec![(TNode::new(0), Duration::from_millis(500))]
+      .into_iter()
+      .collect(),
+  );
+
+  // Start a Node which will run for a while.
+  let running = {
+    let graph = graph.clone();
+    let context = context.clone();
+    tokio::spawn(async move { graph.create(TNode::new(0), &context).await })
+  };
+  sleep(Duration::from_millis(100)).await;
+
+  // A short drain times out, but marks the Graph draining, which prevents new requests.
+  assert_eq!(
+    Err(DrainTimeout { running: 1 }),
+    graph.drain_and_wait(Duration::from_millis(10)).await
+  );
+  assert!(graph.is_draining());
+  assert_eq!(Err(()), graph.mark_draining(true));
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Err(TError::Invalidated)
+  );
+
+  // While a longer drain waits for the running Node to complete.
+  assert_eq!(Ok(()), graph.drain_and_wait(Duration::from_secs(10)).await);
+  assert_eq!(running.await.unwrap(), Ok(vec![T(0, 0)]));
+
+  // And once the Graph is no longer draining, requests succeed again.
+  assert_eq!(Ok(()), graph.mark_draining(false));
+  assert!(!graph.is_draining());
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+}
+
+#[tokio::test]
+async fn running_nodes() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone()).with_delays(
+    vec![(TNode::new(1), Duration::from_millis(500))]
+      .into_iter()
+      .collect(),
+  );
+  assert_eq!(
+    graph.create(TNode::new(0), &context).await,
+    Ok(vec![T(0, 0)])
+  );
+  assert!(graph.running_nodes(&context).is_empty());
+
+  // Start a Node which depends on a Node which will run for a while.
+  let running = {
+    let graph = graph.clone();
+    let context = context.clone();
+    tokio::spawn(async move { graph.create(TNode::new(2), &context).await })
+  };
+  sleep(Duration::from_millis(100)).await;
+  let mut running_nodes = graph.running_nodes(&context);
+  running_nodes.sort_by_key(|node| node.0);
+  assert_eq!(vec![TNode::new(1), TNode::new(2)], running_nodes);
+
+  assert_eq!(running.await.unwrap(), Ok(vec![T(0, 0), T(1, 0), T(2, 0)]));
+  assert!(graph.running_nodes(&context).is_empty());
+}
+
+#[tokio::test]
+async fn default_edge_weights() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  let inner = graph.inner.read();
+  assert_eq!(
+    vec![1.0, 1.0],
+    inner
+      .pg
+      .raw_edges()
+      .iter()
+      .map(|edge| edge.weight.weight)
+      .collect::<Vec<_>>()
+  );
+}
+
+#[tokio::test]
+async fn clear_values_preserving_edges() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  let edges = graph.stats().edges;
+
+  // All values are dirtied, but the edges are retained.
+  graph.clear_values_preserving_edges();
+  assert_eq!(edges, graph.stats().edges);
+  {
+    let inner = graph.inner.read();
+    for entry_id in inner.pg.node_indices() {
+      assert_eq!(None, inner.unsafe_entry_for_id(entry_id).peek(&context));
+    }
+  }
+
+  // And re-requesting the Node cleans all of them without re-running.
+  let context = context.new_run(1);
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(Vec::<TNode>::new(), context.runs());
+  assert_eq!(edges, graph.stats().edges);
+}
+
+#[tokio::test]
+async fn diff_generations_between_snapshots() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  let before = graph.snapshot_generations();
+  assert!(diff_generations(&before, &before).is_empty());
+
+  // Change the value of the middle Node, which changes the upper Node, and add a new Node.
+  graph.invalidate_node(&TNode::new(1));
+  let context = context.new_run(1).with_salt(1);
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 1), T(2, 1)])
+  );
+  let after = graph.snapshot_generations();
+
+  let mut changed = diff_generations(&before, &after);
+  changed.sort_by_key(|node| node.0);
+  assert_eq!(vec![TNode::new(1), TNode::new(2)], changed);
+}
+
+#[tokio::test]
+async fn poll_cancellation_and_observe_latest() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(None, graph.observe_latest(&TNode::new(2)));
+
+  let (_, token1) = graph
+    .poll(TNode::new(2), None, None, &context)
+    .await
+    .unwrap();
+  assert_eq!(Some(token1), graph.observe_latest(&TNode::new(2)));
+
+  // Drop a poll while it is waiting for a change.
+  let request = graph.poll(TNode::new(2), Some(token1), None, &context);
+  match timeout(Duration::from_millis(100), request).await {
+    Err(Elapsed { .. }) => (),
+    e => panic!("Should have timed out, instead got: {:?}", e),
+  }
+
+  // The latest token is unchanged, and can be used to poll again.
+  let token2 = graph.observe_latest(&TNode::new(2)).unwrap();
+  assert_eq!(token1, token2);
+  let request = graph.poll(TNode::new(2), Some(token2), None, &context);
+  match timeout(Duration::from_millis(100), request).await {
+    Err(Elapsed { .. }) => (),
+    e => panic!("Should have timed out, instead got: {:?}", e),
+  }
+
+  // Changing the Node causes the token to change.
+  graph.invalidate_node(&TNode::new(0));
+  let context = context.new_run(1).with_salt(1);
+  let (result, token3) = graph
+    .poll(TNode::new(2), Some(token2), None, &context)
+    .await
+    .unwrap();
+  assert_eq!(result, vec![T(0, 1), T(1, 1), T(2, 1)]);
+  assert_ne!(token2, token3);
+  assert_eq!(Some(token3), graph.observe_latest(&TNode::new(2)));
+}
+
+#[tokio::test]
+async fn priority() {
+  let graph = Arc::new(Graph::new());
+  let context = PContext::new(graph.clone());
+
+  // The prioritized Node is started first, but results are returned in the requested order.
+  assert_eq!(
+    graph
+      .get_batch(None, &context, vec![PNode(1, 0), PNode(2, -1)])
+      .await
+      .map(|items| items.into_iter().map(|item| item.0).collect::<Vec<_>>()),
+    Ok(vec![1, 2])
+  );
+  assert_eq!(vec![PNode(2, -1), PNode(1, 0)], *context.runs.lock());
+
+  // And walks visit it first among its siblings.
+  let graph = Graph::new();
+  graph
+    .preload(
+      vec![],
+      vec![(PNode(0, 0), PNode(1, 0)), (PNode(0, 0), PNode(2, -1))],
+    )
+    .unwrap();
+  assert_eq!(
+    vec![0, 2, 1],
+    graph
+      .walk(vec![PNode(0, 0)], Direction::Outgoing, |_| false)
+      .map(|node| node.0)
+      .collect::<Vec<_>>()
+  );
+}
+
+#[test]
+fn topological_order() {
+  // 0 -> 1 -> 2 -> 3, with a shortcut from 0 -> 2, and an unreachable 4 -> 0.
+  let graph = graph_with_edges(&[0, 1, 2, 3, 4], &[(0, 1), (1, 2), (2, 3), (0, 2), (4, 0)]);
+  let order = |roots: &[usize]| {
+    graph
+      .topological_order(&roots.iter().map(|&id| TNode::new(id)).collect::<Vec<_>>())
+      .map(|nodes| nodes.into_iter().map(|node| node.0).collect::<Vec<_>>())
+      .map_err(|nodes| nodes.into_iter().map(|node| node.0).collect::<Vec<_>>())
+  };
+  assert_eq!(Ok(vec![3, 2, 1, 0]), order(&[0]));
+  assert_eq!(Ok(vec![3, 2]), order(&[2]));
+
+  // A cycle in the reachable subgraph is reported.
+  let graph = graph_with_edges(&[0, 1, 2, 3], &[(0, 1), (1, 2), (2, 1), (2, 3)]);
+  assert_eq!(
+    Err(vec![1, 2]),
+    graph
+      .topological_order(&[TNode::new(0)])
+      .map_err(|nodes| nodes.into_iter().map(|node| node.0).collect::<Vec<_>>())
+      .map(|_| ())
+  );
+}
+
+#[tokio::test]
+async fn estimated_size_bytes() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  {
+    let mut inner = graph.inner.write();
+    for id in 0..4 {
+      inner.ensure_entry(TNode::new(id));
+    }
+  }
+  let empty_size = graph.estimated_size_bytes(&context);
+  assert!(empty_size > 0);
+
+  // Computing values for the Nodes increases the size in proportion to the size of the values.
+  assert_eq!(
+    graph.create(TNode::new(3), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0), T(3, 0)])
+  );
+  assert_eq!(
+    4 * std::mem::size_of::<Vec<T>>() + (1 + 2 + 3 + 4) * std::mem::size_of::<T>(),
+    graph.estimated_size_bytes(&context) - empty_size
+  );
+}
+
+#[tokio::test]
+async fn gc_unreachable() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(graph.len(), 3);
+  assert_eq!(graph.edge_count(), 2);
+
+  // Only the root's dependent is unreachable.
+  assert_eq!(graph.gc_unreachable(&[TNode::new(1)]), 1);
+  assert_eq!(graph.len(), 2);
+  assert_eq!(graph.edge_count(), 1);
+  assert_eq!(graph.verify_invariants(), Ok(()));
+  assert_eq!(graph.dependents(&TNode::new(2)), None);
+  assert_eq!(graph.dependents(&TNode::new(0)), Some(vec![TNode::new(1)]));
+  assert_eq!(graph.gc_unreachable(&[TNode::new(1)]), 0);
+
+  // The remaining Nodes keep their values, and so do not re-run.
+  let context = context.new_run(1);
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  assert_eq!(context.runs(), vec![]);
+
+  // Without any live roots, everything is removed.
+  assert_eq!(graph.gc_unreachable(&[]), 2);
+  assert_eq!(graph.len(), 0);
+  assert_eq!(graph.edge_count(), 0);
+}
+
+#[tokio::test]
+async fn gc_unreachable_while_retrying() {
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    invalidation_delay: Duration::from_millis(200),
+    ..GraphOptions::default()
+  }));
+  let context = TContext::new(graph.clone())
+    .with_dependencies(
+      vec![(TNode::new(10), vec![]), (TNode::new(20), vec![])]
+        .into_iter()
+        .collect(),
+    )
+    .with_invalidations(vec![(TNode::new(20), 1)].into_iter().collect());
+  assert_eq!(
+    graph.create(TNode::new(10), &context).await,
+    Ok(vec![T(10, 0)])
+  );
+
+  // While a request for a Node is waiting to retry it, its EntryId must remain valid, and so the
+  // Graph is not collected (which would renumber the Node).
+  let request = {
+    let graph = graph.clone();
+    let context = context.clone();
+    tokio::spawn(async move { graph.create(TNode::new(20), &context).await })
+  };
+  sleep(Duration::from_millis(100)).await;
+  assert_eq!(graph.stats().running, 0);
+  assert_eq!(graph.gc_unreachable(&[TNode::new(20)]), 0);
+  assert_eq!(request.await.unwrap(), Ok(vec![T(20, 0)]));
+  assert_eq!(
+    context.runs(),
+    vec![TNode::new(10), TNode::new(20), TNode::new(20)]
+  );
+
+  // But once the request has completed, it is.
+  assert_eq!(graph.gc_unreachable(&[TNode::new(20)]), 1);
+  assert_eq!(graph.verify_invariants(), Ok(()));
+  assert_eq!(
+    graph.create(TNode::new(20), &context).await,
+    Ok(vec![T(20, 0)])
+  );
+}
+
+#[test]
+fn would_create_cycle() {
+  let graph = graph_with_edges(&[0, 1, 2], &[(0, 1), (1, 2)]);
+
+  assert!(graph.would_create_cycle(&TNode::new(2), &TNode::new(0)));
+  assert!(graph.would_create_cycle(&TNode::new(1), &TNode::new(1)));
+  assert!(!graph.would_create_cycle(&TNode::new(0), &TNode::new(2)));
+  assert!(!graph.would_create_cycle(&TNode::new(2), &TNode::new(3)));
+
+  // The graph is unchanged.
+  assert_eq!(graph.len(), 3);
+  assert_eq!(graph.find_cycles(), Vec::<Vec<TNode>>::new());
+}
+
+#[tokio::test]
+async fn invalidate_all_roots() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  // Only the leaf Node is an invalidation root for TNodes.
+  assert_eq!(
+    graph.invalidate_all_roots(),
+    InvalidationResult {
+      cleared: 1,
+      dirtied: 2,
+      skipped: 0,
+    }
+  );
+
+  // Confirm that the root re-runs, and its dependents are cleaned without re-running.
+  let context = context.new_run(1);
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(context.runs(), vec![TNode::new(0)]);
+}
+
+#[tokio::test]
+async fn last_run_duration() {
+  let graph = Arc::new(Graph::new());
+  let delay = Duration::from_millis(100);
+  let context = {
+    let mut delays = HashMap::new();
+    delays.insert(TNode::new(1), delay);
+    TContext::new(graph.clone()).with_delays(delays)
+  };
+
+  assert_eq!(graph.last_run_duration(&TNode::new(1)), None);
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  // The slow Node (and its dependent, which waited for it) took at least the delay.
+  for id in &[1, 2] {
+    let duration = graph.last_run_duration(&TNode::new(*id)).unwrap();
+    assert!(duration >= delay, "{} took {:?}", id, duration);
+  }
+  assert!(graph.last_run_duration(&TNode::new(0)).is_some());
+  assert_eq!(graph.last_run_duration(&TNode::new(3)), None);
+
+  // The recorded durations may be used for the critical path.
+  let (total, path) = graph.critical_path_by_last_run_duration(&[TNode::new(2)]);
+  assert!(total >= delay * 2, "{:?}", total);
+  assert_eq!(
+    path
+      .iter()
+      .map(|entry| entry.node().clone())
+      .collect::<Vec<_>>(),
+    vec![TNode::new(2), TNode::new(1), TNode::new(0)]
+  );
+}
+
+#[tokio::test]
+async fn completion_observer() {
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    max_retries: Some(0),
+    ..GraphOptions::default()
+  }));
+  let completions = Arc::new(Mutex::new(Vec::new()));
+  let completions2 = completions.clone();
+  graph.set_completion_observer(Arc::new(move |node: &TNode, succeeded| {
+    completions2.lock().push((node.clone(), succeeded))
+  }));
+  // The observer is called after a Node's waiters have been notified, so callbacks might race
+  // one another, and the test's observation of the result: wait for them, and then sort them.
+  let await_completions = |expected: usize| {
+    let completions = completions.clone();
+    async move {
+      let deadline = Instant::now() + Duration::from_secs(5);
+      while completions.lock().len() < expected && Instant::now() < deadline {
+        sleep(Duration::from_millis(10)).await;
+      }
+      let mut completions = completions.lock().clone();
+      completions.sort_by_key(|(node, succeeded)| (node.0, *succeeded));
+      completions
+    }
+  };
+
+  // A Node which is invalidated while running fails, but its dependency succeeds.
+  let context =
+    TContext::new(graph.clone()).with_invalidations(vec![(TNode::new(1), 1)].into_iter().collect());
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Err(TError::Invalidated)
+  );
+  assert_eq!(
+    await_completions(2).await,
+    vec![(TNode::new(0), true), (TNode::new(1), false)]
+  );
+
+  // When it is requested again, only the failed Node completes.
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  assert_eq!(
+    await_completions(3).await,
+    vec![
+      (TNode::new(0), true),
+      (TNode::new(1), false),
+      (TNode::new(1), true)
+    ]
+  );
+}
+
+#[tokio::test]
+async fn invalidate_from_roots_coalesced() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  // Two invalidations within the window are applied together: the Node cleared by the second is
+  // counted once, rather than being both dirtied by the first and then cleared by the second.
+  let window = Duration::from_millis(100);
+  let (first, second) = future::join(
+    graph.invalidate_from_roots_coalesced(window, |&TNode(n, _)| n == 0),
+    graph.invalidate_from_roots_coalesced(window, |&TNode(n, _)| n == 1),
+  )
+  .await;
+  let expected = InvalidationResult {
+    cleared: 2,
+    dirtied: 1,
+    skipped: 0,
+  };
+  assert_eq!(first, expected);
+  assert_eq!(second, expected);
+
+  // Both cleared Nodes re-run, and their dependent is cleaned.
+  let context = context.new_run(1);
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(context.runs(), vec![TNode::new(1), TNode::new(0)]);
+
+  // A later invalidation starts a new batch.
+  assert_eq!(
+    graph
+      .invalidate_from_roots_coalesced(window, |&TNode(n, _)| n == 2)
+      .await,
+    InvalidationResult {
+      cleared: 1,
+      dirtied: 0,
+      skipped: 0,
+    }
+  );
+
+  // If every caller of a batch is canceled, the batch is applied rather than left pending.
+  assert!(timeout(
+    Duration::from_millis(10),
+    graph.invalidate_from_roots_coalesced(window, |&TNode(n, _)| n == 0)
+  )
+  .await
+  .is_err());
+  assert!(graph.coalesced_invalidation.lock().is_none());
+  assert_eq!(graph.invalidation_metrics().invalidations, 3);
+
+  // An observer may itself invalidate (here, by joining a new batch and then being canceled),
+  // because a batch is applied without holding the lock on the pending batch.
+  let observer_graph = Arc::downgrade(&graph);
+  let reentered = Arc::new(AtomicBool::new(false));
+  graph.set_invalidation_observer(Arc::new(move |_: &TNode, _: InvalidationKind| {
+    if !reentered.swap(true, Ordering::SeqCst) {
+      let graph = observer_graph.upgrade().unwrap();
+      let _ = graph
+        .invalidate_from_roots_coalesced(window, |&TNode(n, _)| n == 1)
+        .now_or_never();
+    }
+  }));
+  graph
+    .invalidate_from_roots_coalesced(Duration::from_millis(0), |&TNode(n, _)| n == 2)
+    .await;
+  assert_eq!(graph.invalidation_metrics().invalidations, 5);
+}
+
+#[test]
+fn longest_uncacheable_chain() {
+  // Uncacheable Nodes are those with odd ids.
+  let tnode = |id: usize| TNode(id, id % 2 == 0);
+  let graph = Graph::new();
+  {
+    let mut inner = graph.inner.write();
+    let edges = [
+      // A chain of three uncacheable Nodes beneath a cacheable Node.
+      (0, 1),
+      (1, 3),
+      (3, 5),
+      // A chain which is broken by a cacheable Node.
+      (1, 2),
+      (2, 7),
+      // A cycle.
+      (9, 11),
+      (11, 9),
+    ];
+    for &(src, dst) in &edges {
+      let src = inner.ensure_entry(tnode(src));
+      let dst = inner.ensure_entry(tnode(dst));
+      inner.pg.add_edge(
+        src,
+        dst,
+        DependencyEdge {
+          weight: 1.0,
+          generation: Generation::initial(),
+        },
+      );
+    }
+  }
+  assert_eq!(
+    graph.longest_uncacheable_chain(),
+    vec![tnode(1), tnode(3), tnode(5)]
+  );
+
+  // A graph without uncacheable Nodes has no chain.
+  let graph = graph_with_edges(&[0, 1, 2], &[(0, 1), (1, 2)]);
+  assert_eq!(graph.longest_uncacheable_chain(), Vec::<TNode>::new());
+}
+
+#[tokio::test]
+async fn is_clean() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(graph.is_clean(&TNode::new(0), &context), None);
+
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  for id in 0..3 {
+    assert_eq!(graph.is_clean(&TNode::new(id), &context), Some(true));
+  }
+
+  // Both cleared and dirtied Nodes are not clean.
+  graph.invalidate_from_roots(|&TNode(n, _)| n == 1);
+  assert_eq!(graph.is_clean(&TNode::new(0), &context), Some(true));
+  assert_eq!(graph.is_clean(&TNode::new(1), &context), Some(false));
+  assert_eq!(graph.is_clean(&TNode::new(2), &context), Some(false));
+}
+
+#[tokio::test]
+async fn prune_stale_edges() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+
+  // The dependency has not changed, so the edge is preserved.
+  graph.prune_stale_edges(&TNode::new(1));
+  assert_eq!(
+    graph.dependencies(&TNode::new(1), &context),
+    Some(vec![TNode::new(0)])
+  );
+
+  // Invalidate and re-request the dependency with a new value: the edge to it is now stale.
+  graph.invalidate_from_roots(|&TNode(n, _)| n == 0);
+  let context = context.new_run(1).with_salt(1);
+  assert_eq!(
+    graph.create(TNode::new(0), &context).await,
+    Ok(vec![T(0, 1)])
+  );
+  graph.prune_stale_edges(&TNode::new(1));
+  assert_eq!(graph.dependencies(&TNode::new(1), &context), Some(vec![]));
+
+  // And so the dependent re-runs, and depends on the new value.
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 1), T(1, 1)])
+  );
+  assert_eq!(context.runs(), vec![TNode::new(0), TNode::new(1)]);
+  assert_eq!(
+    graph.dependencies(&TNode::new(1), &context),
+    Some(vec![TNode::new(0)])
+  );
+
+  // The edges of a running Node are not pruned, even if a dependency has completed (and so
+  // advanced its Generation) since the edge to it was added.
+  let graph = Arc::new(Graph::new());
+  let context = {
+    let mut edges = HashMap::new();
+    edges.insert(TNode::new(2), vec![TNode::new(0), TNode::new(1)]);
+    edges.insert(TNode::new(1), vec![]);
+    edges.insert(TNode::new(0), vec![]);
+    let mut delays = HashMap::new();
+    delays.insert(TNode::new(1), Duration::from_millis(200));
+    TContext::new(graph.clone())
+      .with_dependencies(edges)
+      .with_delays(delays)
+  };
+  let running = {
+    let graph = graph.clone();
+    let context = context.clone();
+    tokio::spawn(async move { graph.create(TNode::new(2), &context).await })
+  };
+  sleep(Duration::from_millis(100)).await;
+  graph.prune_stale_edges(&TNode::new(2));
+  assert_eq!(running.await.unwrap(), Ok(vec![T(0, 0), T(2, 0)]));
+  graph.prune_stale_edges(&TNode::new(2));
+  let mut dependencies = graph.dependencies(&TNode::new(2), &context).unwrap();
+  dependencies.sort_by_key(|node| node.0);
+  assert_eq!(dependencies, vec![TNode::new(0), TNode::new(1)]);
+}
+
+#[tokio::test]
+async fn prune_stale_edges_reordered() {
+  let graph = Arc::new(Graph::new());
+  let context = {
+    let mut edges = HashMap::new();
+    edges.insert(TNode::new(3), vec![TNode::new(0), TNode::new(1)]);
+    edges.insert(TNode::new(1), vec![]);
+    edges.insert(TNode::new(0), vec![]);
+    TContext::new(graph.clone()).with_dependencies(edges)
+  };
+
+  // Advance the Generation of 0 past that of 1 before 3 consumes them both, so that the
+  // Generations that 3 consumed differ.
+  graph.create(TNode::new(0), &context).await.unwrap();
+  graph.invalidate_from_roots(|&TNode(n, _)| n == 0);
+  let context = context.new_run(1).with_salt(1);
+  graph.create(TNode::new(0), &context).await.unwrap();
+  graph.create(TNode::new(3), &context).await.unwrap();
+
+  // Reorder the edges of 3 by removing and re-adding the last of them, and then add and compact
+  // away a duplicate edge.
+  let before = graph.dependencies(&TNode::new(3), &context).unwrap();
+  {
+    let mut inner = graph.inner.write();
+    let src = inner.nodes[&TNode::new(3)];
+    let last = inner
+      .pg
+      .edges_directed(src, Direction::Outgoing)
+      .last()
+      .map(|edge| (edge.id(), edge.target(), *edge.weight()))
+      .unwrap();
+    inner.pg.remove_edge(last.0);
+    inner.pg.add_edge(src, last.1, last.2);
+    inner.pg.add_edge(src, last.1, last.2);
+  }
+  assert_eq!(graph.compact_edges(), 1);
+  let mut after = graph.dependencies(&TNode::new(3), &context).unwrap();
+  after.reverse();
+  assert_eq!(before, after);
+
+  // Change the value of 1: only the edge to 1 is stale.
+  graph.invalidate_from_roots(|&TNode(n, _)| n == 1);
+  let context = context.new_run(2).with_salt(2);
+  graph.create(TNode::new(1), &context).await.unwrap();
+  graph.prune_stale_edges(&TNode::new(3));
+  assert_eq!(
+    graph.dependencies(&TNode::new(3), &context),
+    Some(vec![TNode::new(0)])
+  );
+}
+
+#[test]
+fn visualize_to_writer_truncated() {
+  let graph = Arc::new(graph_with_edges(&[0, 1, 2], &[(0, 1), (1, 2)]));
+  let context = TContext::new(graph.clone());
+
+  let render = |max_nodes| {
+    let mut rendered = Vec::new();
+    graph
+      .visualize_to_writer(
+        TVisualizer,
+        &[TNode::new(0)],
+        max_nodes,
+        &context,
+        &mut rendered,
+      )
+      .unwrap();
+    String::from_utf8(rendered).unwrap()
+  };
+
+  let expected = [
+    "digraph plans {",
+    "  node[colorscheme=set19];",
+    "  concentrate=true;",
+    "  rankdir=TB;",
+    "  \"TNode(0, true) == <None>\" [style=filled, fillcolor=1];",
+    "    \"TNode(0, true) == <None>\" -> \"TNode(1, true) == <None>\"",
+    "  \"TNode(1, true) == <None>\" [style=filled, fillcolor=1];",
+    "    \"TNode(1, true) == <None>\" -> \"TNode(2, true) == <None>\"",
+    "  \"... (truncated)\" [style=dashed];",
+    "}",
+  ]
+  .iter()
+  .map(|line| format!("{}\n", line))
+  .collect::<String>();
+  assert_eq!(expected, render(Some(2)));
+  // Truncation is deterministic.
+  assert_eq!(render(Some(2)), render(Some(2)));
+
+  // A limit which is not reached does not truncate.
+  assert_eq!(render(None), render(Some(3)));
+  assert!(!render(Some(3)).contains("truncated"));
+}
+
+#[test]
+fn leaves() {
+  let graph = graph_with_edges(
+    &[0, 1, 2, 3, 4, 5],
+    &[(0, 1), (0, 2), (1, 3), (2, 3), (2, 4), (5, 4)],
+  );
+
+  // Leaves which are reachable via multiple paths are reported once.
+  assert_eq!(
+    graph.leaves(&[TNode::new(0)]),
+    vec![TNode::new(3), TNode::new(4)]
+  );
+  assert_eq!(graph.leaves(&[TNode::new(5)]), vec![TNode::new(4)]);
+  assert_eq!(graph.leaves(&[TNode::new(3)]), vec![TNode::new(3)]);
+  assert_eq!(graph.leaves(&[TNode::new(6)]), vec![]);
+}
+
+#[test]
+fn sources() {
+  let graph = graph_with_edges(
+    &[3, 0, 1, 2, 4, 5, 6],
+    &[(0, 1), (0, 2), (1, 3), (2, 3), (2, 4), (5, 4)],
+  );
+
+  // Sources are ordered by when they were added, and include Nodes without any edges.
+  assert_eq!(
+    graph.sources(),
+    vec![TNode::new(0), TNode::new(5), TNode::new(6)]
+  );
+  assert_eq!(Graph::<TNode>::new().sources(), vec![]);
+}
+
+#[tokio::test]
+async fn clone_topology() {
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    max_nodes: Some(4),
+    ..GraphOptions::default()
+  }));
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  let clone = Arc::new(graph.clone_topology());
+  assert_eq!(clone.len(), 3);
+  assert_eq!(
+    clone.dependencies(&TNode::new(2), &context),
+    Some(vec![TNode::new(1)])
+  );
+  assert_eq!(
+    clone.stats(),
+    GraphStats {
+      nodes: 3,
+      edges: 2,
+      not_started: 3,
+      ..GraphStats::default()
+    }
+  );
+
+  // Mutating the clone does not affect the original, but the clone has the original's options.
+  clone.inner.write().ensure_entry(TNode::new(3));
+  assert_eq!(clone.len(), 4);
+  assert_eq!(clone.utilization(), Some(1.0));
+  assert_eq!(graph.len(), 3);
+  assert_eq!(graph.is_clean(&TNode::new(2), &context), Some(true));
+
+  // Values are not copied, so Nodes run when requested from the clone.
+  let clone_context = TContext::new(clone.clone());
+  assert_eq!(
+    clone.create(TNode::new(2), &clone_context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(
+    clone_context.runs(),
+    vec![TNode::new(2), TNode::new(1), TNode::new(0)]
+  );
+}
+
+#[tokio::test]
+async fn wait_for_completion() {
+  let graph = Arc::new(Graph::new());
+  let context = {
+    let mut delays = HashMap::new();
+    delays.insert(TNode::new(0), Duration::from_millis(100));
+    TContext::new(graph.clone()).with_delays(delays)
+  };
+  assert_eq!(
+    graph.wait_for_completion(&TNode::new(1), &context).await,
+    None
+  );
+
+  // Waiting for a running Node returns its value once it completes.
+  let running = {
+    let graph = graph.clone();
+    let context = context.clone();
+    tokio::spawn(async move { graph.create(TNode::new(1), &context).await })
+  };
+  sleep(Duration::from_millis(10)).await;
+  assert_eq!(
+    graph.wait_for_completion(&TNode::new(1), &context).await,
+    Some(Ok(vec![T(0, 0), T(1, 0)]))
+  );
+  assert_eq!(running.await.unwrap(), Ok(vec![T(0, 0), T(1, 0)]));
+
+  // As does waiting for a completed Node.
+  assert_eq!(
+    graph.wait_for_completion(&TNode::new(0), &context).await,
+    Some(Ok(vec![T(0, 0)]))
+  );
+
+  // But waiting for a Node which has not been started does not start it.
+  graph.inner.write().ensure_entry(TNode::new(5));
+  let waiting = timeout(
+    Duration::from_millis(100),
+    graph.wait_for_completion(&TNode::new(5), &context),
+  );
+  assert!(waiting.await.is_err());
+  assert_eq!(context.runs(), vec![TNode::new(1), TNode::new(0)]);
+
+  // Until some other caller starts it.
+  let waiting = {
+    let graph = graph.clone();
+    let context = context.clone();
+    tokio::spawn(async move { graph.wait_for_completion(&TNode::new(5), &context).await })
+  };
+  sleep(Duration::from_millis(10)).await;
+  assert_eq!(
+    graph.create(TNode::new(5), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0), T(3, 0), T(4, 0), T(5, 0)])
+  );
+  assert_eq!(
+    waiting.await.unwrap(),
+    Some(Ok(vec![
+      T(0, 0),
+      T(1, 0),
+      T(2, 0),
+      T(3, 0),
+      T(4, 0),
+      T(5, 0)
+    ]))
+  );
+}
+
+#[tokio::test]
+async fn mark_subtree_draining() {
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    max_retries: Some(0),
+    ..GraphOptions::default()
+  }));
+  let context = TContext::new(graph.clone()).with_dependencies(
+    vec![
+      (TNode::new(2), vec![TNode::new(1)]),
+      (TNode::new(1), vec![]),
+      (TNode::new(12), vec![TNode::new(11)]),
+      (TNode::new(11), vec![]),
+    ]
+    .into_iter()
+    .collect(),
+  );
+
+  // While a subtree is draining, dependencies may not be requested from within it.
+  graph.mark_subtree_draining(&TNode::new(2), true);
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Err(TError::Invalidated)
+  );
+  assert_eq!(graph.dependencies(&TNode::new(2), &context), Some(vec![]));
+
+  // But the rest of the Graph is unaffected.
+  assert_eq!(
+    graph.create(TNode::new(12), &context).await,
+    Ok(vec![T(11, 0), T(12, 0)])
+  );
+
+  // Once the subtree is no longer draining, its dependencies may be requested.
+  graph.mark_subtree_draining(&TNode::new(2), false);
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(1, 0), T(2, 0)])
+  );
+}
+
+#[test]
+fn deterministic_path_tie_breaking() {
+  // A diamond, in which both paths through the middle are equally long. The graph is constructed
+  // with the edges in either order, which would affect which path Bellman-Ford chooses.
+  let edges = [(0, 1), (0, 2), (1, 3), (2, 3)];
+  let mut reversed_edges = edges;
+  reversed_edges.reverse();
+  let duration = |_: &Entry<TNode>| Duration::from_secs(1);
+
+  for edges in &[edges, reversed_edges] {
+    let graph = graph_with_edges(&[3, 2, 1, 0], edges);
+    for _ in 0..10 {
+      let (total, path) = graph.critical_path(&[TNode::new(0)], &duration);
+      assert_eq!(Duration::from_secs(3), total);
+      assert_eq!(
+        vec![0, 1, 3],
+        path.iter().map(|entry| entry.node().0).collect::<Vec<_>>()
+      );
+
+      // As is the path reported for a cycle.
+      let inner = graph.inner.read();
+      let cycle = inner
+        .report_cycle(inner.nodes[&TNode::new(3)], inner.nodes[&TNode::new(0)])
+        .unwrap();
+      assert_eq!(
+        vec![3, 0, 1, 3],
+        cycle
+          .path
+          .iter()
+          .map(|entry| entry.node().0)
+          .collect::<Vec<_>>()
+      );
+    }
+  }
+}
+
+#[tokio::test]
+async fn run_count() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(graph.run_count(&TNode::new(0)), None);
+
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  for id in 0..3 {
+    assert_eq!(graph.run_count(&TNode::new(id)), Some(1));
+  }
+
+  // Requesting clean Nodes does not start them.
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(graph.run_count(&TNode::new(2)), Some(1));
+
+  // But invalidated Nodes are started again, whether they re-run or are cleaned.
+  graph.invalidate_from_roots(|&TNode(n, _)| n == 1);
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(
+    (0..3)
+      .map(|id| graph.run_count(&TNode::new(id)).unwrap())
+      .collect::<Vec<_>>(),
+    vec![1, 2, 2]
+  );
+}
+
+#[tokio::test]
+async fn preload() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.preload(
+      vec![TNode::new(0), TNode::new(3)],
+      vec![
+        (TNode::new(2), TNode::new(1)),
+        (TNode::new(1), TNode::new(0))
+      ],
+    ),
+    Ok(())
+  );
+  assert_eq!(graph.len(), 4);
+  assert_eq!(
+    graph.dependencies(&TNode::new(2), &context),
+    Some(vec![TNode::new(1)])
+  );
+  assert_eq!(graph.stats().not_started, 4);
+
+  // An edge which introduces a cycle leaves the Graph unmodified.
+  let before = graph.stats();
+  assert_eq!(
+    graph.preload(
+      vec![TNode::new(4)],
+      vec![
+        (TNode::new(5), TNode::new(2)),
+        (TNode::new(0), TNode::new(2))
+      ],
+    ),
+    Err(TError::Cyclic)
+  );
+  assert_eq!(
+    graph.inner.write().preload(
+      vec![TNode::new(4)],
+      vec![
+        (TNode::new(5), TNode::new(2)),
+        (TNode::new(0), TNode::new(2))
+      ],
+    ),
+    Err(vec![
+      TNode::new(0),
+      TNode::new(2),
+      TNode::new(1),
+      TNode::new(0)
+    ])
+  );
+  assert_eq!(graph.stats(), before);
+  assert_eq!(graph.dependents(&TNode::new(2)), Some(vec![]));
+  assert_eq!(graph.dependents(&TNode::new(5)), None);
+
+  // The preloaded Nodes can be requested as usual.
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+}
+
+#[tokio::test]
+async fn iter_nodes_ordered() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  graph.inner.write().ensure_entry(TNode::new(5));
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  // Nodes are ordered by when they were added.
+  assert_eq!(
+    graph.iter_nodes_ordered(),
+    vec![TNode::new(5), TNode::new(2), TNode::new(1), TNode::new(0)]
+  );
+
+  // And the order is preserved when unreachable entries are removed.
+  graph.gc_unreachable(&[TNode::new(2)]);
+  assert_eq!(
+    graph.iter_nodes_ordered(),
+    vec![TNode::new(2), TNode::new(1), TNode::new(0)]
+  );
+}
+
+#[tokio::test]
+async fn cancel_node() {
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    max_retries: Some(0),
+    ..GraphOptions::default()
+  }));
+  let context = {
+    let mut delays = HashMap::new();
+    delays.insert(TNode::new(0), Duration::from_millis(2000));
+    TContext::new(graph.clone()).with_delays(delays)
+  };
+  assert!(!graph.cancel_node(&TNode::new(2)));
+
+  let running = {
+    let graph = graph.clone();
+    let context = context.clone();
+    tokio::spawn(async move { graph.create(TNode::new(2), &context).await })
+  };
+  sleep(Duration::from_millis(100)).await;
+  let mut events = Box::pin(graph.subscribe());
+
+  // Canceling the running Node fails its requestor, and also cancels its dependencies.
+  assert!(graph.cancel_node(&TNode::new(2)));
+  assert_eq!(
+    events.next().await,
+    Some(GraphEvent::NodeCanceled(TNode::new(2)))
+  );
+  assert_eq!(running.await.unwrap(), Err(TError::Invalidated));
+  sleep(Duration::from_millis(100)).await;
+  assert_eq!(graph.stats().running, 0);
+  assert_eq!(
+    vec![TNode::new(2), TNode::new(1), TNode::new(0)],
+    context.aborts()
+  );
+  assert!(!graph.cancel_node(&TNode::new(2)));
+}
+
+#[test]
+fn node_height() {
+  // A diamond with one long side.
+  let graph = graph_with_edges(&[0, 1, 2, 3, 4], &[(0, 1), (0, 2), (1, 4), (2, 3), (3, 4)]);
+
+  assert_eq!(graph.node_height(&TNode::new(4)), Some(0));
+  assert_eq!(graph.node_height(&TNode::new(3)), Some(1));
+  assert_eq!(graph.node_height(&TNode::new(1)), Some(1));
+  assert_eq!(graph.node_height(&TNode::new(0)), Some(3));
+  assert_eq!(graph.node_height(&TNode::new(5)), None);
+}
+
+#[tokio::test]
+async fn invalidate_overlapping_skips_dirty() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+
+  // Create four nodes.
+  assert_eq!(
+    graph.create(TNode::new(3), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0), T(3, 0)])
+  );
+
+  // Clear the lowest Node, which dirties all of the nodes above it.
+  assert_eq!(
+    graph.invalidate_from_roots(|&TNode(n, _)| n == 0),
+    InvalidationResult {
+      cleared: 1,
+      dirtied: 3,
+      skipped: 0,
+    }
+  );
+
+  // Clearing an overlapping subtree should skip the dependents which are already dirty.
+  assert_eq!(
+    graph.invalidate_from_roots(|&TNode(n, _)| n == 1),
+    InvalidationResult {
+      cleared: 1,
+      dirtied: 0,
+      skipped: 2,
+    }
+  );
+
+  // And the graph should still recompute correctly.
+  assert_eq!(
+    graph.create(TNode::new(3), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0), T(3, 0)])
+  );
+}
+
+#[tokio::test]
+async fn failed_nodes() {
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    max_retries: Some(0),
+    ..GraphOptions::default()
+  }));
+  let context =
+    TContext::new(graph.clone()).with_invalidations(vec![(TNode::new(3), 1)].into_iter().collect());
+
+  // Successfully create two nodes, and then fail to create a dependent of them.
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  assert_eq!(
+    graph.create(TNode::new(3), &context).await,
+    Err(TError::Invalidated)
+  );
+  assert_eq!(graph.failed_nodes(&context), vec![TNode::new(3)]);
+
+  // Once the failed Node completes successfully, it is no longer reported.
+  assert_eq!(
+    graph.create(TNode::new(3), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0), T(3, 0)])
+  );
+  assert_eq!(graph.failed_nodes(&context), vec![]);
+}
+
+#[test]
+fn visualize_to_writer_does_not_hold_lock() {
+  let graph = Arc::new(graph_with_edges(&[0, 1], &[(0, 1)]));
+  let context = TContext::new(graph.clone());
+
+  // A Write which confirms that another thread can access the Graph while it is being written to.
+  struct ConcurrentLen(Arc<Graph<TNode>>, Vec<usize>);
+  impl io::Write for ConcurrentLen {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      let (sender, receiver) = mpsc::channel();
+      let graph = self.0.clone();
+      thread::spawn(move || sender.send(graph.len()).unwrap());
+      self.1.push(
+        receiver
+          .recv_timeout(Duration::from_secs(10))
+          .expect("The Graph should not be locked while writing."),
+      );
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  let mut writer = ConcurrentLen(graph.clone(), Vec::new());
+  graph
+    .visualize_to_writer(TVisualizer, &[TNode::new(0)], None, &context, &mut writer)
+    .unwrap();
+  assert!(!writer.1.is_empty());
+  assert!(writer.1.iter().all(|&len| len == 2));
+}
+
+#[tokio::test]
+async fn peek() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+
+  // A Node which has not been requested has no value.
+  assert_eq!(graph.peek(&TNode::new(1), &context), None);
+
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  assert_eq!(
+    graph.peek(&TNode::new(1), &context),
+    Some(vec![T(0, 0), T(1, 0)])
+  );
+
+  // Once dirtied, the Node has no value, and peeking does not cause it to be cleaned.
+  graph.invalidate_from_roots(|&TNode(n, _)| n == 0);
+  assert_eq!(graph.peek(&TNode::new(1), &context), None);
+  assert_eq!(graph.peek(&TNode::new(0), &context), None);
+  assert_eq!(context.runs(), vec![TNode::new(1), TNode::new(0)]);
+}
+
+#[tokio::test]
+async fn custom_hasher() {
+  // A BuildHasher which counts the Hashers that it has built.
+  #[derive(Clone, Default)]
+  struct CountingHasher(Arc<Mutex<usize>>);
+  impl BuildHasher for CountingHasher {
+    type Hasher = DefaultHasher;
+
+    fn build_hasher(&self) -> DefaultHasher {
+      *self.0.lock() += 1;
+      DefaultHasher::new()
+    }
+  }
+
+  let counting_hasher = CountingHasher::default();
+  for hasher in vec![NodeHasher::new(counting_hasher.clone()), NodeHasher::fnv()] {
+    let graph = Arc::new(Graph::new_with_options(GraphOptions {
+      hasher,
+      ..GraphOptions::default()
+    }));
+    let context = TContext::new(graph.clone());
+
+    assert_eq!(
+      graph.create(TNode::new(2), &context).await,
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+    assert_eq!(
+      graph.invalidate_from_roots(|&TNode(n, _)| n == 1),
+      InvalidationResult {
+        cleared: 1,
+        dirtied: 1,
+        skipped: 0,
+      }
+    );
+    assert_eq!(
+      graph.create(TNode::new(2), &context).await,
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+    assert_eq!(graph.len(), 3);
+    assert_eq!(graph.verify_invariants(), Ok(()));
+  }
+  assert!(*counting_hasher.0.lock() > 0);
+}
+
+#[tokio::test]
+async fn dirty_reason() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(graph.dirty_reason(&TNode::new(1), &context), None);
+
+  // Clear the lowest Node, which dirties the nodes above it: but until it re-runs, no dependency
+  // has changed.
+  graph.invalidate_from_roots(|&TNode(n, _)| n == 0);
+  assert_eq!(graph.dirty_reason(&TNode::new(1), &context), Some(vec![]));
+
+  // Re-run the lowest Node with a different value, which should be reported as the reason for the
+  // middle Node (but not the upper Node) being dirty.
+  let context = context.new_run(1).with_salt(1);
+  assert_eq!(
+    graph.create(TNode::new(0), &context).await,
+    Ok(vec![T(0, 1)])
+  );
+  assert_eq!(graph.dirty_reason(&TNode::new(0), &context), None);
+  assert_eq!(
+    graph.dirty_reason(&TNode::new(1), &context),
+    Some(vec![TNode::new(0)])
+  );
+  assert_eq!(graph.dirty_reason(&TNode::new(2), &context), Some(vec![]));
+}
+
+#[tokio::test]
+async fn try_get() {
+  let graph = Arc::new(Graph::new());
+  let context = {
+    let mut delays = HashMap::new();
+    delays.insert(TNode::new(0), Duration::from_millis(100));
+    TContext::new(graph.clone()).with_delays(delays)
+  };
+  assert!(graph.try_get(&TNode::new(1), &context).is_none());
+
+  // A running Node can be attached to.
+  let running = {
+    let graph = graph.clone();
+    let context = context.clone();
+    tokio::spawn(async move { graph.create(TNode::new(1), &context).await })
+  };
+  sleep(Duration::from_millis(10)).await;
+  let attached = graph
+    .try_get(&TNode::new(1), &context)
+    .expect("A running Node should be attachable.");
+  assert_eq!(attached.await, Ok(vec![T(0, 0), T(1, 0)]));
+  assert_eq!(running.await.unwrap(), Ok(vec![T(0, 0), T(1, 0)]));
+
+  // As can a completed Node.
+  assert_eq!(
+    graph.try_get(&TNode::new(0), &context).unwrap().await,
+    Ok(vec![T(0, 0)])
+  );
+
+  // But a Node which has not been started, or which is dirty, is not started.
+  graph.inner.write().ensure_entry(TNode::new(5));
+  assert!(graph.try_get(&TNode::new(5), &context).is_none());
+  graph.invalidate_from_roots(|&TNode(n, _)| n == 0);
+  assert!(graph.try_get(&TNode::new(1), &context).is_none());
+  assert_eq!(context.runs(), vec![TNode::new(1), TNode::new(0)]);
+}
+
+#[test]
+fn has_edge() {
+  let graph = graph_with_edges(&[0, 1, 2], &[(0, 1), (1, 2)]);
+
+  assert!(graph.has_edge(&TNode::new(0), &TNode::new(1)));
+  assert!(graph.has_edge(&TNode::new(1), &TNode::new(2)));
+  // Edges are directed, and only direct dependencies are reported.
+  assert!(!graph.has_edge(&TNode::new(1), &TNode::new(0)));
+  assert!(!graph.has_edge(&TNode::new(0), &TNode::new(2)));
+  assert!(!graph.has_edge(&TNode::new(2), &TNode::new(3)));
+}
+
+#[tokio::test]
+async fn export_chrome_trace() {
+  let graph = Arc::new(Graph::new());
+  let context = {
+    let mut delays = HashMap::new();
+    delays.insert(TNode::new(0), Duration::from_millis(10));
+    TContext::new(graph.clone()).with_delays(delays)
+  };
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  // A Node which has never completed is omitted.
+  {
+    let mut inner = graph.inner.write();
+    let src_id = inner.nodes[&TNode::new(1)];
+    let dst_id = inner.ensure_entry(TNode::new(2));
+    inner.add_dependency_edge(src_id, dst_id);
+  }
+
+  let mut exported = Vec::new();
+  graph
+    .export_chrome_trace(&[TNode::new(1)], &mut exported)
+    .unwrap();
+  let exported: serde_json::Value = serde_json::from_slice(&exported).unwrap();
+  let events = exported["traceEvents"].as_array().unwrap();
+  assert_eq!(
+    events
+      .iter()
+      .map(|event| (
+        event["name"].as_str().unwrap(),
+        event["ph"].as_str().unwrap(),
+        event["ts"].as_u64().unwrap()
+      ))
+      .collect::<Vec<_>>(),
+    vec![("TNode(1, true)", "X", 0), ("TNode(0, true)", "X", 0)]
+  );
+
+  // The dependency runs for less time than its dependent, and so is nested within it.
+  let durations = events
+    .iter()
+    .map(|event| event["dur"].as_u64().unwrap())
+    .collect::<Vec<_>>();
+  assert!(durations[1] >= 10_000);
+  assert!(durations[0] >= durations[1]);
+}
+
+#[tokio::test]
+async fn edge_policy() {
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    max_retries: Some(0),
+    ..GraphOptions::default()
+  }));
+  let context = TContext::new(graph.clone());
+
+  // Forbid Node 2 from depending on Node 1.
+  graph.set_edge_policy(Arc::new(|src: &TNode, dst: &TNode| {
+    !(src.0 == 2 && dst.0 == 1)
+  }));
+
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Err(TError::ForbiddenEdge)
+  );
+  assert!(!graph.has_edge(&TNode::new(2), &TNode::new(1)));
+}
+
+#[tokio::test]
+async fn digest_manifest() {
+  let manifest = |graph: Arc<Graph<TNode>>, context: TContext| {
+    let mut manifest = Vec::new();
+    let digest = graph
+      .digest_manifest(&[TNode::new(2)], &context, &mut manifest)
+      .unwrap();
+    (digest, String::from_utf8(manifest).unwrap())
+  };
+
+  // Build the same Nodes in two different orders.
+  let graph1 = Arc::new(Graph::new());
+  let context1 = TContext::new(graph1.clone());
+  assert_eq!(
+    graph1.create(TNode::new(2), &context1).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  let graph2 = Arc::new(Graph::new());
+  let context2 = TContext::new(graph2.clone());
+  assert_eq!(
+    graph2.create(TNode::new(0), &context2).await,
+    Ok(vec![T(0, 0)])
+  );
+  assert_eq!(
+    graph2.create(TNode::new(2), &context2).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  let (digest1, manifest1) = manifest(graph1, context1);
+  let (digest2, manifest2) = manifest(graph2, context2);
+  assert_eq!(manifest1, manifest2);
+  assert_eq!(digest1, digest2);
+  assert_eq!(
+    manifest1
+      .lines()
+      .map(|line| line.split('\t').next().unwrap())
+      .collect::<Vec<_>>(),
+    vec!["TNode(0, true)", "TNode(1, true)", "TNode(2, true)"]
+  );
+
+  // But different values produce a different manifest.
+  let graph3 = Arc::new(Graph::new());
+  let context3 = TContext::new(graph3.clone()).with_salt(1);
+  assert_eq!(
+    graph3.create(TNode::new(2), &context3).await,
+    Ok(vec![T(0, 1), T(1, 1), T(2, 1)])
+  );
+  let (digest3, _) = manifest(graph3, context3);
+  assert_ne!(digest1, digest3);
+}
+
+#[tokio::test]
+// NB: Holding the lock across requests is the point of this test.
+#[allow(clippy::await_holding_lock)]
+async fn existing_dependency_requires_shared_lock() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+
+  // While the topology of the Graph is locked for reading, existing Nodes and dependencies can
+  // still be requested.
+  let src_id = graph.inner.read().nodes[&TNode::new(1)];
+  let _inner = graph.inner.read();
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  assert_eq!(
+    graph.get(Some(src_id), &context, TNode::new(0)).await,
+    Ok(vec![T(0, 0)])
+  );
+}
+
+#[tokio::test]
+async fn snapshot() {
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    max_retries: Some(0),
+    ..GraphOptions::default()
+  }));
+  let context = {
+    let mut delays = HashMap::new();
+    delays.insert(TNode::new(4), Duration::from_millis(500));
+    TContext::new(graph.clone())
+      .with_delays(delays)
+      .with_invalidations(vec![(TNode::new(3), 1)].into_iter().collect())
+  };
+
+  // Complete two Nodes, and then dirty one of them.
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  graph.invalidate_from_roots(|&TNode(n, _)| n == 0);
+  // Fail a Node.
+  assert_eq!(
+    graph.create(TNode::new(3), &context).await,
+    Err(TError::Invalidated)
+  );
+  // Start a Node which will not complete.
+  let _running = {
+    let graph = graph.clone();
+    let context = context.clone();
+    tokio::spawn(async move { graph.create(TNode::new(4), &context).await })
+  };
+  sleep(Duration::from_millis(100)).await;
+  // And add a Node which has not been started.
+  graph.inner.write().ensure_entry(TNode::new(5));
+
+  let mut snapshot = graph.snapshot(&context);
+  snapshot.sort_by_key(|(node, _)| node.0);
+  assert_eq!(
+    snapshot,
+    vec![
+      (TNode::new(0), NodeStatus::NotStarted),
+      (TNode::new(1), NodeStatus::Dirty),
+      (TNode::new(3), NodeStatus::Completed { ok: false }),
+      (TNode::new(4), NodeStatus::Running),
+      (TNode::new(5), NodeStatus::NotStarted),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn retry_backoff() {
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    retry_backoff: Some((Duration::from_millis(40), Duration::from_millis(80))),
+    ..GraphOptions::default()
+  }));
+
+  // A single retry is immediate.
+  let context =
+    TContext::new(graph.clone()).with_invalidations(vec![(TNode::new(0), 1)].into_iter().collect());
+  assert_eq!(
+    graph.create(TNode::new(0), &context).await,
+    Ok(vec![T(0, 0)])
+  );
+  assert_eq!(context.runs(), vec![TNode::new(0); 2]);
+
+  // But subsequent retries back off, with delays of at least half of 40ms, 80ms and 80ms.
+  let context = TContext::new(graph.clone())
+    .with_salt(1)
+    .with_invalidations(vec![(TNode::new(1), 4)].into_iter().collect());
+  let start = Instant::now();
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 1)])
+  );
+  assert!(start.elapsed() >= Duration::from_millis(100));
+  assert_eq!(context.runs(), vec![TNode::new(1); 5]);
+}
+
+#[tokio::test]
+async fn pin() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+
+  // Create two independent Nodes, and pin one of them.
+  assert_eq!(
+    graph.create(TNode::new(0), &context).await,
+    Ok(vec![T(0, 0)])
+  );
+  assert_eq!(
+    graph.create(TNode::new(3), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0), T(3, 0)])
+  );
+  graph.pin(&TNode::new(3));
+
+  // The pinned Node survives both invalidation and clearing, while the unpinned Nodes do not.
+  assert_eq!(
+    graph.invalidate_from_roots(|&TNode(n, _)| n == 2 || n == 3),
+    InvalidationResult {
+      cleared: 1,
+      dirtied: 0,
+      skipped: 0,
+    }
+  );
+  graph.clear();
+  assert_eq!(graph.peek(&TNode::new(0), &context), None);
+  assert_eq!(
+    graph.peek(&TNode::new(3), &context),
+    Some(vec![T(0, 0), T(1, 0), T(2, 0), T(3, 0)])
+  );
+
+  // Once unpinned, it is cleared.
+  graph.unpin(&TNode::new(3));
+  graph.clear();
+  assert_eq!(graph.peek(&TNode::new(3), &context), None);
+}
+
+#[test]
+fn condensation() {
+  let graph = graph_with_edges(
+    &[0, 1, 2, 3, 4, 5],
+    &[(0, 1), (1, 2), (2, 0), (2, 3), (4, 4), (5, 3), (5, 0)],
+  );
+  let (groups, edges) = graph.condensation();
+  assert_eq!(
+    groups,
+    vec![
+      vec![TNode::new(0), TNode::new(1), TNode::new(2)],
+      vec![TNode::new(3)],
+      vec![TNode::new(4)],
+      vec![TNode::new(5)],
+    ]
+  );
+  assert_eq!(edges, vec![(0, 1), (3, 0), (3, 1)]);
+}
+
+#[tokio::test]
+async fn critical_path_async() {
+  let durations = [1, 5, 3, 1];
+  let graph = graph_with_edges(&[0, 1, 2, 3], &[(0, 1), (0, 2), (1, 3), (2, 3)]);
+  let duration = |entry: &Entry<TNode>| Duration::from_secs(durations[entry.node().0]);
+  let path_ids = |path: Vec<Entry<TNode>>| path.iter().map(|e| e.node().0).collect::<Vec<_>>();
+
+  let (expected_total, expected_path) = graph.critical_path(&[TNode::new(0)], &duration);
+  let (total, path) = graph.critical_path_async(&[TNode::new(0)], &duration).await;
+  assert_eq!(expected_total, total);
+  assert_eq!(path_ids(expected_path), path_ids(path));
+  assert_eq!(Duration::from_secs(7), total);
+}
+
+#[tokio::test]
+async fn uncached_result_reruns() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone()).with_salt(TRANSIENT_SALT);
+
+  // A result which should not be cached is returned, but re-runs on the next request.
+  let expected = Ok(vec![T(0, TRANSIENT_SALT), T(1, TRANSIENT_SALT)]);
+  assert_eq!(graph.create(TNode::new(1), &context).await, expected);
+  assert_eq!(context.runs(), vec![TNode::new(1), TNode::new(0)]);
+  assert_eq!(graph.create(TNode::new(1), &context).await, expected);
+  assert_eq!(
+    context.runs(),
+    vec![TNode::new(1), TNode::new(0), TNode::new(1), TNode::new(0)]
+  );
+
+  // But once the result should be cached, it is.
+  let context = context.with_salt(0);
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  assert_eq!(context.runs().len(), 6);
+}
+
+#[tokio::test]
+async fn preview_invalidation() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  // Previewing reports the cleared and dirtied Nodes, but does not invalidate them.
+  let mut preview = graph.preview_invalidation(|&TNode(n, _)| n == 1);
+  preview.sort_by_key(|n| n.0);
+  assert_eq!(preview, vec![TNode::new(1), TNode::new(2)]);
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(
+    context.runs(),
+    vec![TNode::new(2), TNode::new(1), TNode::new(0)]
+  );
+
+  // And matches the actual invalidation.
+  let result = graph.invalidate_from_roots(|&TNode(n, _)| n == 1);
+  assert_eq!(preview.len(), result.cleared + result.dirtied);
+
+  // Nodes which are already dirty are not included.
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  graph.clear_values_preserving_edges();
+  assert_eq!(
+    graph.preview_invalidation(|&TNode(n, _)| n == 0),
+    vec![TNode::new(0)]
+  );
+}
+
+#[tokio::test]
+async fn max_nodes() {
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    max_nodes: Some(3),
+    ..GraphOptions::default()
+  }));
+  let context = TContext::new(graph.clone());
+  assert_eq!(graph.utilization(), Some(0.0));
+
+  // Filling the Graph succeeds, and requests for existing Nodes continue to succeed.
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  assert_eq!(graph.utilization(), Some(1.0));
+
+  // But a request for a new Node fails.
+  assert_eq!(
+    graph.create(TNode::new(3), &context).await,
+    Err(TError::GraphFull)
+  );
+  assert_eq!(graph.len(), 3);
+
+  // As does preloading or polling a new Node, although preloading existing Nodes succeeds.
+  assert_eq!(
+    graph.preload(vec![TNode::new(3)], vec![(TNode::new(4), TNode::new(0))]),
+    Err(TError::GraphFull)
+  );
+  assert_eq!(
+    graph.preload(vec![TNode::new(0)], vec![(TNode::new(1), TNode::new(0))]),
+    Ok(())
+  );
+  let token = graph.observe_latest(&TNode::new(0)).unwrap();
+  assert_eq!(
+    graph.poll(TNode::new(3), Some(token), None, &context).await,
+    Err(TError::GraphFull)
+  );
+  assert_eq!(graph.len(), 3);
+
+  assert_eq!(Graph::<TNode>::new().utilization(), None);
+
+  // The limit also applies in combination with other options.
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    max_nodes: Some(3),
+    max_concurrency: Some(1),
+    ..GraphOptions::default()
+  }));
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(3), &context).await,
+    Err(TError::GraphFull)
+  );
+  assert_eq!(graph.len(), 3);
+}
+
+#[tokio::test]
+async fn reset_node() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  // Resetting the middle Node does not dirty its dependent, which is not re-run.
+  graph.reset_node(&TNode::new(1));
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(
+    context.runs(),
+    vec![TNode::new(2), TNode::new(1), TNode::new(0)]
+  );
+
+  // But the reset Node itself re-runs (without re-running its dependency).
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  assert_eq!(
+    context.runs(),
+    vec![TNode::new(2), TNode::new(1), TNode::new(0), TNode::new(1)]
+  );
+}
+
+#[test]
+fn top_critical_paths() {
+  // Two independent chains, where the right chain is longer than the left.
+  let durations = [1, 2, 3, 2, 3, 4];
+  let graph = graph_with_edges(&[0, 1, 2, 3, 4, 5], &[(0, 1), (1, 2), (3, 4), (4, 5)]);
+  let duration = |entry: &Entry<TNode>| Duration::from_secs(durations[entry.node().0]);
+  let roots = [TNode::new(0), TNode::new(3)];
+
+  let paths = graph
+    .top_critical_paths(&roots, &duration, 3)
+    .into_iter()
+    .map(|(total, path)| (total, path.iter().map(|e| e.node().0).collect::<Vec<_>>()))
+    .collect::<Vec<_>>();
+  assert_eq!(
+    paths,
+    vec![
+      (Duration::from_secs(9), vec![3, 4, 5]),
+      (Duration::from_secs(6), vec![0, 1, 2]),
+    ]
+  );
+
+  // The first path is the critical path.
+  let (total, path) = graph.critical_path(&roots, &duration);
+  assert_eq!(
+    (total, path.iter().map(|e| e.node().0).collect::<Vec<_>>()),
+    paths[0]
+  );
+  assert_eq!(graph.top_critical_paths(&roots, &duration, 1).len(), 1);
+
+  // A single root with two disjoint branches: once the root is used by the first path, the other
+  // branch is still reported.
+  let durations = [1, 5, 5, 2, 2];
+  let graph = graph_with_edges(&[0, 1, 2, 3, 4], &[(0, 1), (1, 2), (0, 3), (3, 4)]);
+  let duration = |entry: &Entry<TNode>| Duration::from_secs(durations[entry.node().0]);
+  let paths = graph
+    .top_critical_paths(&[TNode::new(0)], &duration, 3)
+    .into_iter()
+    .map(|(total, path)| (total, path.iter().map(|e| e.node().0).collect::<Vec<_>>()))
+    .collect::<Vec<_>>();
+  assert_eq!(
+    paths,
+    vec![
+      (Duration::from_secs(11), vec![0, 1, 2]),
+      (Duration::from_secs(4), vec![3, 4]),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn subscribe() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  let mut events = Box::pin(graph.subscribe());
+
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  // NB: A Node's dependents may complete before its own completion is published, so the order of
+  // the completion events is not deterministic.
+  let mut started = Vec::new();
+  let mut completed = Vec::new();
+  for _ in 0..4 {
+    match events.next().await.unwrap() {
+      GraphEvent::NodeStarted(node) => started.push(node),
+      GraphEvent::NodeCompleted { node, ok } => completed.push((node, ok)),
+      e => panic!("Unexpected event: {:?}", e),
+    }
+  }
+  completed.sort_by_key(|(node, _)| node.0);
+  assert_eq!(started, vec![TNode::new(1), TNode::new(0)]);
+  assert_eq!(
+    completed,
+    vec![(TNode::new(0), true), (TNode::new(1), true)]
+  );
+
+  graph.invalidate_from_roots(|&TNode(n, _)| n == 0);
+  assert_eq!(
+    events.next().await,
+    Some(GraphEvent::NodeCleared(TNode::new(0)))
+  );
+  assert_eq!(
+    events.next().await,
+    Some(GraphEvent::NodeDirtied(TNode::new(1)))
+  );
+}
+
+#[test]
+fn detect_cycle_with_direction() {
+  let graph = graph_with_edges(&[0, 1, 2, 3], &[(0, 1), (1, 2), (3, 1)]);
+  for &direction in &[Direction::Outgoing, Direction::Incoming] {
+    assert!(graph.detect_cycle_with_direction(&TNode::new(2), &TNode::new(0), direction));
+    assert!(!graph.detect_cycle_with_direction(&TNode::new(0), &TNode::new(2), direction));
+  }
+
+  // The automatically chosen direction searches from whichever end has the lower degree.
+  let inner = graph.inner.read();
+  let id = |n: usize| inner.nodes[&TNode::new(n)];
+  // Node 2 has no dependencies, but Node 1 has two dependents.
+  assert_eq!(
+    inner.cycle_search_direction(id(1), id(2)),
+    Direction::Outgoing
+  );
+  // Node 1 has one dependency, and Node 0 has no dependents.
+  assert_eq!(
+    inner.cycle_search_direction(id(0), id(1)),
+    Direction::Incoming
+  );
+}
+
+#[test]
+fn all_paths() {
+  // A diamond with an additional shortcut from the top to the bottom.
+  let graph = graph_with_edges(
+    &[0, 1, 2, 3, 4],
+    &[(0, 2), (0, 1), (1, 3), (2, 3), (0, 3), (3, 4)],
+  );
+  let paths = |src: usize, dst: usize, max_paths: usize| {
+    graph
+      .all_paths(&TNode::new(src), &TNode::new(dst), max_paths)
+      .into_iter()
+      .map(|path| path.into_iter().map(|n| n.0).collect::<Vec<_>>())
+      .collect::<Vec<_>>()
+  };
+
+  assert_eq!(
+    paths(0, 4, 10),
+    vec![vec![0, 1, 3, 4], vec![0, 2, 3, 4], vec![0, 3, 4]]
+  );
+  assert_eq!(paths(0, 4, 2), vec![vec![0, 1, 3, 4], vec![0, 2, 3, 4]]);
+  assert_eq!(paths(1, 4, 10), vec![vec![1, 3, 4]]);
+  assert_eq!(paths(4, 0, 10), Vec::<Vec<usize>>::new());
+  assert_eq!(paths(4, 4, 10), vec![vec![4]]);
+
+  // Entries which cannot reach the dst are not searched: here, 2^20 paths through a chain of
+  // diamonds which does not lead to the dst.
+  let mut edges = vec![(0, 1), (0, 100)];
+  for top in (1..60).step_by(3) {
+    edges.extend(&[
+      (top, top + 1),
+      (top, top + 2),
+      (top + 1, top + 3),
+      (top + 2, top + 3),
+    ]);
+  }
+  let mut nodes: Vec<_> = (0..=61).collect();
+  nodes.push(100);
+  let graph = graph_with_edges(&nodes, &edges);
+  assert_eq!(
+    graph.all_paths(&TNode::new(0), &TNode::new(100), usize::MAX),
+    vec![vec![TNode::new(0), TNode::new(100)]]
+  );
+}
+
+#[test]
+fn critical_path_incremental() {
+  let durations = Mutex::new(vec![1, 5, 3, 1]);
+  let graph = graph_with_edges(&[0, 1, 2, 3], &[(0, 1), (0, 2), (1, 3), (2, 3)]);
+  let duration = |entry: &Entry<TNode>| Duration::from_secs(durations.lock()[entry.node().0]);
+  let path_ids = |(total, path): (Duration, Vec<Entry<TNode>>)| {
+    (total, path.iter().map(|e| e.node().0).collect::<Vec<_>>())
+  };
+  let roots = [TNode::new(0)];
+
+  let mut cache = graph.critical_path_cache(&roots, &duration);
+  assert_eq!(
+    path_ids(graph.critical_path_incremental(&mut cache, &[], &duration)),
+    (Duration::from_secs(7), vec![0, 1, 3])
+  );
+
+  // Making the right branch slower makes it critical.
+  durations.lock()[2] = 10;
+  assert_eq!(
+    path_ids(graph.critical_path_incremental(&mut cache, &[TNode::new(2)], &duration)),
+    (Duration::from_secs(12), vec![0, 2, 3])
+  );
+  assert_eq!(
+    path_ids(graph.critical_path(&roots, &duration)),
+    (Duration::from_secs(12), vec![0, 2, 3])
+  );
+
+  // And when both branches are equally slow, ties are broken in the same way as a full
+  // recomputation.
+  durations.lock()[1] = 10;
+  assert_eq!(
+    path_ids(graph.critical_path_incremental(&mut cache, &[TNode::new(1)], &duration)),
+    path_ids(graph.critical_path(&roots, &duration)),
+  );
+
+  // Replacing a dependency changes the topology without changing the number of entries or edges,
+  // but is still detected: the left branch is no longer reachable, and so does not contribute.
+  graph
+    .replace_dependency(&TNode::new(0), &TNode::new(1), &TNode::new(3))
+    .unwrap();
+  assert_eq!((graph.len(), graph.edge_count()), (4, 4));
+  durations.lock()[1] = 20;
+  assert_eq!(
+    path_ids(graph.critical_path_incremental(&mut cache, &[TNode::new(1)], &duration)),
+    (Duration::from_secs(12), vec![0, 2, 3])
+  );
+}
+
+#[test]
+fn self_loops() {
+  let graph = graph_with_edges(&[0, 1, 2], &[(0, 1), (1, 2)]);
+  assert_eq!(graph.self_loops(), vec![]);
+
+  let graph = graph_with_edges(&[0, 1, 2], &[(2, 2), (0, 1), (1, 1), (2, 2)]);
+  assert_eq!(graph.self_loops(), vec![TNode::new(1), TNode::new(2)]);
+}
+
+#[tokio::test]
+async fn invalidate_nodes() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone()).with_dependencies(
+    vec![(TNode::new(3), vec![TNode::new(0), TNode::new(2)])]
+      .into_iter()
+      .collect(),
+  );
+  assert_eq!(
+    graph.create(TNode::new(3), &context).await,
+    Ok(vec![T(0, 0), T(3, 0)])
+  );
+
+  // Clearing two Nodes (and ignoring one which is not present) dirties each of their dependents
+  // once, including their shared dependent.
+  assert_eq!(
+    graph.invalidate_nodes(&[TNode::new(0), TNode::new(2), TNode::new(4)]),
+    InvalidationResult {
+      cleared: 2,
+      dirtied: 2,
+      skipped: 0,
+    }
+  );
+}
+
+#[tokio::test]
+async fn clear_matching() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  let generations = || {
+    let inner = graph.inner.read();
+    (0..3)
+      .map(|n| {
+        inner
+          .unsafe_entry_for_id(inner.nodes[&TNode::new(n)])
+          .generation()
+      })
+      .collect::<Vec<_>>()
+  };
+  let initial_generations = generations();
+
+  // Clear only the top Node: it re-runs, but the others retain their values and generations.
+  graph.clear_matching(|&TNode(n, _)| n == 2);
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(
+    context.runs(),
+    vec![TNode::new(2), TNode::new(1), TNode::new(0), TNode::new(2)]
+  );
+  assert_eq!(&generations()[..2], &initial_generations[..2]);
+}
+
+#[test]
+fn with_raw_graph() {
+  let graph = graph_with_edges(&[0, 1, 2], &[(0, 1), (1, 2)]);
+  let id = graph.node_index(&TNode::new(1)).unwrap();
+  let dep_id = graph.node_index(&TNode::new(2)).unwrap();
+  assert_eq!(graph.node_index(&TNode::new(3)), None);
+
+  graph.with_raw_graph(|pg| {
+    assert_eq!(pg.node_count(), 3);
+    assert_eq!(pg.edge_count(), 2);
+    assert_eq!(pg[id].node(), &TNode::new(1));
+    assert_eq!(pg.neighbors(id).collect::<Vec<_>>(), vec![dep_id]);
+    let edge = pg.find_edge(id, dep_id).unwrap();
+    assert_eq!(pg[edge].weight(), 1.0);
+  });
+}
+
+#[test]
+fn hot_dependencies() {
+  // Three roots (0, 1, and 2) which share a slow dependency (3) and a fast one (4), and one of
+  // which has an even slower private dependency (5).
+  let durations = [1, 1, 1, 5, 2, 10];
+  let graph = graph_with_edges(
+    &[0, 1, 2, 3, 4, 5],
+    &[(0, 3), (1, 3), (2, 3), (0, 4), (1, 4), (2, 4), (2, 5)],
+  );
+  let duration = |entry: &Entry<TNode>| Duration::from_secs(durations[entry.node().0]);
+  let roots = [TNode::new(0), TNode::new(1), TNode::new(2)];
+
+  assert_eq!(
+    graph.hot_dependencies(&roots, &duration, 3),
+    vec![
+      (TNode::new(3), 2, Duration::from_secs(5)),
+      (TNode::new(5), 1, Duration::from_secs(10)),
+      (TNode::new(0), 1, Duration::from_secs(1)),
+    ]
+  );
+}
+
+#[test]
+fn cycle_strategy_breaks_lowest_weight_edge() {
+  // A path from 1 to 0, on which the edge from 2 to 3 has the lowest weight.
+  let graph = graph_with_edges(&[0, 1, 2, 3], &[(1, 2), (2, 3), (3, 0)]);
+  let context = TContext::new(Arc::new(Graph::new()));
+  let mut inner = graph.inner.write();
+  let ids = (0..4)
+    .map(|n| inner.nodes[&TNode::new(n)])
+    .collect::<Vec<_>>();
+  let edge = inner.pg.find_edge(ids[2], ids[3]).unwrap();
+  inner.pg[edge].weight = 0.5;
+
+  // By default, a dependency from 0 to 1 is cyclic.
+  assert_eq!(
+    graph
+      .add_dependency(&mut inner, Some(ids[0]), &context, TNode::new(1))
+      .map(|_| ()),
+    Err(TError::Cyclic)
+  );
+
+  // But when breaking cycles, the lowest weight edge is removed instead.
+  graph.set_cycle_strategy(CycleStrategy::BreakLowestWeightEdge);
+  assert!(graph
+    .add_dependency(&mut inner, Some(ids[0]), &context, TNode::new(1))
+    .is_ok());
+  assert!(inner.pg.find_edge(ids[2], ids[3]).is_none());
+  assert!(inner.pg.find_edge(ids[0], ids[1]).is_some());
+  assert!(petgraph::algo::toposort(&inner.pg, None).is_ok());
+
+  // A self-cycle cannot be broken.
+  assert_eq!(
+    graph
+      .add_dependency(&mut inner, Some(ids[0]), &context, TNode::new(0))
+      .map(|_| ()),
+    Err(TError::Cyclic)
+  );
+}
+
+#[test]
+fn diameter() {
+  // A chain, with an additional dependent of its second Node.
+  let graph = graph_with_edges(&[0, 1, 2, 3, 4], &[(0, 1), (1, 2), (2, 3), (4, 1)]);
+  assert_eq!(
+    graph.diameter(&[TNode::new(0), TNode::new(4)]),
+    (3, Some(TNode::new(1)))
+  );
+  // Only the reachable subgraph is considered.
+  assert_eq!(graph.diameter(&[TNode::new(2)]), (1, Some(TNode::new(2))));
+  assert_eq!(graph.diameter(&[TNode::new(5)]), (0, None));
+}
+
+#[tokio::test]
+async fn try_load_cached() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone())
+    .with_cached(vec![(TNode::new(1), vec![T(1, 7)])].into_iter().collect());
+
+  // The middle Node is loaded rather than run, and so its dependency never runs either.
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(1, 7), T(2, 0)])
+  );
+  assert_eq!(context.runs(), vec![TNode::new(2)]);
+  assert_eq!(graph.peek(&TNode::new(1), &context), Some(vec![T(1, 7)]));
+}
+
+#[tokio::test]
+async fn state_age_visualizer() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  // Add a Node which has never run.
+  graph.inner.write().ensure_entry(TNode::new(2));
+
+  let color = |visualizer: &mut StateAgeVisualizer, id: usize| {
+    let inner = graph.inner.read();
+    let entry = inner.entry_for_id(inner.nodes[&TNode::new(id)]).unwrap();
+    NodeVisualizer::<TNode>::color(visualizer, entry, &context)
+  };
+
+  // Relative to a long maximum age, the Nodes that ran have the darkest color.
+  let mut visualizer = StateAgeVisualizer::new(Duration::from_secs(3600));
+  assert_eq!(color(&mut visualizer, 0), "9");
+  assert_eq!(color(&mut visualizer, 1), "9");
+  assert_eq!(color(&mut visualizer, 2), "white");
+
+  // And once they are older than the maximum age, the lightest.
+  thread::sleep(Duration::from_millis(10));
+  let mut visualizer = StateAgeVisualizer::new(Duration::from_millis(1));
+  assert_eq!(color(&mut visualizer, 0), "1");
+  assert_eq!(color(&mut visualizer, 2), "white");
+}
+
+#[tokio::test]
+async fn max_concurrency() {
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    max_concurrency: Some(2),
+    ..GraphOptions::default()
+  }));
+  let nodes = vec![TNode::new(1), TNode::new(2), TNode::new(3)];
+  let context = {
+    let edges = nodes.iter().map(|n| (n.clone(), vec![])).collect();
+    let delays = nodes
+      .iter()
+      .map(|n| (n.clone(), Duration::from_millis(200)))
+      .collect();
+    TContext::new(graph.clone())
+      .with_dependencies(edges)
+      .with_delays(delays)
+  };
+
+  // Request three slow Nodes at once: all three complete, but no more than two run at once.
+  assert_eq!(
+    future::join_all(nodes.into_iter().map(|n| graph.create(n, &context))).await,
+    vec![Ok(vec![T(1, 0)]), Ok(vec![T(2, 0)]), Ok(vec![T(3, 0)])]
+  );
+  assert_eq!(context.runs().len(), 3);
+  assert!(context.max_concurrency() <= 2);
+
+  // A chain of dependencies longer than the limit does not deadlock, because Nodes release their
+  // permits while waiting for their dependencies.
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    max_concurrency: Some(1),
+    ..GraphOptions::default()
+  }));
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    timeout(
+      Duration::from_secs(10),
+      graph.create(TNode::new(3), &context)
+    )
+    .await,
+    Ok(Ok(vec![T(0, 0), T(1, 0), T(2, 0), T(3, 0)]))
+  );
+
+  // A canceled run releases its permit and unregisters its slot.
+  let graph = Arc::new(Graph::new_with_options(GraphOptions {
+    max_concurrency: Some(1),
+    ..GraphOptions::default()
+  }));
+  let context = {
+    let mut delays = HashMap::new();
+    delays.insert(TNode::new(0), Duration::from_millis(2000));
+    TContext::new(graph.clone()).with_delays(delays)
+  };
+  assert!(timeout(
+    Duration::from_millis(100),
+    graph.create(TNode::new(1), &context)
+  )
+  .await
+  .is_err());
+  sleep(Duration::from_millis(100)).await;
+  assert_eq!(context.aborts(), vec![TNode::new(1), TNode::new(0)]);
+  let limit = graph.concurrency_limit.as_ref().unwrap();
+  assert!(limit.running.lock().is_empty());
+  assert_eq!(limit.semaphore.available_permits(), 1);
+}
+
+#[test]
+fn metadata() {
+  let graph = graph_with_edges(&[0, 1, 2], &[(2, 1)]);
+  let get = |id: usize| graph.get_metadata(&TNode::new(id), "owner");
+  graph.set_metadata(&TNode::new(0), "owner".to_owned(), "a".to_owned());
+  graph.set_metadata(&TNode::new(2), "owner".to_owned(), "b".to_owned());
+  // Metadata cannot be set for a Node which is not present.
+  graph.set_metadata(&TNode::new(3), "owner".to_owned(), "c".to_owned());
+  assert_eq!(get(0), Some("a".to_owned()));
+  assert_eq!(get(1), None);
+  assert_eq!(get(2), Some("b".to_owned()));
+  assert_eq!(get(3), None);
+  assert_eq!(graph.get_metadata(&TNode::new(2), "team"), None);
+
+  // Metadata survives invalidation and clearing.
+  graph.invalidate_nodes(&[TNode::new(0), TNode::new(1)]);
+  graph.clear();
+  assert_eq!(get(0), Some("a".to_owned()));
+  assert_eq!(get(2), Some("b".to_owned()));
+
+  // But not removal, and it follows Nodes whose EntryIds change due to the removal of others.
+  assert_eq!(graph.gc_unreachable(&[TNode::new(2)]), 1);
+  assert_eq!(get(0), None);
+  assert_eq!(get(1), None);
+  assert_eq!(get(2), Some("b".to_owned()));
+  assert_eq!(graph.verify_invariants(), Ok(()));
+  graph.set_metadata(&TNode::new(0), "team".to_owned(), "d".to_owned());
+  assert_eq!(graph.get_metadata(&TNode::new(0), "team"), None);
+}
+
+#[test]
+fn reachable_node_count() {
+  let graph = graph_with_edges(&[0, 1, 2, 3, 4], &[(2, 1), (1, 0), (3, 0)]);
+  assert_eq!(graph.reachable_node_count(&[TNode::new(2)]), 3);
+  // Shared dependencies are only counted once, and missing roots are ignored.
+  assert_eq!(
+    graph.reachable_node_count(&[TNode::new(2), TNode::new(3), TNode::new(5)]),
+    4
+  );
+  assert_eq!(graph.reachable_node_count(&[TNode::new(4)]), 1);
+  assert_eq!(graph.reachable_node_count(&[]), 0);
+}
+
+#[tokio::test]
+async fn replace_dependency() {
+  let graph = Arc::new(Graph::new());
+  let context = {
+    let mut edges = HashMap::new();
+    edges.insert(TNode::new(2), vec![TNode::new(1)]);
+    edges.insert(TNode::new(1), vec![TNode::new(0)]);
+    edges.insert(TNode::new(0), vec![]);
+    edges.insert(TNode::new(3), vec![]);
+    TContext::new(graph.clone()).with_dependencies(edges)
+  };
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  let dependencies = |id: usize| graph.dependencies(&TNode::new(id), &context).unwrap();
+  let is_dirty = |id: usize| {
+    let inner = graph.inner.read();
+    inner
+      .unsafe_entry_for_id(inner.nodes[&TNode::new(id)])
+      .is_dirty()
+  };
+
+  // A replacement which would create a cycle fails, and leaves the Graph unchanged.
+  assert_eq!(
+    graph.replace_dependency(&TNode::new(1), &TNode::new(0), &TNode::new(2)),
+    Err(TError::Cyclic)
+  );
+  assert_eq!(dependencies(1), vec![TNode::new(0)]);
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(
+    context.runs(),
+    vec![TNode::new(2), TNode::new(1), TNode::new(0)]
+  );
+
+  // As does a replacement for a pinned src, which could not be dirtied.
+  graph.pin(&TNode::new(1));
+  assert_eq!(
+    graph.replace_dependency(&TNode::new(1), &TNode::new(0), &TNode::new(3)),
+    Err(TError::ForbiddenEdge)
+  );
+  assert_eq!(dependencies(1), vec![TNode::new(0)]);
+  graph.unpin(&TNode::new(1));
+
+  // Otherwise, the edge is replaced, and the src (and its dependents) are dirtied, which is
+  // observed like any other invalidation.
+  let events = Arc::new(Mutex::new(Vec::new()));
+  let observer_events = events.clone();
+  graph.set_invalidation_observer(Arc::new(move |node: &TNode, kind: InvalidationKind| {
+    observer_events.lock().push((node.clone(), kind));
+  }));
+  assert_eq!(
+    graph.replace_dependency(&TNode::new(1), &TNode::new(0), &TNode::new(3)),
+    Ok(())
+  );
+  assert_eq!(dependencies(1), vec![TNode::new(3)]);
+  assert_eq!(graph.verify_invariants(), Ok(()));
+  assert!(is_dirty(1));
+  assert!(is_dirty(2));
+  assert!(!is_dirty(0));
+  assert_eq!(
+    *events.lock(),
+    vec![
+      (TNode::new(1), InvalidationKind::Dirtied),
+      (TNode::new(2), InvalidationKind::Dirtied),
+    ]
+  );
+  assert_eq!(graph.invalidation_metrics().dirtied, 2);
+
+  // The src re-runs rather than being cleaned (and so re-establishes its actual dependencies),
+  // while its dependent is cleaned.
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(
+    context.runs(),
+    vec![TNode::new(2), TNode::new(1), TNode::new(0), TNode::new(1)]
+  );
+  assert_eq!(dependencies(1), vec![TNode::new(0)]);
+}
+
+#[test]
+fn verify_invariants() {
+  let graph = graph_with_edges(&[0, 1, 2], &[(2, 1), (1, 0)]);
+  assert_eq!(graph.verify_invariants(), Ok(()));
+
+  // A Node which maps to the entry for another Node.
+  let entry_id = graph.node_index(&TNode::new(0)).unwrap();
+  graph.inner.write().nodes.insert(TNode::new(3), entry_id);
+  assert!(graph.verify_invariants().is_err());
+  graph.inner.write().nodes.remove(&TNode::new(3));
+  assert_eq!(graph.verify_invariants(), Ok(()));
+
+  // A self-loop.
+  graph.inner.write().pg.add_edge(
+    entry_id,
+    entry_id,
+    DependencyEdge {
+      weight: 1.0,
+      generation: Generation::initial(),
+    },
+  );
+  assert_eq!(
+    graph.verify_invariants(),
+    Err(format!("{} has a self-loop", TNode::new(0)))
+  );
+}
+
+#[tokio::test]
+async fn sample_nodes_by_duration() {
+  let graph = Arc::new(Graph::new());
+  let nodes = vec![TNode::new(1), TNode::new(2), TNode::new(3)];
+  let context = {
+    let edges = nodes.iter().map(|n| (n.clone(), vec![])).collect();
+    let mut delays = HashMap::new();
+    delays.insert(TNode::new(1), Duration::from_millis(100));
+    TContext::new(graph.clone())
+      .with_dependencies(edges)
+      .with_delays(delays)
+  };
+  for node in &nodes {
+    assert!(graph.create(node.clone(), &context).await.is_ok());
+  }
+  // A Node which has never run.
+  graph.inner.write().ensure_entry(TNode::new(4));
+
+  // Sampling at least as many Nodes as have run returns all of them.
+  let mut sample = graph.sample_nodes_by_duration(10, 0);
+  sample.sort_by_key(|node| node.0);
+  assert_eq!(sample, nodes);
+  assert_eq!(graph.sample_nodes_by_duration(0, 0), vec![]);
+
+  // Samples are deterministic for a seed, and are dominated by the slowest Node.
+  let samples = (0..20)
+    .map(|seed| graph.sample_nodes_by_duration(1, seed))
+    .collect::<Vec<_>>();
+  assert_eq!(
+    samples,
+    (0..20)
+      .map(|seed| graph.sample_nodes_by_duration(1, seed))
+      .collect::<Vec<_>>()
+  );
+  let slowest = samples
+    .iter()
+    .filter(|sample| sample == &&vec![TNode::new(1)])
+    .count();
+  assert!(slowest >= 15, "Sampled the slowest Node {} times.", slowest);
+}
+
+#[test]
+fn critical_path_with_context() {
+  // A diamond, where the durations of the Nodes are recorded by the context.
+  let graph = Arc::new(graph_with_edges(
+    &[0, 1, 2, 3],
+    &[(0, 1), (0, 2), (1, 3), (2, 3)],
+  ));
+  let delays = vec![(1, 5), (2, 3), (3, 1)]
+    .into_iter()
+    .map(|(id, secs)| (TNode::new(id), Duration::from_secs(secs)))
+    .collect();
+  let context = TContext::new(graph.clone()).with_delays(delays);
+  let duration = |entry: &Entry<TNode>, context: &TContext| {
+    context
+      .delays
+      .get(entry.node())
+      .cloned()
+      .unwrap_or_default()
+  };
+
+  let (total, path) = graph.critical_path_with_context(&[TNode::new(0)], &context, &duration);
+  assert_eq!(Duration::from_secs(6), total);
+  assert_eq!(
+    vec![0, 1, 3],
+    path.iter().map(|e| e.node().0).collect::<Vec<_>>()
+  );
+}
+
+#[test]
+fn compact_edges() {
+  let graph = graph_with_edges(&[0, 1, 2], &[(1, 0), (2, 1)]);
+  assert_eq!(graph.compact_edges(), 0);
+
+  // Add the same edge repeatedly, with varying weights.
+  {
+    let mut inner = graph.inner.write();
+    let (src, dst) = (inner.nodes[&TNode::new(2)], inner.nodes[&TNode::new(1)]);
+    for weight in &[3.0, 2.0, 5.0] {
+      inner.pg.add_edge(
+        src,
+        dst,
+        DependencyEdge {
+          weight: *weight,
+          generation: Generation::initial(),
+        },
+      );
+    }
+  }
+  assert_eq!(graph.edge_count(), 5);
+
+  // Compacting collapses them into a single edge with the largest weight.
+  assert_eq!(graph.compact_edges(), 3);
+  assert_eq!(graph.edge_count(), 2);
+  assert_eq!(graph.verify_invariants(), Ok(()));
+  let (weights, dependencies) = {
+    let inner = graph.inner.read();
+    let src = inner.nodes[&TNode::new(2)];
+    (
+      inner
+        .pg
+        .edges_directed(src, Direction::Outgoing)
+        .map(|edge| edge.weight().weight())
+        .collect::<Vec<_>>(),
+      inner
+        .pg
+        .neighbors_directed(inner.nodes[&TNode::new(1)], Direction::Outgoing)
+        .count(),
+    )
+  };
+  assert_eq!(weights, vec![5.0]);
+  assert_eq!(dependencies, 1);
+  assert_eq!(graph.compact_edges(), 0);
+}
+
+#[tokio::test]
+async fn recorded_dep_generations() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(graph.recorded_dep_generations(&TNode::new(1)), None);
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  let generation = |id: usize| {
+    let inner = graph.inner.read();
+    inner
+      .unsafe_entry_for_id(inner.nodes[&TNode::new(id)])
+      .generation()
+  };
+  assert_eq!(
+    graph.recorded_dep_generations(&TNode::new(1)),
+    Some(vec![generation(0)])
+  );
+  assert_eq!(graph.recorded_dep_generations(&TNode::new(0)), Some(vec![]));
+
+  // The recorded Generations are retained when the Node is dirtied, and so no longer match once
+  // its dependency has re-run with a new value.
+  let recorded = graph.recorded_dep_generations(&TNode::new(1));
+  graph.invalidate_node(&TNode::new(0));
+  assert_eq!(graph.recorded_dep_generations(&TNode::new(1)), recorded);
+  let context = context.new_run(1).with_salt(1);
+  assert_eq!(
+    graph.create(TNode::new(0), &context).await,
+    Ok(vec![T(0, 1)])
+  );
+  assert_eq!(graph.recorded_dep_generations(&TNode::new(1)), recorded);
+  assert_ne!(recorded, Some(vec![generation(0)]));
+}
+
+#[tokio::test]
+async fn get_forced() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  let is_dirty = |id: usize| {
+    let inner = graph.inner.read();
+    inner
+      .unsafe_entry_for_id(inner.nodes[&TNode::new(id)])
+      .is_dirty()
+  };
+
+  // A forced request re-runs the Node, but because its value does not change, its dependent is
+  // not dirtied.
+  let context = context.new_run(1);
+  assert_eq!(
+    graph.get_forced(&context, TNode::new(0)).await,
+    Ok(vec![T(0, 0)])
+  );
+  assert_eq!(context.runs(), vec![TNode::new(0)]);
+  assert!(!is_dirty(1));
+
+  // But if its value changes, its dependent is dirtied, and re-runs when next requested.
+  let context = context.new_run(2).with_salt(1);
+  assert_eq!(
+    graph.get_forced(&context, TNode::new(0)).await,
+    Ok(vec![T(0, 1)])
+  );
+  assert!(is_dirty(1));
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 1), T(1, 1)])
+  );
+  assert_eq!(context.runs(), vec![TNode::new(0), TNode::new(1)]);
+}
+
+#[tokio::test]
+async fn get_forced_failure() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+
+  // If the forced run fails (here, because it now depends on its own dependent), the failure is
+  // not memoized, but the previous value is gone: the dependent is dirtied, and observers see it.
+  let context = context.new_run(1).with_dependencies(
+    vec![(TNode::new(1), vec![TNode::new(2)])]
+      .into_iter()
+      .collect(),
+  );
+  assert_eq!(
+    graph.get_forced(&context, TNode::new(1)).await,
+    Err(TError::Cyclic)
+  );
+  {
+    let inner = graph.inner.read();
+    assert!(inner
+      .unsafe_entry_for_id(inner.nodes[&TNode::new(2)])
+      .is_dirty());
+  }
+  assert_eq!(
+    graph.invalidation_metrics(),
+    InvalidationMetrics {
+      invalidations: 1,
+      cleared: 0,
+      dirtied: 1,
+      skipped: 0,
+    }
+  );
+}
+
+#[tokio::test]
+async fn invalidation_metrics() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+  assert_eq!(
+    graph.create(TNode::new(2), &context).await,
+    Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+  );
+  assert_eq!(graph.invalidation_metrics(), InvalidationMetrics::default());
+
+  // Totals accumulate across invalidations.
+  graph.invalidate_node(&TNode::new(0));
+  graph.invalidate_node(&TNode::new(1));
+  assert_eq!(
+    graph.invalidation_metrics(),
+    InvalidationMetrics {
+      invalidations: 2,
+      cleared: 2,
+      dirtied: 2,
+      skipped: 1,
+    }
+  );
+}
+
 ///
 /// A token containing the id of a Node and the id of a Context, respectively. Has a short name
 /// to minimize the verbosity of tests.
@@ -766,7 +4268,7 @@ struct T(usize, usize);
 /// A node that builds a Vec of tokens by recursively requesting itself and appending its value
 /// to the result.
 ///
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 struct TNode(usize, bool /*cacheability*/);
 impl TNode {
   fn new(id: usize) -> Self {
@@ -792,9 +4294,14 @@ impl Node for TNode {
 
   async fn run(self, context: TContext) -> Result<Vec<T>, TError> {
     let mut abort_guard = context.abort_guard(self.clone());
+    let _concurrency_guard = context.concurrency_guard();
     context.ran(self.clone());
     let token = T(self.0, context.salt());
     context.maybe_delay(&self).await;
+    if context.maybe_invalidated(&self) {
+      abort_guard.did_not_abort();
+      return Err(TError::Invalidated);
+    }
     let res = match context.dependencies_of(&self) {
       deps if !deps.is_empty() => {
         // Request all dependencies, but include only the first in our output value.
@@ -818,8 +4325,31 @@ impl Node for TNode {
   fn cacheable(&self) -> bool {
     self.1
   }
+
+  fn is_invalidation_root(&self) -> bool {
+    self.0 == 0
+  }
+
+  fn item_heap_size(&self, item: &Vec<T>) -> usize {
+    item.len() * std::mem::size_of::<T>()
+  }
+
+  fn item_digests(&self, item: &Vec<T>) -> Vec<Digest> {
+    vec![Digest::of_bytes(format!("{:?}", item).as_bytes())]
+  }
+
+  fn try_load_cached(&self, context: &TContext) -> Option<Vec<T>> {
+    context.cached.get(self).cloned()
+  }
+
+  fn should_cache_result(&self, result: &Result<Vec<T>, TError>) -> bool {
+    // Results computed with TRANSIENT_SALT are not memoized, to allow for testing uncached results.
+    !matches!(result, Ok(item) if item.iter().any(|t| t.1 == TRANSIENT_SALT))
+  }
 }
 
+const TRANSIENT_SALT: usize = 99;
+
 impl std::fmt::Display for TNode {
   fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
     write!(f, "{:?}", self)
@@ -889,9 +4419,15 @@ struct TContext {
   edges: Arc<HashMap<TNode, Vec<TNode>>>,
   delays: Arc<HashMap<TNode, Duration>>,
   uncacheable: Arc<HashSet<TNode>>,
+  // Values which are loaded for TNodes (via Node::try_load_cached) rather than running them.
+  cached: Arc<HashMap<TNode, Vec<T>>>,
+  // The number of times that each TNode should fail as invalidated before succeeding.
+  invalidations: Arc<Mutex<HashMap<TNode, usize>>>,
   graph: Arc<Graph<TNode>>,
   aborts: Arc<Mutex<Vec<TNode>>>,
   runs: Arc<Mutex<Vec<TNode>>>,
+  // The number of TNodes which are currently running, and the most which have run at once.
+  concurrency: Arc<Mutex<(usize, usize)>>,
   entry_id: Option<EntryId>,
   stats: Arc<Mutex<Stats>>,
 }
@@ -910,6 +4446,8 @@ impl NodeContext for TContext {
       edges: self.edges.clone(),
       delays: self.delays.clone(),
       uncacheable: self.uncacheable.clone(),
+      cached: self.cached.clone(),
+      invalidations: self.invalidations.clone(),
       graph: self.graph.clone(),
       aborts: self.aborts.clone(),
       runs: self.runs.clone(),
@@ -943,9 +4481,12 @@ impl TContext {
       edges: Arc::default(),
       delays: Arc::default(),
       uncacheable: Arc::default(),
+      cached: Arc::default(),
+      invalidations: Arc::default(),
       graph,
       aborts: Arc::default(),
       runs: Arc::default(),
+      concurrency: Arc::default(),
       entry_id: None,
       stats: Arc::default(),
     }
@@ -966,6 +4507,16 @@ impl TContext {
     self
   }
 
+  fn with_cached(mut self, cached: HashMap<TNode, Vec<T>>) -> TContext {
+    self.cached = Arc::new(cached);
+    self
+  }
+
+  fn with_invalidations(mut self, invalidations: HashMap<TNode, usize>) -> TContext {
+    self.invalidations = Arc::new(Mutex::new(invalidations));
+    self
+  }
+
   fn with_salt(mut self, salt: usize) -> TContext {
     self.salt = salt;
     self
@@ -1003,6 +4554,29 @@ impl TContext {
     runs.push(node);
   }
 
+  fn concurrency_guard(&self) -> ConcurrencyGuard {
+    let mut concurrency = self.concurrency.lock();
+    concurrency.0 += 1;
+    concurrency.1 = cmp::max(concurrency.0, concurrency.1);
+    ConcurrencyGuard {
+      concurrency: self.concurrency.clone(),
+    }
+  }
+
+  ///
+  /// Returns true if the given TNode should fail as invalidated (consuming one of its remaining
+  /// invalidations).
+  ///
+  fn maybe_invalidated(&self, node: &TNode) -> bool {
+    match self.invalidations.lock().get_mut(node) {
+      Some(remaining) if *remaining > 0 => {
+        *remaining -= 1;
+        true
+      }
+      _ => false,
+    }
+  }
+
   async fn maybe_delay(&self, node: &TNode) {
     if let Some(delay) = self.delays.get(node) {
       sleep(*delay).await;
@@ -1033,6 +4607,10 @@ impl TContext {
   fn runs(&self) -> Vec<TNode> {
     self.runs.lock().clone()
   }
+
+  fn max_concurrency(&self) -> usize {
+    self.concurrency.lock().1
+  }
 }
 
 ///
@@ -1058,9 +4636,25 @@ impl Drop for AbortGuard {
   }
 }
 
+///
+/// A guard that counts a TNode as running until it is dropped (whether the run completes or is
+/// canceled).
+///
+struct ConcurrencyGuard {
+  concurrency: Arc<Mutex<(usize, usize)>>,
+}
+
+impl Drop for ConcurrencyGuard {
+  fn drop(&mut self) {
+    self.concurrency.lock().0 -= 1;
+  }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 enum TError {
   Cyclic,
+  ForbiddenEdge,
+  GraphFull,
   Invalidated,
 }
 impl NodeError for TError {
@@ -1071,4 +4665,155 @@ impl NodeError for TError {
   fn cyclic(_path: Vec<String>) -> Self {
     TError::Cyclic
   }
+
+  fn forbidden_edge(_src: String, _dst: String) -> Self {
+    TError::ForbiddenEdge
+  }
+
+  fn graph_full() -> Self {
+    TError::GraphFull
+  }
+}
+
+///
+/// A Node with no dependencies and an explicit `Node::priority`, which records the order in which
+/// it runs, and whose values count their clones.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct PNode(usize, i32 /*priority*/);
+
+#[async_trait]
+impl Node for PNode {
+  type Context = PContext;
+  type Item = PItem;
+  type Error = TError;
+
+  async fn run(self, context: PContext) -> Result<PItem, TError> {
+    context.runs.lock().push(self.clone());
+    Ok(PItem(self.0, context.clones.clone()))
+  }
+
+  fn cacheable(&self) -> bool {
+    true
+  }
+
+  fn priority(&self) -> i32 {
+    self.1
+  }
+}
+
+impl std::fmt::Display for PNode {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+    write!(f, "{:?}", self)
+  }
+}
+
+///
+/// The value of a PNode, which increments the shared count of clones when cloned.
+///
+#[derive(Debug)]
+struct PItem(usize, Arc<Mutex<usize>>);
+
+impl PartialEq for PItem {
+  fn eq(&self, other: &Self) -> bool {
+    self.0 == other.0
+  }
+}
+
+impl Eq for PItem {}
+
+impl Clone for PItem {
+  fn clone(&self) -> Self {
+    *self.1.lock() += 1;
+    PItem(self.0, self.1.clone())
+  }
+}
+
+#[derive(Clone)]
+struct PContext {
+  graph: Arc<Graph<PNode>>,
+  runs: Arc<Mutex<Vec<PNode>>>,
+  clones: Arc<Mutex<usize>>,
+  stats: Arc<Mutex<Stats>>,
+}
+
+impl PContext {
+  fn new(graph: Arc<Graph<PNode>>) -> PContext {
+    PContext {
+      graph,
+      runs: Arc::default(),
+      clones: Arc::default(),
+      stats: Arc::default(),
+    }
+  }
+}
+
+impl NodeContext for PContext {
+  type Node = PNode;
+  type RunId = usize;
+
+  fn stats<'a>(&'a self) -> Box<dyn DerefMut<Target = Stats> + 'a> {
+    Box::new(self.stats.lock())
+  }
+
+  fn clone_for(&self, _entry_id: EntryId) -> PContext {
+    self.clone()
+  }
+
+  fn run_id(&self) -> &usize {
+    &0
+  }
+
+  fn graph(&self) -> &Graph<PNode> {
+    &self.graph
+  }
+
+  fn spawn<F>(&self, future: F)
+  where
+    F: Future<Output = ()> + Send + 'static,
+  {
+    tokio::spawn(future);
+  }
+}
+
+///
+/// A visualizer which renders every Node with the same color.
+///
+struct TVisualizer;
+
+impl NodeVisualizer<TNode> for TVisualizer {
+  fn color_scheme(&self) -> &str {
+    "set19"
+  }
+
+  fn color(&mut self, _entry: &Entry<TNode>, _context: &TContext) -> String {
+    "1".to_owned()
+  }
+}
+
+///
+/// Constructs a Graph containing (NotStarted) entries for the TNodes with the given ids, connected
+/// by the given edges.
+///
+fn graph_with_edges(node_ids: &[usize], edges: &[(usize, usize)]) -> Graph<TNode> {
+  let graph = Graph::new();
+  {
+    let mut inner = graph.inner.write();
+    for &node_id in node_ids {
+      inner.ensure_entry(TNode::new(node_id));
+    }
+    for &(src, dst) in edges {
+      let src = inner.nodes[&TNode::new(src)];
+      let dst = inner.nodes[&TNode::new(dst)];
+      inner.pg.add_edge(
+        src,
+        dst,
+        DependencyEdge {
+          weight: 1.0,
+          generation: Generation::initial(),
+        },
+      );
+    }
+  }
+  graph
 }
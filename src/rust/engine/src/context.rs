@@ -499,7 +499,9 @@ pub struct InvalidatableGraph(Graph<NodeKey>);
 
 impl Invalidatable for InvalidatableGraph {
   fn invalidate(&self, paths: &HashSet<PathBuf>, caller: &str) -> usize {
-    let InvalidationResult { cleared, dirtied } = self.invalidate_from_roots(move |node| {
+    let InvalidationResult {
+      cleared, dirtied, ..
+    } = self.invalidate_from_roots(move |node| {
       if let Some(fs_subject) = node.fs_subject() {
         paths.contains(fs_subject)
       } else {
@@ -514,8 +516,9 @@ impl Invalidatable for InvalidatableGraph {
   }
 
   fn invalidate_all(&self, caller: &str) -> usize {
-    let InvalidationResult { cleared, dirtied } =
-      self.invalidate_from_roots(|node| node.fs_subject().is_some());
+    let InvalidationResult {
+      cleared, dirtied, ..
+    } = self.invalidate_all_roots();
     info!(
       "{} invalidation: cleared {} and dirtied {} nodes for all paths",
       caller, cleared, dirtied
@@ -1561,6 +1561,14 @@ impl Node for NodeKey {
     }
   }
 
+  fn is_invalidation_root(&self) -> bool {
+    self.fs_subject().is_some()
+  }
+
+  fn item_digests(&self, output: &NodeOutput) -> Vec<hashing::Digest> {
+    output.digests()
+  }
+
   fn cacheable_item(&self, output: &NodeOutput) -> bool {
     match (self, output) {
       (
@@ -1637,6 +1645,17 @@ impl NodeError for Failure {
       externs::doc_url("targets#dependencies-and-dependency-inference")
     ))
   }
+
+  fn forbidden_edge(src: String, dst: String) -> Failure {
+    throw(&format!(
+      "The dependency of {} on {} is forbidden by the dependency graph's edge policy.",
+      src, dst
+    ))
+  }
+
+  fn graph_full() -> Failure {
+    throw("The dependency graph has reached its maximum number of nodes.")
+  }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -77,6 +77,7 @@ impl Scheduler {
     self.core.graph.visualize(
       Visualizer::default(),
       &session.roots_nodes(),
+      None,
       path,
       &context,
     )
@@ -158,13 +159,25 @@ impl Scheduler {
   /// Return all Digests currently in memory in this Scheduler.
   ///
   pub fn all_digests(&self, session: &Session) -> HashSet<hashing::Digest> {
-    let context = Context::new(self.core.clone(), session.clone());
     let mut digests = HashSet::new();
+    self.for_each_digest(session, |digest| {
+      digests.insert(digest);
+    });
+    digests
+  }
+
+  ///
+  /// Calls the given function with each Digest currently in memory in this Scheduler, without
+  /// collecting them.
+  ///
+  /// NB: A Digest which is held by multiple Nodes will be visited once per Node.
+  ///
+  pub fn for_each_digest<F: FnMut(hashing::Digest)>(&self, session: &Session, mut f: F) {
+    let context = Context::new(self.core.clone(), session.clone());
     self
       .core
       .graph
-      .visit_live(&context, |_, v| digests.extend(v.digests()));
-    digests
+      .visit_live(&context, |_, v| v.digests().into_iter().for_each(&mut f));
   }
 
   pub async fn run_local_interactive_process(